@@ -6,6 +6,10 @@
 #include "audio_thread_priority.h"
 
 int main() {
+  assert(atp_free_handle(nullptr) == ATP_ERROR_INVALID_ARGUMENT);
+  assert(atp_last_error_code() == ATP_ERROR_INVALID_ARGUMENT);
+  assert(atp_last_error_message());
+
 #ifdef __linux__
   atp_thread_info* info = atp_get_current_thread_info();
   atp_thread_info* info2 = nullptr;
@@ -13,12 +17,18 @@ int main() {
   uint8_t buffer[ATP_THREAD_INFO_SIZE];
   atp_serialize_thread_info(info, buffer);
 
-  info2 = atp_deserialize_thread_info(buffer);
+  info2 = atp_try_deserialize_thread_info(buffer, ATP_THREAD_INFO_SIZE);
+  assert(info2);
 
-  int rv = memcmp(info, info2, ATP_THREAD_INFO_SIZE);
+  uint8_t buffer2[ATP_THREAD_INFO_SIZE];
+  atp_serialize_thread_info(info2, buffer2);
+  int rv = memcmp(buffer, buffer2, ATP_THREAD_INFO_SIZE);
 
   assert(!rv);
 
+  assert(!atp_try_deserialize_thread_info(buffer, ATP_THREAD_INFO_SIZE - 1));
+  assert(atp_last_error_code() == ATP_ERROR_INVALID_ARGUMENT);
+
   atp_free_thread_info(info);
   atp_free_thread_info(info2);
 
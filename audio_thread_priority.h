@@ -5,6 +5,7 @@
 #ifndef AUDIO_THREAD_PRIORITY_H
 #define AUDIO_THREAD_PRIORITY_H
 
+#include <stddef.h>
 #include <stdint.h>
 #include <stdlib.h>
 
@@ -14,8 +15,105 @@
  */
 struct atp_handle;
 struct atp_thread_info;
+/* The size of a serialized atp_thread_info, the same on all Linux targets. */
 extern size_t ATP_THREAD_INFO_SIZE;
 
+/**
+ * Error codes, returned by the functions returning an int32_t, and by
+ * `atp_last_error_code`. Each one matches an `ErrorKind` of the Rust API. These
+ * values are stable, and new ones may be added: treat an unknown negative value
+ * like ATP_ERROR_OTHER.
+ */
+#define ATP_OK 0
+#define ATP_ERROR_OTHER -1
+/* Not allowed to change the scheduling of the thread, or rtkit refused. */
+#define ATP_ERROR_PERMISSION_DENIED -2
+/* rtkit or the D-Bus daemon is not running or cannot be reached. */
+#define ATP_ERROR_SERVICE_UNAVAILABLE -3
+/* A limit such as rtkit's maximum or RLIMIT_RTTIME is in the way. */
+#define ATP_ERROR_LIMIT_EXCEEDED -4
+/* An invalid argument, such as a sample rate of zero or a NULL pointer. */
+#define ATP_ERROR_INVALID_ARGUMENT -5
+/* The thread to promote or demote does not exist, or has exited. */
+#define ATP_ERROR_THREAD_NOT_FOUND -6
+/* Not supported by this platform, build or backend. */
+#define ATP_ERROR_UNSUPPORTED -7
+/* The service performing the promotion did not answer in time. */
+#define ATP_ERROR_TIMEOUT -8
+
+/**
+ * Scheduling policies, for `atp_promotion_options::policy`.
+ */
+/* The default of the backend: SCHED_RR with rtkit, SCHED_FIFO natively. */
+#define ATP_POLICY_DEFAULT 0
+#define ATP_POLICY_FIFO 1
+#define ATP_POLICY_RR 2
+/* SCHED_DEADLINE, set by the native backend only, with CAP_SYS_NICE. */
+#define ATP_POLICY_DEADLINE 3
+
+/**
+ * Flags, for `atp_promotion_options::flags`.
+ */
+/* Let children forked by the promoted thread inherit real-time scheduling. */
+#define ATP_PROMOTION_NO_RESET_ON_FORK 1
+
+/**
+ * Log levels, passed to an `atp_log_callback`.
+ */
+#define ATP_LOG_ERROR 1
+#define ATP_LOG_WARN 2
+#define ATP_LOG_INFO 3
+#define ATP_LOG_DEBUG 4
+#define ATP_LOG_TRACE 5
+
+/**
+ * A log callback: it gets the level of the record (one of the ATP_LOG_*
+ * values), its message, valid for the duration of the call, and the
+ * `user_data` passed to `atp_set_log_callback`.
+ */
+typedef void (*atp_log_callback)(int32_t level, const char *message,
+                                 void *user_data);
+
+/**
+ * The options of a promotion, for `atp_promote_current_thread_to_real_time_ex`
+ * and `atp_promote_thread_to_real_time_ex`. The options other than the buffer
+ * size and sample rate are only used on Linux.
+ *
+ * Zeroed fields keep the defaults. Zero-initialize the struct, and set
+ * `struct_size` to `sizeof(atp_promotion_options)`, so that a later version of
+ * the library, with more fields, knows which ones the caller set:
+ *
+ *   atp_promotion_options options = {};
+ *   options.struct_size = sizeof(options);
+ *   options.audio_samplerate_hz = 48000;
+ */
+typedef struct atp_promotion_options {
+  size_t struct_size;
+  /* Number of frames per audio buffer, or 0 if unknown. */
+  uint32_t audio_buffer_frames;
+  /* Sample-rate of the audio stream, in Hz. */
+  uint32_t audio_samplerate_hz;
+  /* Real-time priority to request, 1-99, or 0 for the default. */
+  int32_t priority;
+  /* One of the ATP_POLICY_* values. */
+  int32_t policy;
+  /* A combination of the ATP_PROMOTION_* flags. */
+  uint32_t flags;
+  /* CPU time the thread may use without blocking, in microseconds, or 0 for
+   * the duration of one buffer. */
+  uint64_t budget_us;
+  /* Names of the backends to try in order ("rtkit", "portal", "native",
+   * "nice", or a custom backend registered from Rust), or NULL for the
+   * backends set for the process. */
+  const char *const *backends;
+  size_t backends_count;
+} atp_promotion_options;
+
+/* The size of the first version of `atp_promotion_options`, the smallest
+ * `struct_size` the library accepts. */
+#define ATP_PROMOTION_OPTIONS_SIZE_V1                                          \
+  (offsetof(atp_promotion_options, backends_count) + sizeof(size_t))
+
 #ifdef __cplusplus
 extern "C" {
 #endif // __cplusplus
@@ -28,17 +126,32 @@ extern "C" {
  * or an upper bound.
  * audio_samplerate_hz: sample-rate for this audio stream, in Hz
  *
- * Returns an opaque handle in case of success, NULL otherwise.
+ * Returns an opaque handle in case of success, NULL otherwise, see
+ * `atp_last_error_code` and `atp_last_error_message`.
  */
 atp_handle *atp_promote_current_thread_to_real_time(uint32_t audio_buffer_frames,
                                                     uint32_t audio_samplerate_hz);
 
 
+/**
+ * Promotes the current thread to real-time priority, with options.
+ *
+ * options: the buffer size, sample rate and options of the promotion.
+ *
+ * Returns an opaque handle in case of success, NULL otherwise, see
+ * `atp_last_error_code` and `atp_last_error_message`.
+ */
+atp_handle *atp_promote_current_thread_to_real_time_ex(const atp_promotion_options *options);
+
 /**
  * Demotes the current thread, promoted to real-time priority via
  * `atp_promote_current_thread_to_real_time`, back to its previous priority.
  *
- * Returns 0 in case of success, non-zero otherwise.
+ * On Linux, this can be called from any thread of the process, for example a
+ * cleanup thread. It fails if the promoted thread has exited.
+ *
+ * Returns 0 in case of success, a negative ATP_ERROR_* code otherwise
+ * (ATP_ERROR_INVALID_ARGUMENT when handle is NULL).
  */
 int32_t atp_demote_current_thread_from_real_time(atp_handle *handle);
 
@@ -48,10 +161,41 @@ int32_t atp_demote_current_thread_from_real_time(atp_handle *handle);
  * handle must be synchronized externally (or the related thread must have
  * exited).
  *
- * Returns 0 in case of success, non-zero otherwise.
+ * Returns 0 in case of success, ATP_ERROR_INVALID_ARGUMENT when handle is NULL.
  */
 int32_t atp_free_handle(atp_handle *handle);
 
+/**
+ * Forwards the log records of the library to `callback`, so that they can be
+ * sent to the logging system of the host. This installs a logger for the whole
+ * process, and fails if one is already installed, for example by a Rust part of
+ * the application.
+ *
+ * callback: called with each log record, from the thread that logs it, or NULL
+ * to stop forwarding records. It must not call `atp_set_log_callback`.
+ * user_data: passed to `callback` as is.
+ *
+ * Returns 0 in case of success, ATP_ERROR_UNSUPPORTED if another logger is
+ * installed. Once this returns, the previous callback is not called anymore.
+ */
+int32_t atp_set_log_callback(atp_log_callback callback, void *user_data);
+
+/**
+ * The code of the last error of an `atp_*` function on the calling thread: one
+ * of the negative ATP_ERROR_* codes, or ATP_OK if none failed yet. Like errno, a
+ * successful call does not reset it.
+ */
+int32_t atp_last_error_code();
+
+/**
+ * The message of the last error of an `atp_*` function on the calling thread,
+ * including the D-Bus or OS error it comes from, or NULL if none failed yet.
+ *
+ * The string is owned by the library, and stays valid until the next `atp_*`
+ * function fails on the calling thread, or the thread exits.
+ */
+const char *atp_last_error_message();
+
 /*
  * Linux-only API.
  *
@@ -83,7 +227,8 @@ int32_t atp_free_handle(atp_handle *handle);
  * or an upper bound.
  * audio_samplerate_hz: sample-rate for this audio stream, in Hz
  *
- * Returns an opaque handle in case of success, NULL otherwise.
+ * Returns an opaque handle in case of success, NULL otherwise, see
+ * `atp_last_error_code` and `atp_last_error_message`.
  *
  * This is useful on Linux only, to promote a thread from another process or
  * thread when the thread to promote cannot do so itself (for example because it
@@ -91,11 +236,25 @@ int32_t atp_free_handle(atp_handle *handle);
  */
 atp_handle *atp_promote_thread_to_real_time(atp_thread_info *thread_info);
 
+/**
+ * Promotes a thread, possibly in another process, to real-time priority, with
+ * options.
+ *
+ * thread_info: info on the thread to promote, gathered with
+ * `atp_get_current_thread_info()`, called on the thread itself.
+ * options: the buffer size, sample rate and options of the promotion.
+ *
+ * Returns an opaque handle in case of success, NULL otherwise, see
+ * `atp_last_error_code` and `atp_last_error_message`.
+ */
+atp_handle *atp_promote_thread_to_real_time_ex(atp_thread_info *thread_info,
+                                               const atp_promotion_options *options);
+
 /**
  * Demotes a thread, promoted to real-time priority via
  * `atp_promote_thread_to_real_time`, back to its previous priority.
  *
- * Returns 0 in case of success, non-zero otherwise.
+ * Returns 0 in case of success, a negative ATP_ERROR_* code otherwise.
  *
  * This is useful on Linux only, to promote a thread from another process or
  * thread when the thread to promote cannot do so itself (for example because it
@@ -108,7 +267,8 @@ int32_t atp_demote_thread_from_real_time(atp_thread_info* thread_info);
  * another thread and/or process.
  *
  * Returns a non-null pointer to an `atp_thread_info` structure in case of
- * success, to be freed later with `atp_free_thread_info`, and NULL otherwise.
+ * success, to be freed later with `atp_free_thread_info`, and NULL otherwise,
+ * see `atp_last_error_code` and `atp_last_error_message`.
  *
  * This is useful on Linux only, to promote a thread from another process or
  * thread when the thread to promote cannot do so itself (for example because it
@@ -119,33 +279,56 @@ atp_thread_info *atp_get_current_thread_info();
 /**
  * Free an `atp_thread_info` structure.
  *
- * Returns 0 in case of success, non-zero in case of error (because thread_info
- * was NULL).
+ * Returns 0 in case of success, ATP_ERROR_INVALID_ARGUMENT in case of error
+ * (because thread_info was NULL).
  */
 int32_t atp_free_thread_info(atp_thread_info *thread_info);
 
 /**
- * Serialize an `atp_thread_info` to a byte buffer that is
- * sizeof(atp_thread_info) long.
+ * Serialize an `atp_thread_info` to a byte buffer that is ATP_THREAD_INFO_SIZE
+ * bytes long. The buffer starts with a magic number, a format version and the
+ * length of the payload, and ends with a checksum. The fields are
+ * little-endian and of fixed width, so that a 32-bit process can send its
+ * thread info to a 64-bit one, and the other way around.
  */
 void atp_serialize_thread_info(atp_thread_info *thread_info, uint8_t *bytes);
 
 /**
- * Deserialize a byte buffer of sizeof(atp_thread_info) to an `atp_thread_info`
- * pointer. It can be then freed using atp_free_thread_info.
+ * Deserialize a byte buffer of ATP_THREAD_INFO_SIZE bytes to an
+ * `atp_thread_info` pointer. It can be then freed using atp_free_thread_info.
+ *
+ * Returns NULL if the buffer does not hold a valid serialized thread info.
+ * Prefer `atp_try_deserialize_thread_info`, which does not trust the buffer to
+ * be ATP_THREAD_INFO_SIZE bytes long.
  * */
 atp_thread_info* atp_deserialize_thread_info(uint8_t *bytes);
 
+/**
+ * Deserialize a byte buffer of `length` bytes, received from another process,
+ * to an `atp_thread_info` pointer. It can be then freed using
+ * atp_free_thread_info.
+ *
+ * Returns NULL if the buffer does not hold a thread info serialized by a
+ * compatible version of this library: wrong length, magic number, format
+ * version or checksum. See `atp_last_error_code` and `atp_last_error_message`.
+ */
+atp_thread_info *atp_try_deserialize_thread_info(const uint8_t *bytes,
+                                                 size_t length);
+
 /**
  * Set the real-time computation limit (RLIMIT_RTTIME) for the calling process.
  *
  * This is needed by the rtkit/D-Bus backend before a thread can be promoted
  * from another process, and must be called from within that process (it can be
- * done before a sandbox lockdown). Without the `dbus` feature no such limit is
- * required and this is a no-op.
+ * done before a sandbox lockdown). It does not need rtkit to be reachable: the
+ * limit is kept within rtkit's default maximum. With the native backend, this sets the
+ * RLIMIT_RTTIME budget of the process to the duration of one buffer, which the
+ * promoting process may lack the privilege to do.
  *
  * This only sets the limit. For actually promoting the thread to a real-time
  * scheduling class, see `atp_promote_thread_to_real_time`.
+ *
+ * Returns 0 in case of success, a negative ATP_ERROR_* code otherwise.
  */
 int32_t atp_set_real_time_limit(uint32_t audio_buffer_frames,
                                 uint32_t audio_samplerate_hz);
@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Promotion without blocking the thread being promoted.
+//!
+//! Promoting through rtkit is a D-Bus round trip that can take up to the rtkit timeout, which is
+//! too long to wait for on a thread that has to start rendering audio. Instead, the request is
+//! handed to a helper thread that promotes the caller by its thread info, like a remote promotion,
+//! while the caller keeps running at normal priority.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::{
+    promote_thread_to_real_time_with, AudioThreadPriorityError, PromotionOptions, RtPriorityHandle,
+    RtPriorityThreadInfo,
+};
+
+type PromotionResult = Result<RtPriorityHandle, AudioThreadPriorityError>;
+type Callback = Box<dyn FnOnce(PromotionResult) + Send>;
+
+enum PromotionState {
+    Pending,
+    Complete(PromotionResult),
+    /// The result was handed out by [`PendingPromotion::poll`].
+    Taken,
+}
+
+/// The state shared with a [`PendingPromotion`], completed by the helper thread.
+struct Token(Arc<(Mutex<PromotionState>, Condvar)>);
+
+impl Token {
+    fn complete(&self, result: PromotionResult) {
+        let (state, condvar) = &*self.0;
+        *state.lock().unwrap_or_else(PoisonError::into_inner) = PromotionState::Complete(result);
+        condvar.notify_all();
+    }
+}
+
+/// A token dropped before its promotion completed, with a request lost with the helper thread,
+/// completes with an error: `PendingPromotion::wait` would block forever otherwise.
+impl Drop for Token {
+    fn drop(&mut self) {
+        let (state, condvar) = &*self.0;
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let PromotionState::Pending = *state {
+            *state = PromotionState::Complete(Err(AudioThreadPriorityError::new(
+                "the promotion thread exited before completing the promotion",
+            )));
+            condvar.notify_all();
+        }
+    }
+}
+
+/// Where to deliver the result of a promotion.
+enum Completion {
+    Token(Token),
+    Callback(Callback),
+}
+
+impl Completion {
+    fn complete(self, result: PromotionResult) {
+        match self {
+            Completion::Token(token) => token.complete(result),
+            Completion::Callback(callback) => callback(result),
+        }
+    }
+}
+
+struct Request {
+    thread_info: RtPriorityThreadInfo,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: PromotionOptions,
+    completion: Completion,
+}
+
+/// The channel to the helper thread, and the process that started it: a child forked afterwards
+/// does not have the thread, and starts its own.
+static HELPER: Mutex<Option<(Sender<Request>, libc::pid_t)>> = Mutex::new(None);
+
+/// Serve the promotion requests. A panic, in a backend or in a callback, is caught, so that it does
+/// not take the helper thread down with the requests queued after it.
+fn helper_loop(requests: std::sync::mpsc::Receiver<Request>) {
+    for request in requests {
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            promote_thread_to_real_time_with(
+                request.thread_info,
+                request.audio_buffer_frames,
+                request.audio_samplerate_hz,
+                &request.options,
+            )
+        }))
+        .unwrap_or_else(|_| Err(AudioThreadPriorityError::new("the promotion panicked")));
+        let completion = request.completion;
+        if catch_unwind(AssertUnwindSafe(|| completion.complete(result))).is_err() {
+            log::error!("A promotion callback panicked");
+        }
+    }
+}
+
+/// Hand `request` to the helper thread, starting it if needed.
+fn submit(request: Request) -> Result<(), AudioThreadPriorityError> {
+    let mut helper = HELPER.lock().unwrap_or_else(PoisonError::into_inner);
+    let pid = unsafe { libc::getpid() };
+    let request = match helper.as_ref() {
+        Some((sender, helper_pid)) if *helper_pid == pid => match sender.send(request) {
+            Ok(()) => return Ok(()),
+            // The helper thread is gone, start another one.
+            Err(e) => e.0,
+        },
+        _ => request,
+    };
+
+    let (sender, receiver) = channel();
+    std::thread::Builder::new()
+        .name("atp_promoter".into())
+        .spawn(move || helper_loop(receiver))
+        .map_err(|e| {
+            AudioThreadPriorityError::from_os_error(
+                &format!("could not start the promotion thread: {e}"),
+                &e,
+            )
+        })?;
+    sender
+        .send(request)
+        .map_err(|_| AudioThreadPriorityError::new("the promotion thread exited"))?;
+    *helper = Some((sender, pid));
+    Ok(())
+}
+
+/// A promotion requested with `promote_current_thread_to_real_time_async`, still in progress or
+/// complete.
+///
+/// The thread keeps running at its current priority until the promotion completes.
+pub struct PendingPromotion {
+    shared: Arc<(Mutex<PromotionState>, Condvar)>,
+}
+
+impl PendingPromotion {
+    /// Returns the result of the promotion if it has completed, or `None` if it is still in
+    /// progress. The result is only returned once: later calls return `None`.
+    pub fn poll(&mut self) -> Option<Result<RtPriorityHandle, AudioThreadPriorityError>> {
+        let (state, _) = &*self.shared;
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        match std::mem::replace(&mut *state, PromotionState::Taken) {
+            PromotionState::Complete(result) => Some(result),
+            pending => {
+                *state = pending;
+                None
+            }
+        }
+    }
+
+    /// Returns `true` once the promotion has completed, successfully or not, and its result has not
+    /// been taken by `poll` yet.
+    pub fn is_complete(&self) -> bool {
+        let (state, _) = &*self.shared;
+        matches!(
+            *state.lock().unwrap_or_else(PoisonError::into_inner),
+            PromotionState::Complete(_)
+        )
+    }
+
+    /// Block until the promotion completes, and return its result. This is an error if the result
+    /// was already returned by `poll`.
+    pub fn wait(self) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        while let PromotionState::Pending = *state {
+            state = condvar.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+        match std::mem::replace(&mut *state, PromotionState::Taken) {
+            PromotionState::Complete(result) => result,
+            _ => Err(AudioThreadPriorityError::new(
+                "the promotion result was already returned by poll",
+            )),
+        }
+    }
+}
+
+/// Request the promotion of `thread_info` from the helper thread, and return a token to poll or
+/// wait for the result.
+pub fn promote_async(
+    thread_info: RtPriorityThreadInfo,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<PendingPromotion, AudioThreadPriorityError> {
+    let shared = Arc::new((Mutex::new(PromotionState::Pending), Condvar::new()));
+    submit(Request {
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options: options.clone(),
+        completion: Completion::Token(Token(shared.clone())),
+    })?;
+    Ok(PendingPromotion { shared })
+}
+
+/// Request the promotion of `thread_info` from the helper thread, and call `callback` there with the
+/// result.
+pub fn promote_with_callback(
+    thread_info: RtPriorityThreadInfo,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+    callback: Callback,
+) -> Result<(), AudioThreadPriorityError> {
+    submit(Request {
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options: options.clone(),
+        completion: Completion::Callback(callback),
+    })
+}
@@ -0,0 +1,125 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The interface of a Linux promotion backend, see [`PriorityBackend`].
+
+use std::any::Any;
+
+use crate::{AudioThreadPriorityError, ErrorKind, PromotionOptions, RtPriorityThreadInfo};
+
+/// A mechanism to promote threads to real-time priority on Linux.
+///
+/// The built-in backends (rtkit, the desktop portal, the native backend and the nice level boost)
+/// implement this trait. An application can implement it for its own mechanism, for example a
+/// privileged helper process, register it with `register_priority_backend`, and add it to the
+/// backends to try as `LinuxBackend::Custom`, with `set_linux_backends` or
+/// `PromotionOptions::backends`.
+///
+/// A backend that cannot honour the `options` of a promotion should return an error, so that the
+/// next backend is tried. The thread to promote is described by its `RtPriorityThreadInfo`: its
+/// `thread_id`, `pid`, and the `policy` and `priority` to restore on demotion, or, for a backend in
+/// another process, its `serialize` bytes.
+pub trait PriorityBackend: Send + Sync {
+    /// The name of the backend, used to refer to it in `LinuxBackend::Custom` and in errors.
+    fn name(&self) -> &'static str;
+
+    /// Promote the calling thread, described by `thread_info`, to real-time priority.
+    fn promote_current_thread(
+        &self,
+        thread_info: RtPriorityThreadInfo,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError>;
+
+    /// Undo a promotion made by `promote_current_thread`. This can be called from another thread
+    /// of the process than the promoted one; a backend that cannot demote the thread from there
+    /// should return an error.
+    fn demote_current_thread(
+        &self,
+        promotion: BackendPromotion,
+    ) -> Result<(), AudioThreadPriorityError>;
+
+    /// Promote a thread, possibly in another process, to real-time priority.
+    fn promote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfo,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError>;
+
+    /// Restore a thread, possibly in another process, to the scheduling it had when `thread_info`
+    /// was captured.
+    fn demote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfo,
+    ) -> Result<(), AudioThreadPriorityError>;
+
+    /// Adapt a promotion to a new buffer size or sample rate, without demoting the thread:
+    /// recompute what the backend derived from the buffer duration. Does nothing by default.
+    fn update_parameters(
+        &self,
+        promotion: &mut BackendPromotion,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let _ = (promotion, audio_buffer_frames, audio_samplerate_hz);
+        Ok(())
+    }
+
+    /// Prepare the calling process to be promoted by another process, which uses this backend. Does
+    /// nothing by default.
+    fn set_real_time_limit(
+        &self,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let _ = (audio_buffer_frames, audio_samplerate_hz);
+        Ok(())
+    }
+}
+
+/// A promotion made by a [`PriorityBackend`], holding what the backend needs to undo it.
+pub struct BackendPromotion {
+    state: Box<dyn Any + Send>,
+    granted_priority: Option<u32>,
+}
+
+impl BackendPromotion {
+    /// A promotion to the real-time priority `granted_priority`, or `None` if the scheduling has no
+    /// priority. `state` is handed back to the backend on demotion, see [`BackendPromotion::state`].
+    pub fn new<T: Any + Send>(state: T, granted_priority: Option<u32>) -> BackendPromotion {
+        BackendPromotion {
+            state: Box::new(state),
+            granted_priority,
+        }
+    }
+
+    /// The real-time priority the thread was granted.
+    pub fn granted_priority(&self) -> Option<u32> {
+        self.granted_priority
+    }
+
+    /// The state passed to `new`, or an error if it is not a `T`: a promotion handed to another
+    /// backend than the one that made it.
+    pub fn state<T: Any>(self) -> Result<T, AudioThreadPriorityError> {
+        self.state.downcast().map(|state| *state).map_err(|_| {
+            AudioThreadPriorityError::with_kind(
+                ErrorKind::InvalidArgument,
+                "promotion made by another backend",
+            )
+        })
+    }
+
+    /// The state passed to `new`, to update it in place, or an error if it is not a `T`.
+    pub fn state_mut<T: Any>(&mut self) -> Result<&mut T, AudioThreadPriorityError> {
+        self.state.downcast_mut().ok_or_else(|| {
+            AudioThreadPriorityError::with_kind(
+                ErrorKind::InvalidArgument,
+                "promotion made by another backend",
+            )
+        })
+    }
+}
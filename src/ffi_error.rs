@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The error codes of the C API, and the last error of each thread, to be read with
+//! `atp_last_error_code` and `atp_last_error_message`.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+use crate::{AudioThreadPriorityError, ErrorKind};
+
+/// Success.
+pub const ATP_OK: i32 = 0;
+/// `ErrorKind::Other`, or any error kind added after this list.
+pub const ATP_ERROR_OTHER: i32 = -1;
+/// `ErrorKind::PermissionDenied`.
+pub const ATP_ERROR_PERMISSION_DENIED: i32 = -2;
+/// `ErrorKind::ServiceUnavailable`.
+pub const ATP_ERROR_SERVICE_UNAVAILABLE: i32 = -3;
+/// `ErrorKind::LimitExceeded`.
+pub const ATP_ERROR_LIMIT_EXCEEDED: i32 = -4;
+/// `ErrorKind::InvalidArgument`, including a NULL pointer passed to the C API.
+pub const ATP_ERROR_INVALID_ARGUMENT: i32 = -5;
+/// `ErrorKind::ThreadNotFound`.
+pub const ATP_ERROR_THREAD_NOT_FOUND: i32 = -6;
+/// `ErrorKind::Unsupported`.
+pub const ATP_ERROR_UNSUPPORTED: i32 = -7;
+/// `ErrorKind::Timeout`.
+pub const ATP_ERROR_TIMEOUT: i32 = -8;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(i32, CString)>> = const { RefCell::new(None) };
+}
+
+impl ErrorKind {
+    /// The code of this kind of error in the C API. These values are stable.
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorKind::PermissionDenied => ATP_ERROR_PERMISSION_DENIED,
+            ErrorKind::ServiceUnavailable => ATP_ERROR_SERVICE_UNAVAILABLE,
+            ErrorKind::LimitExceeded => ATP_ERROR_LIMIT_EXCEEDED,
+            ErrorKind::InvalidArgument => ATP_ERROR_INVALID_ARGUMENT,
+            ErrorKind::ThreadNotFound => ATP_ERROR_THREAD_NOT_FOUND,
+            ErrorKind::Unsupported => ATP_ERROR_UNSUPPORTED,
+            ErrorKind::Timeout => ATP_ERROR_TIMEOUT,
+            ErrorKind::Other => ATP_ERROR_OTHER,
+        }
+    }
+}
+
+/// Record `error` as the last error of the calling thread, and return its code, for a C function
+/// that failed.
+pub(crate) fn set_last_error(error: &AudioThreadPriorityError) -> i32 {
+    let code = error.kind().code();
+    // The message of an error cannot have a NUL byte in C.
+    let message =
+        CString::new(error.to_string().replace('\0', "\u{FFFD}")).expect("NUL bytes were replaced");
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some((code, message)));
+    code
+}
+
+/// Record an invalid argument of a C function, such as a NULL pointer, as the last error of the
+/// calling thread, and return its code.
+pub(crate) fn invalid_argument(message: &str) -> i32 {
+    set_last_error(&AudioThreadPriorityError::with_kind(
+        ErrorKind::InvalidArgument,
+        message,
+    ))
+}
+
+/// The code of the last error of an `atp_*` function on the calling thread, with a C API.
+///
+/// # Return value
+///
+/// One of the negative `ATP_ERROR_*` codes, or `ATP_OK` (0) if no `atp_*` function failed on this
+/// thread yet. A successful call does not reset it, like `errno`.
+#[no_mangle]
+pub extern "C" fn atp_last_error_code() -> i32 {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ATP_OK, |(code, _)| *code)
+    })
+}
+
+/// The message of the last error of an `atp_*` function on the calling thread, with a C API. It
+/// is the full text of the `AudioThreadPriorityError`, including the D-Bus or OS error it comes
+/// from.
+///
+/// # Return value
+///
+/// A NUL-terminated string, owned by this library, that stays valid until the next `atp_*`
+/// function fails on this thread, or the thread exits. NULL if no `atp_*` function failed on this
+/// thread yet.
+#[no_mangle]
+pub extern "C" fn atp_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |(_, message)| message.as_ptr())
+    })
+}
@@ -0,0 +1,161 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Forwarding of the log records of this crate to a callback of a C or C++ host, see
+//! [`atp_set_log_callback`].
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::sync::{PoisonError, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::ffi_error::set_last_error;
+use crate::{AudioThreadPriorityError, ErrorKind, ATP_OK};
+
+/// `log::Level::Error`.
+pub const ATP_LOG_ERROR: i32 = 1;
+/// `log::Level::Warn`.
+pub const ATP_LOG_WARN: i32 = 2;
+/// `log::Level::Info`.
+pub const ATP_LOG_INFO: i32 = 3;
+/// `log::Level::Debug`.
+pub const ATP_LOG_DEBUG: i32 = 4;
+/// `log::Level::Trace`.
+pub const ATP_LOG_TRACE: i32 = 5;
+
+/// A log callback, with a C API: it gets the level of the record (one of the `ATP_LOG_*` values),
+/// its message, and the `user_data` passed to `atp_set_log_callback`.
+#[allow(non_camel_case_types)]
+pub type atp_log_callback =
+    Option<unsafe extern "C" fn(level: i32, message: *const c_char, user_data: *mut c_void)>;
+
+struct Sink {
+    /// Whether `LOGGER` is the logger of the process. It is installed on first use, and stays.
+    installed: bool,
+    /// The callback and its user data, stored as an address so that it can be shared by threads.
+    callback: Option<(unsafe extern "C" fn(i32, *const c_char, *mut c_void), usize)>,
+}
+
+static SINK: RwLock<Sink> = RwLock::new(Sink {
+    installed: false,
+    callback: None,
+});
+
+struct CallbackLogger;
+
+static LOGGER: CallbackLogger = CallbackLogger;
+
+impl Log for CallbackLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        SINK.read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .callback
+            .is_some()
+    }
+
+    fn log(&self, record: &Record) {
+        // The lock is held during the call, so that the callback is not called anymore once
+        // `atp_set_log_callback` has replaced it.
+        let sink = SINK.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some((callback, user_data)) = sink.callback {
+            let message = CString::new(record.args().to_string().replace('\0', "\u{FFFD}"))
+                .expect("NUL bytes were replaced");
+            unsafe {
+                callback(
+                    record.level() as i32,
+                    message.as_ptr(),
+                    user_data as *mut c_void,
+                )
+            };
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Forward the log records to `callback`, with a C API, so that a C or C++ host can send them to
+/// its own logging system. This installs a logger for the whole process, so it fails if another
+/// one is installed, for example by a Rust part of the application.
+///
+/// # Arguments
+///
+/// * `callback` - the function to call with each log record, from the thread that logs it, or
+///   NULL to stop forwarding records. It must not call `atp_set_log_callback`.
+/// * `user_data` - passed to `callback` as is.
+///
+/// # Return value
+///
+/// 0 in case of success, `ATP_ERROR_UNSUPPORTED` if another logger is installed. Once this
+/// returns, the previous callback is not called anymore.
+///
+/// # Safety
+///
+/// `callback` must be safe to call from any thread with `user_data`, until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn atp_set_log_callback(
+    callback: atp_log_callback,
+    user_data: *mut c_void,
+) -> i32 {
+    let mut sink = SINK.write().unwrap_or_else(PoisonError::into_inner);
+    match callback {
+        Some(callback) => {
+            if !sink.installed {
+                if log::set_logger(&LOGGER).is_err() {
+                    return set_last_error(&AudioThreadPriorityError::with_kind(
+                        ErrorKind::Unsupported,
+                        "another logger is installed",
+                    ));
+                }
+                sink.installed = true;
+            }
+            sink.callback = Some((callback, user_data as usize));
+            log::set_max_level(LevelFilter::Trace);
+        }
+        None => {
+            sink.callback = None;
+            if sink.installed {
+                log::set_max_level(LevelFilter::Off);
+            }
+        }
+    }
+    ATP_OK
+}
+
+// With `terminal-logging`, the tests install their own logger.
+#[cfg(all(test, not(feature = "terminal-logging")))]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::sync::Mutex;
+
+    unsafe extern "C" fn record(level: i32, message: *const c_char, user_data: *mut c_void) {
+        let records = &*(user_data as *const Mutex<Vec<(i32, String)>>);
+        let message = CStr::from_ptr(message).to_string_lossy().into_owned();
+        records.lock().unwrap().push((level, message));
+    }
+
+    #[test]
+    fn test_log_callback() {
+        let records = Mutex::new(Vec::new());
+        let user_data = &records as *const _ as *mut c_void;
+        assert_eq!(
+            unsafe { atp_set_log_callback(Some(record), user_data) },
+            ATP_OK
+        );
+        log::warn!("forwarded to the callback");
+        assert_eq!(
+            unsafe { atp_set_log_callback(None, std::ptr::null_mut()) },
+            ATP_OK
+        );
+        log::warn!("not forwarded anymore");
+
+        // Other tests may log at the same time.
+        let records = records.into_inner().unwrap();
+        assert!(records.contains(&(ATP_LOG_WARN, "forwarded to the callback".to_string())));
+        assert!(!records
+            .iter()
+            .any(|(_, message)| message == "not forwarded anymore"));
+    }
+}
@@ -0,0 +1,303 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The promotion options of the C API, see [`atp_promotion_options`].
+
+use std::convert::TryFrom;
+use std::os::raw::c_char;
+use std::time::Duration;
+
+use crate::{AudioThreadPriorityError, ErrorKind, PromotionOptions, SchedulingPolicy};
+
+/// The default policy of the backend.
+pub const ATP_POLICY_DEFAULT: i32 = 0;
+/// `SchedulingPolicy::Fifo`.
+pub const ATP_POLICY_FIFO: i32 = 1;
+/// `SchedulingPolicy::RoundRobin`.
+pub const ATP_POLICY_RR: i32 = 2;
+/// `SchedulingPolicy::Deadline`.
+pub const ATP_POLICY_DEADLINE: i32 = 3;
+
+/// A flag of `atp_promotion_options`: let children forked by the promoted thread inherit
+/// real-time scheduling, like `PromotionOptions::reset_on_fork(false)`.
+pub const ATP_PROMOTION_NO_RESET_ON_FORK: u32 = 1;
+
+/// The options of a promotion, with a C API, mirroring `PromotionOptions`.
+///
+/// Zeroed fields keep the defaults, so that new fields can be added at the end: `struct_size` tells
+/// which ones the caller knows about. It must be at least `ATP_PROMOTION_OPTIONS_SIZE_V1`, and the
+/// fields past it keep their defaults. A caller built against a later version may pass a larger
+/// struct, as long as the fields this library does not know about are zero.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub struct atp_promotion_options {
+    /// `sizeof(atp_promotion_options)`, as known by the caller.
+    pub struct_size: usize,
+    /// The exact or an upper limit on the number of frames that have to be rendered each callback,
+    /// or 0 for a sensible default value.
+    pub audio_buffer_frames: u32,
+    /// The sample-rate for this audio stream, in Hz.
+    pub audio_samplerate_hz: u32,
+    /// The real-time priority to request, 1-99, or 0 for the default.
+    pub priority: i32,
+    /// One of the `ATP_POLICY_*` values.
+    pub policy: i32,
+    /// A combination of the `ATP_PROMOTION_*` flags.
+    pub flags: u32,
+    /// The CPU time the thread may use without blocking, in microseconds, or 0 for the value
+    /// derived from the buffer size and sample rate.
+    pub budget_us: u64,
+    /// The names of the backends to try, in order ("rtkit", "portal", "native", "nice", or the
+    /// name of a backend registered with `register_priority_backend`), or NULL for the backends
+    /// set for the process.
+    pub backends: *const *const c_char,
+    /// The number of names in `backends`.
+    pub backends_count: usize,
+}
+
+/// The fields of the first version of `atp_promotion_options`, which must never change.
+#[repr(C)]
+#[allow(dead_code)]
+struct PromotionOptionsV1 {
+    struct_size: usize,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    priority: i32,
+    policy: i32,
+    flags: u32,
+    budget_us: u64,
+    backends: *const *const c_char,
+    backends_count: usize,
+}
+
+/// The size of the first version of `atp_promotion_options`, the smallest `struct_size` accepted.
+pub const ATP_PROMOTION_OPTIONS_SIZE_V1: usize = std::mem::size_of::<PromotionOptionsV1>();
+
+fn invalid(message: &str) -> AudioThreadPriorityError {
+    AudioThreadPriorityError::with_kind(ErrorKind::InvalidArgument, message)
+}
+
+/// Read and check the options at `options`: the buffer size, the sample rate, and the
+/// `PromotionOptions`.
+///
+/// # Safety
+///
+/// `options` must be null, or point to `struct_size` readable bytes, and `backends` to
+/// `backends_count` NUL-terminated strings.
+pub(crate) unsafe fn read_promotion_options(
+    options: *const atp_promotion_options,
+) -> Result<(u32, u32, PromotionOptions), AudioThreadPriorityError> {
+    if options.is_null() {
+        return Err(invalid("options is NULL"));
+    }
+    let size = std::ptr::read_unaligned(options as *const usize);
+    if size < ATP_PROMOTION_OPTIONS_SIZE_V1 {
+        return Err(invalid(&format!(
+            "struct_size is {size}, expected at least {ATP_PROMOTION_OPTIONS_SIZE_V1}"
+        )));
+    }
+    let known = std::mem::size_of::<atp_promotion_options>();
+    if size > known {
+        let unknown = std::slice::from_raw_parts((options as *const u8).add(known), size - known);
+        if unknown.iter().any(|&byte| byte != 0) {
+            return Err(AudioThreadPriorityError::with_kind(
+                ErrorKind::Unsupported,
+                "options this version of the library does not know about are set",
+            ));
+        }
+    }
+    // Only the `struct_size` bytes the caller declared are read: the fields of a later version
+    // than the caller's stay zeroed, which keeps their defaults.
+    let mut raw = std::mem::MaybeUninit::<atp_promotion_options>::zeroed();
+    std::ptr::copy_nonoverlapping(
+        options as *const u8,
+        raw.as_mut_ptr() as *mut u8,
+        size.min(known),
+    );
+    let raw = raw.assume_init();
+
+    let mut options = PromotionOptions::new();
+    match raw.priority {
+        0 => {}
+        priority => {
+            let priority = u8::try_from(priority).map_err(|_| {
+                invalid(&format!(
+                    "invalid real-time priority {priority}, expected an integer 1-99"
+                ))
+            })?;
+            options = options.priority(priority);
+        }
+    }
+    options = match raw.policy {
+        ATP_POLICY_DEFAULT => options,
+        ATP_POLICY_FIFO => options.policy(SchedulingPolicy::Fifo),
+        ATP_POLICY_RR => options.policy(SchedulingPolicy::RoundRobin),
+        ATP_POLICY_DEADLINE => options.policy(SchedulingPolicy::Deadline),
+        policy => return Err(invalid(&format!("unknown policy {policy}"))),
+    };
+    if raw.flags & !ATP_PROMOTION_NO_RESET_ON_FORK != 0 {
+        return Err(invalid(&format!("unknown flags {:#x}", raw.flags)));
+    }
+    if raw.flags & ATP_PROMOTION_NO_RESET_ON_FORK != 0 {
+        options = options.reset_on_fork(false);
+    }
+    if raw.budget_us != 0 {
+        options = options.budget(Duration::from_micros(raw.budget_us));
+    }
+    // The backends are only known on Linux, where the options are used.
+    #[cfg(target_os = "linux")]
+    if !raw.backends.is_null() {
+        let names = std::slice::from_raw_parts(raw.backends, raw.backends_count);
+        let mut backends = Vec::with_capacity(names.len());
+        for &name in names {
+            if name.is_null() {
+                return Err(invalid("a backend name is NULL"));
+            }
+            let name = std::ffi::CStr::from_ptr(name).to_string_lossy();
+            backends.push(
+                crate::rt_linux_chain::backend_named(&name)
+                    .ok_or_else(|| invalid(&format!("no backend is called {name}")))?,
+            );
+        }
+        options = options.backends(&backends);
+    }
+    Ok((raw.audio_buffer_frames, raw.audio_samplerate_hz, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LinuxBackend;
+
+    fn options() -> atp_promotion_options {
+        atp_promotion_options {
+            struct_size: std::mem::size_of::<atp_promotion_options>(),
+            audio_buffer_frames: 512,
+            audio_samplerate_hz: 44100,
+            priority: 0,
+            policy: ATP_POLICY_DEFAULT,
+            flags: 0,
+            budget_us: 0,
+            backends: std::ptr::null(),
+            backends_count: 0,
+        }
+    }
+
+    #[test]
+    fn test_read_promotion_options() {
+        let (frames, rate, defaults) = unsafe { read_promotion_options(&options()) }.unwrap();
+        assert_eq!((frames, rate), (512, 44100));
+        assert_eq!(defaults, PromotionOptions::new());
+
+        let names = [
+            b"native\0".as_ptr() as *const c_char,
+            b"nice\0".as_ptr() as *const c_char,
+        ];
+        let raw = atp_promotion_options {
+            priority: 5,
+            policy: ATP_POLICY_RR,
+            flags: ATP_PROMOTION_NO_RESET_ON_FORK,
+            budget_us: 2000,
+            backends: names.as_ptr(),
+            backends_count: names.len(),
+            ..options()
+        };
+        let (_, _, read) = unsafe { read_promotion_options(&raw) }.unwrap();
+        let expected = PromotionOptions::new()
+            .priority(5)
+            .policy(SchedulingPolicy::RoundRobin)
+            .reset_on_fork(false)
+            .budget(Duration::from_millis(2))
+            .backends(&[LinuxBackend::Native, LinuxBackend::Nice]);
+        if cfg!(target_os = "linux") {
+            assert_eq!(read, expected);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let unknown = [b"unknown\0".as_ptr() as *const c_char];
+            let raw = atp_promotion_options {
+                backends: unknown.as_ptr(),
+                backends_count: unknown.len(),
+                ..options()
+            };
+            assert!(unsafe { read_promotion_options(&raw) }.is_err());
+        }
+
+        let invalid = [
+            atp_promotion_options {
+                struct_size: 8,
+                ..options()
+            },
+            atp_promotion_options {
+                priority: 256,
+                ..options()
+            },
+            atp_promotion_options {
+                policy: 7,
+                ..options()
+            },
+            atp_promotion_options {
+                flags: 2,
+                ..options()
+            },
+        ];
+        for raw in &invalid {
+            let error = unsafe { read_promotion_options(raw) }.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+        }
+        assert!(unsafe { read_promotion_options(std::ptr::null()) }.is_err());
+    }
+
+    // A caller built against the first version passes a struct of `ATP_PROMOTION_OPTIONS_SIZE_V1`
+    // bytes, whatever fields were added since.
+    #[test]
+    fn test_read_first_version_promotion_options() {
+        let v1 = PromotionOptionsV1 {
+            struct_size: ATP_PROMOTION_OPTIONS_SIZE_V1,
+            audio_buffer_frames: 256,
+            audio_samplerate_hz: 48000,
+            priority: 7,
+            policy: ATP_POLICY_FIFO,
+            flags: 0,
+            budget_us: 0,
+            backends: std::ptr::null(),
+            backends_count: 0,
+        };
+        let raw = &v1 as *const PromotionOptionsV1 as *const atp_promotion_options;
+        let (frames, rate, read) = unsafe { read_promotion_options(raw) }.unwrap();
+        assert_eq!((frames, rate), (256, 48000));
+        assert_eq!(
+            read,
+            PromotionOptions::new()
+                .priority(7)
+                .policy(SchedulingPolicy::Fifo)
+        );
+    }
+
+    // A caller built against a later version passes a larger struct: the fields this version does
+    // not know about must be zero.
+    #[test]
+    fn test_read_larger_promotion_options() {
+        #[repr(C)]
+        struct Later {
+            options: atp_promotion_options,
+            extra: u64,
+        }
+        let mut later = Later {
+            options: atp_promotion_options {
+                struct_size: std::mem::size_of::<Later>(),
+                ..options()
+            },
+            extra: 0,
+        };
+        let raw = &later as *const Later as *const atp_promotion_options;
+        assert!(unsafe { read_promotion_options(raw) }.is_ok());
+        later.extra = 1;
+        let raw = &later as *const Later as *const atp_promotion_options;
+        let error = unsafe { read_promotion_options(raw) }.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,121 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A promotion that is undone when it goes out of scope, see [`RtPriorityGuard`].
+
+use std::thread::{self, ThreadId};
+
+use log::warn;
+
+use crate::{
+    demote_current_thread_from_real_time, promote_current_thread_to_real_time_with,
+    AudioThreadPriorityError, PromotionOptions, RtPriorityHandle,
+};
+
+/// A promotion of the calling thread to real-time priority, undone when the guard is dropped.
+///
+/// Dropped on the thread it promoted, the guard demotes it like
+/// `demote_current_thread_from_real_time`. Dropped on another thread, for example after the audio
+/// thread handed it to the thread that stops the stream, it logs a warning. On Linux, the handle
+/// identifies the thread by its tid, so it is demoted all the same. On the other platforms, the
+/// promotion can only be undone from the thread it promoted, so it is left in place.
+///
+/// [`RtPriorityGuard::leak`] and [`RtPriorityGuard::into_raw`] opt out of the demotion.
+pub struct RtPriorityGuard {
+    /// `None` once the promotion was demoted, leaked or handed out.
+    handle: Option<RtPriorityHandle>,
+    owner: ThreadId,
+}
+
+impl RtPriorityGuard {
+    /// The handle of the promotion, for example to know which backend performed it on Linux.
+    pub fn handle(&self) -> &RtPriorityHandle {
+        self.handle
+            .as_ref()
+            .expect("the handle is only taken when the guard is consumed")
+    }
+
+    /// Demote the thread now, to get the error if it fails, which dropping the guard only logs.
+    /// Except on Linux, this must be called on the thread the guard promoted.
+    pub fn demote(mut self) -> Result<(), AudioThreadPriorityError> {
+        demote_current_thread_from_real_time(self.take())
+    }
+
+    /// Keep the thread promoted: nothing is done when the thread exits, or when it is demoted by
+    /// other means.
+    pub fn leak(mut self) {
+        drop(self.take());
+    }
+
+    /// The handle of the promotion, to be passed to `demote_current_thread_from_real_time`: from any
+    /// thread on Linux, where the handle identifies the thread by its tid, and on the promoted thread
+    /// elsewhere. The thread stays promoted if it is dropped.
+    pub fn into_raw(mut self) -> RtPriorityHandle {
+        self.take()
+    }
+
+    fn take(&mut self) -> RtPriorityHandle {
+        self.handle
+            .take()
+            .expect("the handle is only taken when the guard is consumed")
+    }
+}
+
+impl Drop for RtPriorityGuard {
+    fn drop(&mut self) {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return,
+        };
+        if thread::current().id() == self.owner {
+            if let Err(e) = demote_current_thread_from_real_time(handle) {
+                warn!("Could not demote the thread when dropping its guard: {e}");
+            }
+            return;
+        }
+
+        cfg_if::cfg_if! {
+            if #[cfg(target_os = "linux")] {
+                warn!("Real-time guard dropped on another thread, demoting the promoted thread from there.");
+                if let Err(e) = demote_current_thread_from_real_time(handle) {
+                    warn!("Could not demote the thread when dropping its guard: {e}");
+                }
+            } else {
+                warn!("Real-time guard dropped on another thread, the promoted thread stays real-time.");
+                drop(handle);
+            }
+        }
+    }
+}
+
+/// Promote the calling thread to real-time priority, until the returned guard is dropped.
+///
+/// This is `promote_current_thread_to_real_time_with`, with the demotion done by the guard.
+///
+/// # Arguments
+///
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+///   rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+/// * `options` - the options for this promotion.
+///
+/// # Return value
+///
+/// A `RtPriorityGuard` that demotes the thread when dropped, or `Err` if the thread could not be
+/// promoted.
+pub fn promote_current_thread_to_real_time_guarded(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<RtPriorityGuard, AudioThreadPriorityError> {
+    let handle = promote_current_thread_to_real_time_with(
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options,
+    )?;
+    Ok(RtPriorityGuard {
+        handle: Some(handle),
+        owner: thread::current().id(),
+    })
+}
@@ -7,16 +7,78 @@
 //!
 //! - **macOS**: the Mach time-constraint scheduling policy.
 //! - **Windows**: the Multimedia Class Scheduler Service (MMCSS), "Pro Audio" task.
-//! - **Linux** (default, `dbus` feature enabled): rtkit, over D-Bus. This suits unprivileged,
-//!   sandboxed desktop processes, since rtkit performs the privileged scheduling change on their
-//!   behalf.
-//! - **Linux** (`dbus` feature disabled): direct promotion with `pthread_setschedparam` and the
-//!   `SCHED_FIFO` policy. This needs no D-Bus daemon, and works whenever the process may request
-//!   real-time scheduling: running as root, holding `CAP_SYS_NICE`, or with an `RLIMIT_RTPRIO`
-//!   limit configured (e.g. systemd `LimitRTPRIO` or `/etc/security/limits.conf`). The requested
-//!   priority defaults to 10 and can be changed with `set_rt_priority` (Linux, no-`dbus` only).
+//! - **Linux**: a chain of backends, tried in turn until one succeeds. The handle tells which one
+//!   did (`RtPriorityHandle::backend`), and if they all fail, the error says why each one did. The
+//!   chain is set for the process with `set_linux_backends`, or for one promotion with
+//!   `PromotionOptions::backends`. The backends are:
+//!   - `LinuxBackend::Rtkit`: rtkit, over D-Bus. This suits unprivileged desktop processes, since
+//!     rtkit performs the privileged scheduling change on their behalf. The connection to the
+//!     system bus is opened on first use and then reused by the whole process;
+//!     `set_rtkit_timeout` changes how long to wait for rtkit (10 seconds by default). It is not
+//!     used inside a Flatpak or Snap sandbox, where the system bus is not reachable.
+//!   - `LinuxBackend::Portal`: the `org.freedesktop.portal.Realtime` interface of
+//!     xdg-desktop-portal on the session bus, which forwards requests from sandboxed processes to
+//!     rtkit.
+//!   - `LinuxBackend::Native`: direct promotion with `sched_setscheduler` and the `SCHED_FIFO`
+//!     policy. This needs no D-Bus daemon, and works whenever the process may request real-time
+//!     scheduling: running as root, holding `CAP_SYS_NICE`, or with an `RLIMIT_RTPRIO` limit
+//!     configured (e.g. systemd `LimitRTPRIO` or `/etc/security/limits.conf`). The requested
+//!     priority defaults to 10 and can be changed with `set_rt_priority`; it is lowered to the
+//!     hard `RLIMIT_RTPRIO` limit if that is lower, and `RtPriorityHandle::granted_priority` tells
+//!     the priority the thread got. To promote a thread of the calling process, the soft
+//!     `RLIMIT_RTPRIO` limit is raised up to the hard limit if it is in the way: meanwhile, any
+//!     thread of the process may make itself real-time up to that priority. It is restored once
+//!     the threads promoted since are demoted. The limits of other processes are left alone.
+//!   - `LinuxBackend::Nice`: a lower nice level, which is not real-time scheduling. This is a last
+//!     resort, and is only used if added to the chain.
+//!   - `LinuxBackend::Custom`: a mechanism of the application, such as a privileged helper process,
+//!     implementing `PriorityBackend` and registered with `register_priority_backend`.
+//!
+//!   The default chain is rtkit, the portal, then the native backend. The D-Bus backends need the
+//!   `dbus` feature (enabled by default), or the `with_rust_dbus` feature, which uses `zbus`, a
+//!   D-Bus client written in Rust, instead of the C `libdbus-1`, making static linking and
+//!   cross-compiling simpler. zbus does not survive `fork`: in a child forked from a process that
+//!   used it, the D-Bus backends fail with `ErrorKind::ServiceUnavailable`. Without either
+//!   feature, the default chain is the native backend alone.
+//! - **Linux** (opt-in): `promote_current_thread_to_real_time_deadline` uses the `SCHED_DEADLINE`
+//!   policy, with a runtime, deadline and period derived from the buffer duration. rtkit cannot
+//!   grant it, so it is set by the native backend, and needs `CAP_SYS_NICE`.
 //! - **Other platforms**: a no-op that reports success.
 //!
+//! On Linux, `promote_current_thread_to_real_time_with` and `promote_thread_to_real_time_with` take
+//! `PromotionOptions` to choose the priority, policy, reset-on-fork behaviour and budget of a single
+//! promotion. `promote_current_thread_to_real_time_async` and
+//! `promote_current_thread_to_real_time_with_callback` request the promotion from a helper thread,
+//! so that the calling thread does not block on rtkit. `update_real_time_parameters` adapts a
+//! promotion to a new buffer size or sample rate, without demoting the thread.
+//!
+//! A `RtPriorityHandle` that is dropped leaves the thread real-time.
+//! `promote_current_thread_to_real_time_guarded` returns a `RtPriorityGuard` instead, which demotes
+//! the thread when dropped.
+//!
+//! On Linux, a real-time thread that runs past its `RLIMIT_RTTIME` soft limit gets `SIGXCPU`, which
+//! terminates the process by default. `install_sigxcpu_handler` installs a handler that demotes the
+//! thread instead, and records the event, to be read with `take_sigxcpu_events`.
+//!
+//! The `limits` module reads and sets the resource limits of the process that matter for
+//! real-time threads on Linux: `RLIMIT_RTTIME`, `RLIMIT_RTPRIO`, `RLIMIT_NICE` and
+//! `RLIMIT_MEMLOCK`.
+//!
+//! `AudioThreadPriorityError::kind` classifies a failure (permission denied, service unavailable,
+//! limit exceeded...), so that callers can react to it without parsing the message. The error
+//! also carries the OS error code (`raw_os_error`) or the D-Bus error name (`dbus_error_name`) it
+//! comes from, if any. In the C API, the functions return the matching negative `ATP_ERROR_*`
+//! code, or NULL, and `atp_last_error_code` and `atp_last_error_message` tell why the last call
+//! failed on the calling thread. `atp_promote_current_thread_to_real_time_ex` and
+//! `atp_promote_thread_to_real_time_ex` take an `atp_promotion_options`, the C counterpart of
+//! `PromotionOptions`.
+//!
+//! The crate logs through the `log` facade. C and C++ hosts can receive the log records with
+//! `atp_set_log_callback`.
+//!
+//! With the `testing` feature, the `testing` module has a fake Linux backend that records
+//! promotions instead of performing them, for the unit tests of applications.
+//!
 //! # Example
 //!
 //! ```rust
@@ -52,28 +114,184 @@ use cfg_if::cfg_if;
 use std::error::Error;
 use std::fmt;
 
-/// The OS-specific issue is available as `inner`
+mod ffi_error;
+mod ffi_log;
+mod ffi_options;
+mod guard;
+mod options;
+pub use ffi_error::{
+    atp_last_error_code, atp_last_error_message, ATP_ERROR_INVALID_ARGUMENT,
+    ATP_ERROR_LIMIT_EXCEEDED, ATP_ERROR_OTHER, ATP_ERROR_PERMISSION_DENIED,
+    ATP_ERROR_SERVICE_UNAVAILABLE, ATP_ERROR_THREAD_NOT_FOUND, ATP_ERROR_TIMEOUT,
+    ATP_ERROR_UNSUPPORTED, ATP_OK,
+};
+use ffi_error::{invalid_argument, set_last_error};
+pub use ffi_log::{
+    atp_log_callback, atp_set_log_callback, ATP_LOG_DEBUG, ATP_LOG_ERROR, ATP_LOG_INFO,
+    ATP_LOG_TRACE, ATP_LOG_WARN,
+};
+use ffi_options::read_promotion_options;
+pub use ffi_options::{
+    atp_promotion_options, ATP_POLICY_DEADLINE, ATP_POLICY_DEFAULT, ATP_POLICY_FIFO, ATP_POLICY_RR,
+    ATP_PROMOTION_NO_RESET_ON_FORK, ATP_PROMOTION_OPTIONS_SIZE_V1,
+};
+pub use guard::{promote_current_thread_to_real_time_guarded, RtPriorityGuard};
+pub use options::{LinuxBackend, PromotionOptions, SchedulingPolicy};
+
+/// What went wrong, to react to an `AudioThreadPriorityError` without matching on its message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The process is not allowed to change the scheduling of the thread, for example because it is
+    /// not privileged and has no `RLIMIT_RTPRIO` limit, or rtkit refused.
+    PermissionDenied,
+    /// The service performing the promotion, such as rtkit or the D-Bus daemon, is not running or
+    /// cannot be reached.
+    ServiceUnavailable,
+    /// A limit was in the way: rtkit's maximum priority or budget, the `RLIMIT_RTTIME` hard limit,
+    /// or the CPU time left for `SCHED_DEADLINE` reservations.
+    LimitExceeded,
+    /// An argument is invalid, such as a sample rate of zero or a priority above 99.
+    InvalidArgument,
+    /// The thread to promote or demote does not exist, or has exited.
+    ThreadNotFound,
+    /// The platform, the build or the backend cannot do what was asked.
+    Unsupported,
+    /// The service performing the promotion did not answer in time.
+    Timeout,
+    /// Any other error.
+    Other,
+}
+
+/// The OS-specific issue is available as `inner`, and its category as `kind`.
 #[derive(Debug)]
 pub struct AudioThreadPriorityError {
     message: String,
-    inner: Option<Box<dyn Error + 'static>>,
+    inner: Option<Box<dyn Error + Send + Sync + 'static>>,
+    kind: ErrorKind,
+    raw_os_error: Option<i32>,
+    dbus_error_name: Option<String>,
 }
 
 impl AudioThreadPriorityError {
-    cfg_if! {
-        if #[cfg(all(target_os = "linux", feature = "dbus"))] {
-            fn new_with_inner(message: &str, inner: Box<dyn Error>) -> AudioThreadPriorityError {
-                AudioThreadPriorityError {
-                    message: message.into(),
-                    inner: Some(inner),
-                }
-            }
+    /// An error caused by `inner`. The kind, the OS error code and the D-Bus error name are taken
+    /// from `inner` when it is an `std::io::Error` or an `AudioThreadPriorityError`.
+    #[allow(dead_code)]
+    fn new_with_inner(
+        message: &str,
+        inner: Box<dyn Error + Send + Sync>,
+    ) -> AudioThreadPriorityError {
+        let mut error = AudioThreadPriorityError::new(message);
+        if let Some(io_error) = inner.downcast_ref::<std::io::Error>() {
+            error.kind = ErrorKind::of_io_error(io_error);
+            error.raw_os_error = io_error.raw_os_error();
+        } else if let Some(cause) = inner.downcast_ref::<AudioThreadPriorityError>() {
+            error.kind = cause.kind;
+            error.raw_os_error = cause.raw_os_error;
+            error.dbus_error_name = cause.dbus_error_name.clone();
         }
+        error.inner = Some(inner);
+        error
     }
-    fn new(message: &str) -> AudioThreadPriorityError {
+
+    /// An error with `message` and no inner error, for example for a `PriorityBackend`
+    /// implemented by the application. Its kind is `ErrorKind::Other`.
+    pub fn new(message: &str) -> AudioThreadPriorityError {
+        AudioThreadPriorityError::with_kind(ErrorKind::Other, message)
+    }
+
+    /// An error of kind `kind`, with `message` and no inner error.
+    pub fn with_kind(kind: ErrorKind, message: &str) -> AudioThreadPriorityError {
         AudioThreadPriorityError {
             message: message.into(),
             inner: None,
+            kind,
+            raw_os_error: None,
+            dbus_error_name: None,
+        }
+    }
+
+    /// An error caused by the OS error `error`, taking its kind and error code.
+    #[allow(dead_code)]
+    fn from_os_error(message: &str, error: &std::io::Error) -> AudioThreadPriorityError {
+        AudioThreadPriorityError {
+            raw_os_error: error.raw_os_error(),
+            ..AudioThreadPriorityError::with_kind(ErrorKind::of_io_error(error), message)
+        }
+    }
+
+    /// An error returned by a D-Bus service, with its D-Bus error name, such as
+    /// `org.freedesktop.DBus.Error.AccessDenied`.
+    #[allow(dead_code)]
+    fn from_dbus(name: &str, message: &str) -> AudioThreadPriorityError {
+        AudioThreadPriorityError {
+            dbus_error_name: Some(name.into()),
+            ..AudioThreadPriorityError::with_kind(
+                ErrorKind::of_dbus_error(name),
+                &format!("{name}:{message}"),
+            )
+        }
+    }
+
+    /// What went wrong.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The OS error code (`errno`, or `GetLastError` on Windows) that caused this error, if any.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.raw_os_error
+    }
+
+    /// The name of the D-Bus error that caused this error, if any, such as
+    /// `org.freedesktop.DBus.Error.ServiceUnknown` when rtkit is not running.
+    pub fn dbus_error_name(&self) -> Option<&str> {
+        self.dbus_error_name.as_deref()
+    }
+}
+
+impl ErrorKind {
+    fn of_io_error(error: &std::io::Error) -> ErrorKind {
+        cfg_if! {
+            if #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))] {
+                match error.raw_os_error() {
+                    Some(libc::ESRCH) => return ErrorKind::ThreadNotFound,
+                    // `SCHED_DEADLINE` admission control.
+                    Some(libc::EBUSY) => return ErrorKind::LimitExceeded,
+                    _ => {}
+                }
+            }
+        }
+        match error.kind() {
+            std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            std::io::ErrorKind::InvalidInput => ErrorKind::InvalidArgument,
+            std::io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ErrorKind::Timeout,
+            std::io::ErrorKind::NotFound
+            | std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::BrokenPipe => ErrorKind::ServiceUnavailable,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn of_dbus_error(name: &str) -> ErrorKind {
+        match name.strip_prefix("org.freedesktop.DBus.Error.") {
+            Some("AccessDenied" | "AuthFailed" | "InteractiveAuthorizationRequired") => {
+                ErrorKind::PermissionDenied
+            }
+            Some(
+                "ServiceUnknown" | "NameHasNoOwner" | "NoServer" | "Disconnected" | "NoNetwork"
+                | "FileNotFound" | "SpawnFailed" | "SpawnExecFailed" | "SpawnChildExited",
+            ) => ErrorKind::ServiceUnavailable,
+            Some("NoReply" | "Timeout" | "TimedOut") => ErrorKind::Timeout,
+            Some("LimitsExceeded") => ErrorKind::LimitExceeded,
+            Some("InvalidArgs" | "InvalidSignature") => ErrorKind::InvalidArgument,
+            Some("UnknownMethod" | "UnknownInterface" | "UnknownProperty" | "NotSupported") => {
+                ErrorKind::Unsupported
+            }
+            _ => ErrorKind::Other,
         }
     }
 }
@@ -94,7 +312,9 @@ impl Error for AudioThreadPriorityError {
     }
 
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.inner.as_ref().map(|e| e.as_ref())
+        self.inner
+            .as_ref()
+            .map(|e| e.as_ref() as &(dyn Error + 'static))
     }
 }
 
@@ -105,43 +325,49 @@ cfg_if! {
         extern crate libc;
         use rt_mach::promote_current_thread_to_real_time_internal;
         use rt_mach::demote_current_thread_from_real_time_internal;
+        use rt_mach::update_real_time_parameters_internal;
         use rt_mach::RtPriorityHandleInternal;
     } else if #[cfg(target_os = "windows")] {
         mod rt_win;
         use rt_win::promote_current_thread_to_real_time_internal;
         use rt_win::demote_current_thread_from_real_time_internal;
         use rt_win::RtPriorityHandleInternal;
-    } else if #[cfg(all(target_os = "linux", feature = "dbus"))] {
-        mod rt_linux;
-        extern crate dbus;
-        extern crate libc;
-        use rt_linux::promote_current_thread_to_real_time_internal;
-        use rt_linux::demote_current_thread_from_real_time_internal;
-        use rt_linux::set_real_time_hard_limit_internal as set_real_time_hard_limit;
-        use rt_linux::get_current_thread_info_internal;
-        use rt_linux::promote_thread_to_real_time_internal;
-        use rt_linux::demote_thread_from_real_time_internal;
-        use rt_linux::RtPriorityThreadInfoInternal;
-        use rt_linux::RtPriorityHandleInternal;
-        #[no_mangle]
-        /// Size of a RtPriorityThreadInfo or atp_thread_info struct, for use in FFI.
-        pub static ATP_THREAD_INFO_SIZE: usize = std::mem::size_of::<RtPriorityThreadInfo>();
     } else if #[cfg(target_os = "linux")] {
-        // Linux without the `dbus` feature: promote directly with SCHED_FIFO instead of no-oping.
+        // The backends are tried in turn at run time, see `rt_linux_chain`.
+        #[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+        mod rt_linux;
+        #[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+        mod rt_linux_bus;
+        #[cfg(all(any(feature = "dbus", feature = "with_rust_dbus"), any(test, feature = "testing")))]
+        mod rt_linux_fake_rtkit;
+        mod rt_linux_chain;
+        mod rt_linux_deadline;
         mod rt_linux_native;
+        mod rt_linux_nice;
+        mod rt_linux_rttime;
+        mod rt_linux_sigxcpu;
+        #[cfg(test)]
+        mod test_child;
         extern crate libc;
-        use rt_linux_native::promote_current_thread_to_real_time_internal;
-        use rt_linux_native::demote_current_thread_from_real_time_internal;
-        use rt_linux_native::set_real_time_hard_limit_internal as set_real_time_hard_limit;
-        use rt_linux_native::get_current_thread_info_internal;
-        use rt_linux_native::promote_thread_to_real_time_internal;
-        use rt_linux_native::demote_thread_from_real_time_internal;
+        use rt_linux_chain::promote_current_thread_to_real_time_internal;
+        use rt_linux_chain::demote_current_thread_from_real_time_internal;
+        use rt_linux_chain::update_real_time_parameters_internal;
+        use rt_linux_chain::set_real_time_hard_limit_internal as set_real_time_hard_limit;
+        use rt_linux_chain::get_current_thread_info_internal;
+        use rt_linux_chain::promote_thread_to_real_time_internal;
+        use rt_linux_chain::demote_thread_from_real_time_internal;
         use rt_linux_native::RtPriorityThreadInfoInternal;
-        use rt_linux_native::RtPriorityHandleInternal;
+        use rt_linux_chain::RtPriorityHandleInternal;
+        pub use rt_linux_chain::{register_priority_backend, set_linux_backends};
         pub use rt_linux_native::set_rt_priority;
+        pub use rt_linux_sigxcpu::{
+            install_sigxcpu_handler, take_sigxcpu_events, uninstall_sigxcpu_handler, SigxcpuEvent,
+        };
+        #[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+        pub use rt_linux::set_rtkit_timeout;
         #[no_mangle]
-        /// Size of a RtPriorityThreadInfo or atp_thread_info struct, for use in FFI.
-        pub static ATP_THREAD_INFO_SIZE: usize = std::mem::size_of::<RtPriorityThreadInfo>();
+        /// Size of a serialized RtPriorityThreadInfo or atp_thread_info struct, for use in FFI.
+        pub static ATP_THREAD_INFO_SIZE: usize = RtPriorityThreadInfo::SERIALIZED_SIZE;
     } else if #[cfg(target_os = "android")] {
         mod rt_android;
         use rt_android::promote_current_thread_to_real_time_internal;
@@ -176,7 +402,7 @@ cfg_if! {
         /// Fallback implementation that performs no operation for unsupported platforms.
         pub fn promote_current_thread_to_real_time_internal(_: u32, audio_samplerate_hz: u32) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
             if audio_samplerate_hz == 0 {
-                return Err(AudioThreadPriorityError{message: "sample rate is zero".to_string(), inner: None});
+                return Err(AudioThreadPriorityError::with_kind(ErrorKind::InvalidArgument, "sample rate is zero"));
             }
             // no-op
             Ok(RtPriorityHandle{})
@@ -204,7 +430,7 @@ cfg_if! {
             audio_samplerate_hz: u32,
         ) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
             if audio_samplerate_hz == 0 {
-                return Err(AudioThreadPriorityError::new("sample rate is zero"));
+                return Err(AudioThreadPriorityError::with_kind(ErrorKind::InvalidArgument, "sample rate is zero"));
             }
             Ok(RtPriorityHandle{})
         }
@@ -220,14 +446,27 @@ cfg_if! {
 }
 
 /// Opaque handle to a thread handle structure.
+///
+/// On Linux, `granted_priority` tells the real-time priority the thread was actually given, which
+/// can be lower than the one requested.
 pub type RtPriorityHandle = RtPriorityHandleInternal;
 
 cfg_if! {
     if #[cfg(target_os = "linux")] {
+mod async_promotion;
+mod backend;
+pub use async_promotion::PendingPromotion;
+pub use backend::{BackendPromotion, PriorityBackend};
+pub mod limits;
+#[cfg(feature = "testing")]
+pub mod testing;
+
 /// Opaque handle to a thread's scheduling information.
 ///
-/// This can be serialized to raw bytes and sent to another process via IPC, so that process can
-/// promote the thread to real-time priority on its behalf.
+/// This can be serialized to bytes with `serialize` and sent to another process via IPC, so that
+/// process can promote the thread to real-time priority on its behalf. The bytes are versioned and
+/// checksummed, and `try_deserialize` rejects a truncated, corrupted or incompatible message. The
+/// encoding is the same on all Linux targets, so the two processes may be 32-bit and 64-bit.
 ///
 /// This is useful on Linux only, to promote a thread from another process or thread when the
 /// thread to promote cannot do so itself (for example because it is sandboxed).
@@ -255,7 +494,7 @@ pub fn get_current_thread_info() -> Result<RtPriorityThreadInfo, AudioThreadPrio
 /// thread to promote cannot do so itself (for example because it is sandboxed).
 pub fn thread_info_serialize(
     thread_info: RtPriorityThreadInfo,
-) -> [u8; std::mem::size_of::<RtPriorityThreadInfo>()] {
+) -> [u8; RtPriorityThreadInfo::SERIALIZED_SIZE] {
     thread_info.serialize()
 }
 
@@ -267,12 +506,36 @@ pub fn thread_info_serialize(
 /// # Arguments
 ///
 /// A byte buffer containing a serialized `RtPriorityThreadInfo`.
+///
+/// # Return value
+///
+/// If `bytes` is not a valid serialized `RtPriorityThreadInfo`, see `thread_info_try_deserialize`,
+/// a thread info that no thread has, which cannot be promoted or demoted.
+#[deprecated(note = "use `thread_info_try_deserialize`, which returns an error for invalid bytes")]
 pub fn thread_info_deserialize(
-    bytes: [u8; std::mem::size_of::<RtPriorityThreadInfo>()],
+    bytes: [u8; RtPriorityThreadInfo::SERIALIZED_SIZE],
 ) -> RtPriorityThreadInfo {
+    #[allow(deprecated)]
     RtPriorityThreadInfoInternal::deserialize(bytes)
 }
 
+/// From a byte buffer, return a `RtPriorityThreadInfo`, or an error if the buffer does not hold a
+/// thread info serialized by a compatible version of this crate: its length, magic number, format
+/// version and checksum are checked, so that a stale or corrupted message cannot promote an
+/// arbitrary thread.
+///
+/// This is useful on Linux only, to promote a thread from another process or thread when the
+/// thread to promote cannot do so itself (for example because it is sandboxed).
+///
+/// # Arguments
+///
+/// A byte buffer containing a serialized `RtPriorityThreadInfo`.
+pub fn thread_info_try_deserialize(
+    bytes: &[u8],
+) -> Result<RtPriorityThreadInfo, AudioThreadPriorityError> {
+    RtPriorityThreadInfoInternal::try_deserialize(bytes)
+}
+
 /// Get the calling thread's information, to promote it from another process or thread, with a C
 /// API.
 ///
@@ -284,12 +547,16 @@ pub fn thread_info_deserialize(
 /// # Return value
 ///
 /// A pointer to a struct that can be serialized and deserialized, and that can be passed to
-/// `atp_promote_thread_to_real_time`, even from another process.
+/// `atp_promote_thread_to_real_time`, even from another process. NULL in case of error, see
+/// `atp_last_error_code` and `atp_last_error_message`.
 #[no_mangle]
 pub extern "C" fn atp_get_current_thread_info() -> *mut atp_thread_info {
     match get_current_thread_info() {
         Ok(thread_info) => Box::into_raw(Box::new(atp_thread_info(thread_info))),
-        _ => std::ptr::null_mut(),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
     }
 }
 
@@ -301,7 +568,7 @@ pub extern "C" fn atp_get_current_thread_info() -> *mut atp_thread_info {
 ///
 /// # Return value
 ///
-/// 0 in case of success, 1 otherwise (if `thread_info` is NULL).
+/// 0 in case of success, `ATP_ERROR_INVALID_ARGUMENT` otherwise (if `thread_info` is NULL).
 ///
 /// # Safety
 ///
@@ -309,7 +576,7 @@ pub extern "C" fn atp_get_current_thread_info() -> *mut atp_thread_info {
 #[no_mangle]
 pub unsafe extern "C" fn atp_free_thread_info(thread_info: *mut atp_thread_info) -> i32 {
     if thread_info.is_null() {
-        return 1;
+        return invalid_argument("thread_info is NULL");
     }
     drop(Box::from_raw(thread_info));
     0
@@ -318,7 +585,7 @@ pub unsafe extern "C" fn atp_free_thread_info(thread_info: *mut atp_thread_info)
 /// Return a byte buffer containing serialized information about a thread, to promote it to
 /// real-time from elsewhere, with a C API.
 ///
-/// `bytes` MUST be `std::mem::size_of<RtPriorityThreadInfo>()` bytes long.
+/// `bytes` MUST be `RtPriorityThreadInfo::SERIALIZED_SIZE` bytes long.
 ///
 /// This is exposed in the C API as `ATP_THREAD_INFO_SIZE`.
 ///
@@ -349,6 +616,11 @@ pub unsafe extern "C" fn atp_serialize_thread_info(
 ///
 /// A byte buffer containing a serialized `RtPriorityThreadInfo`.
 ///
+/// # Return value
+///
+/// A pointer to an `atp_thread_info`, or NULL if the buffer does not hold a valid serialized
+/// thread info, see `atp_try_deserialize_thread_info`.
+///
 /// # Safety
 ///
 /// This function is safe only and only if pointer is at least ATP_THREAD_INFO_SIZE bytes long.
@@ -356,9 +628,45 @@ pub unsafe extern "C" fn atp_serialize_thread_info(
 pub unsafe extern "C" fn atp_deserialize_thread_info(
     in_bytes: *mut u8,
 ) -> *mut atp_thread_info {
-    let bytes = *(in_bytes as *mut [u8; std::mem::size_of::<RtPriorityThreadInfoInternal>()]);
-    let thread_info = RtPriorityThreadInfoInternal::deserialize(bytes);
-    Box::into_raw(Box::new(atp_thread_info(thread_info)))
+    atp_try_deserialize_thread_info(in_bytes, RtPriorityThreadInfo::SERIALIZED_SIZE)
+}
+
+/// From a byte buffer of `length` bytes, return a `RtPriorityThreadInfo`, with a C API. Unlike
+/// `atp_deserialize_thread_info`, this does not trust the buffer to be `ATP_THREAD_INFO_SIZE` bytes
+/// long.
+///
+/// # Arguments
+///
+/// `bytes` - a byte buffer containing a serialized `RtPriorityThreadInfo`.
+/// `length` - the length of the buffer.
+///
+/// # Return value
+///
+/// A pointer to an `atp_thread_info`, to be freed with `atp_free_thread_info`, or NULL if the
+/// buffer does not hold a thread info serialized by a compatible version of this library (wrong
+/// length, magic number, format version or checksum), see `atp_last_error_code` and
+/// `atp_last_error_message`.
+///
+/// # Safety
+///
+/// This function is safe only and only if `bytes` is null or at least `length` bytes long.
+#[no_mangle]
+pub unsafe extern "C" fn atp_try_deserialize_thread_info(
+    bytes: *const u8,
+    length: usize,
+) -> *mut atp_thread_info {
+    if bytes.is_null() {
+        invalid_argument("bytes is NULL");
+        return std::ptr::null_mut();
+    }
+    let bytes = std::slice::from_raw_parts(bytes, length);
+    match RtPriorityThreadInfoInternal::try_deserialize(bytes) {
+        Ok(thread_info) => Box::into_raw(Box::new(atp_thread_info(thread_info))),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
+    }
 }
 
 /// Promote a particular thread to real-time priority.
@@ -382,19 +690,59 @@ pub fn promote_thread_to_real_time(
     thread_info: RtPriorityThreadInfo,
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
+) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+    promote_thread_to_real_time_with(
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        &PromotionOptions::default(),
+    )
+}
+
+/// Promote a particular thread to real-time priority, with options for this promotion only.
+///
+/// This is `promote_thread_to_real_time`, with the priority, scheduling policy, reset-on-fork
+/// behaviour and budget taken from `options` instead of the backend's defaults.
+///
+/// # Arguments
+///
+/// * `thread_info` - information about the thread to promote, gathered using
+/// `get_current_thread_info`.
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+/// rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+/// * `options` - the options for this promotion.
+///
+/// # Return value
+///
+/// This function returns a `Result<RtPriorityHandle>`, which is an opaque struct to be passed to
+/// `demote_current_thread_from_real_time` to revert to the previous thread priority. It is an error
+/// if the backend cannot honour `options`.
+pub fn promote_thread_to_real_time_with(
+    thread_info: RtPriorityThreadInfo,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
 ) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
     if audio_samplerate_hz == 0 {
-        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+        return Err(AudioThreadPriorityError::with_kind(ErrorKind::InvalidArgument, "sample rate is zero"));
     }
     promote_thread_to_real_time_internal(
         thread_info,
         audio_buffer_frames,
         audio_samplerate_hz,
+        options,
     )
 }
 
 /// Demotes a thread from real-time priority.
 ///
+/// On Linux, this restores the scheduling policy and priority saved in `thread_info`. If this
+/// process promoted the thread, the backend that promoted it is tried first, so that a thread
+/// promoted by a `LinuxBackend::Custom` backend is demoted by it; otherwise, the first backend of
+/// the chain that succeeds demotes it. A nice level lowered by `LinuxBackend::Nice` is only
+/// restored by `demote_current_thread_from_real_time`, with the handle of the promotion.
+///
 /// # Arguments
 ///
 /// * `thread_info` - An opaque struct returned from a successful call to
@@ -407,6 +755,115 @@ pub fn demote_thread_from_real_time(thread_info: RtPriorityThreadInfo) -> Result
     demote_thread_from_real_time_internal(thread_info)
 }
 
+/// Promote the calling thread to real-time priority with the `SCHED_DEADLINE` policy.
+///
+/// Rather than a fixed priority, the thread gets a CPU reservation derived from the callback
+/// duration: its period and deadline are the duration of one buffer, and it is guaranteed half of
+/// that as runtime. This is opt-in, and only the native backend can set it, so it always needs
+/// `CAP_SYS_NICE`. It fails with a clear error when the kernel's admission control cannot fit the
+/// reservation.
+///
+/// This is available on Linux only.
+///
+/// # Arguments
+///
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+/// rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+///
+/// # Return value
+///
+/// This function returns a `Result<RtPriorityHandle>`, which is an opaque struct to be passed to
+/// `demote_current_thread_from_real_time` to restore the previous scheduling attributes.
+pub fn promote_current_thread_to_real_time_deadline(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+    promote_current_thread_to_real_time_with(
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        &PromotionOptions::new().policy(SchedulingPolicy::Deadline),
+    )
+}
+
+/// Promote the calling thread to real-time priority, without waiting for the promotion to complete.
+///
+/// The promotion request is sent from a helper thread, so this returns immediately, and the calling
+/// thread keeps running at its current priority until the promotion completes. This avoids blocking
+/// an audio thread on a D-Bus round trip to rtkit, which can take up to the rtkit timeout.
+///
+/// This is available on Linux only.
+///
+/// # Arguments
+///
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+/// rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+/// * `options` - the options for this promotion.
+///
+/// # Return value
+///
+/// A `PendingPromotion`, to poll or wait for the result. Once complete, the `RtPriorityHandle` it
+/// yields is to be passed to `demote_current_thread_from_real_time` on the calling thread, like the
+/// one returned by `promote_current_thread_to_real_time`.
+pub fn promote_current_thread_to_real_time_async(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<PendingPromotion, AudioThreadPriorityError> {
+    if audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::with_kind(ErrorKind::InvalidArgument, "sample rate is zero"));
+    }
+    let thread_info = get_current_thread_info()?;
+    async_promotion::promote_async(
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options,
+    )
+}
+
+/// Promote the calling thread to real-time priority, without waiting for the promotion to
+/// complete, and call `callback` with the result.
+///
+/// This is `promote_current_thread_to_real_time_async`, with a callback instead of a token.
+/// `callback` is called on the helper thread that performed the promotion, so it should not block.
+///
+/// This is available on Linux only.
+///
+/// # Arguments
+///
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+/// rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+/// * `options` - the options for this promotion.
+/// * `callback` - called with the result of the promotion.
+///
+/// # Return value
+///
+/// `Ok` if the promotion was requested, `Err` otherwise, in which case `callback` is not called.
+pub fn promote_current_thread_to_real_time_with_callback<F>(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+    callback: F,
+) -> Result<(), AudioThreadPriorityError>
+where
+    F: FnOnce(Result<RtPriorityHandle, AudioThreadPriorityError>) + Send + 'static,
+{
+    if audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::with_kind(ErrorKind::InvalidArgument, "sample rate is zero"));
+    }
+    let thread_info = get_current_thread_info()?;
+    async_promotion::promote_with_callback(
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options,
+        Box::new(callback),
+    )
+}
+
 /// Opaque info to a particular thread.
 #[allow(non_camel_case_types)]
 pub struct atp_thread_info(RtPriorityThreadInfo);
@@ -426,21 +883,69 @@ pub struct atp_thread_info(RtPriorityThreadInfo);
 ///
 /// # Return value
 ///
-/// A pointer to an `atp_handle` in case of success, NULL otherwise.
+/// A pointer to an `atp_handle` in case of success, NULL otherwise, see `atp_last_error_code` and
+/// `atp_last_error_message`.
 ///
 /// # Safety
 ///
-/// This function is safe as long as the first pointer comes from this library.
+/// This function is safe as long as the first pointer comes from this library, or is null.
 #[no_mangle]
 pub unsafe extern "C" fn atp_promote_thread_to_real_time(
     thread_info: *mut atp_thread_info,
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
 ) -> *mut atp_handle {
+    if thread_info.is_null() {
+        invalid_argument("thread_info is NULL");
+        return std::ptr::null_mut();
+    }
     let thread_info = &mut *thread_info;
     match promote_thread_to_real_time(thread_info.0, audio_buffer_frames, audio_samplerate_hz) {
         Ok(handle) => Box::into_raw(Box::new(atp_handle(handle))),
-        _ => std::ptr::null_mut(),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Promote a specific thread to real-time, with options, with a C API. This is
+/// `promote_thread_to_real_time_with`, for C.
+///
+/// # Arguments
+///
+/// `thread_info` - the information of the thread to promote to real-time, gather from calling
+/// `atp_get_current_thread_info` on the thread to promote.
+/// `options` - the buffer size, sample rate and options of the promotion.
+///
+/// # Return value
+///
+/// A pointer to an `atp_handle` in case of success, NULL otherwise, see `atp_last_error_code` and
+/// `atp_last_error_message`.
+///
+/// # Safety
+///
+/// This function is safe as long as the first pointer comes from this library, or is null, and the
+/// second one is null or points to a valid `atp_promotion_options` of `struct_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn atp_promote_thread_to_real_time_ex(
+    thread_info: *mut atp_thread_info,
+    options: *const atp_promotion_options,
+) -> *mut atp_handle {
+    if thread_info.is_null() {
+        invalid_argument("thread_info is NULL");
+        return std::ptr::null_mut();
+    }
+    let thread_info = &mut *thread_info;
+    let promotion = read_promotion_options(options).and_then(|(frames, rate, options)| {
+        promote_thread_to_real_time_with(thread_info.0, frames, rate, &options)
+    });
+    match promotion {
+        Ok(handle) => Box::into_raw(Box::new(atp_handle(handle))),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
     }
 }
 
@@ -452,7 +957,7 @@ pub unsafe extern "C" fn atp_promote_thread_to_real_time(
 ///
 /// # Return value
 ///
-/// 0 in case of success, non-zero otherwise.
+/// 0 in case of success, a negative `ATP_ERROR_*` code otherwise.
 ///
 /// # Safety
 ///
@@ -460,13 +965,13 @@ pub unsafe extern "C" fn atp_promote_thread_to_real_time(
 #[no_mangle]
 pub unsafe extern "C" fn atp_demote_thread_from_real_time(thread_info: *mut atp_thread_info) -> i32 {
     if thread_info.is_null() {
-        return 1;
+        return invalid_argument("thread_info is NULL");
     }
     let thread_info = (*thread_info).0;
 
     match demote_thread_from_real_time(thread_info) {
         Ok(_) => 0,
-        _ => 1,
+        Err(e) => set_last_error(&e),
     }
 }
 
@@ -480,15 +985,14 @@ pub unsafe extern "C" fn atp_demote_thread_from_real_time(thread_info: *mut atp_
 ///
 /// # Return value
 ///
-/// 0 in case of success, 1 otherwise.
+/// 0 in case of success, a negative `ATP_ERROR_*` code otherwise.
 #[no_mangle]
 pub extern "C" fn atp_set_real_time_limit(audio_buffer_frames: u32,
                                           audio_samplerate_hz: u32) -> i32 {
-    let r = set_real_time_hard_limit(audio_buffer_frames, audio_samplerate_hz);
-    if r.is_err() {
-        return 1;
+    match set_real_time_hard_limit(audio_buffer_frames, audio_samplerate_hz) {
+        Ok(_) => 0,
+        Err(e) => set_last_error(&e),
     }
-    0
 }
 
 }
@@ -509,15 +1013,63 @@ pub extern "C" fn atp_set_real_time_limit(audio_buffer_frames: u32,
 pub fn promote_current_thread_to_real_time(
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
+) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
+    promote_current_thread_to_real_time_with(
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        &PromotionOptions::default(),
+    )
+}
+
+/// Promote the calling thread to real-time priority, with options for this promotion only.
+///
+/// This is `promote_current_thread_to_real_time`, with the priority, scheduling policy,
+/// reset-on-fork behaviour and budget taken from `options` instead of the backend's defaults. The
+/// options are only used on Linux.
+///
+/// # Arguments
+///
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+///   rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+/// * `options` - the options for this promotion.
+///
+/// # Return value
+///
+/// This function returns a `Result<RtPriorityHandle>`, which is an opaque struct to be passed to
+/// `demote_current_thread_from_real_time` to revert to the previous thread priority. It is an error
+/// if the backend cannot honour `options`.
+pub fn promote_current_thread_to_real_time_with(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
 ) -> Result<RtPriorityHandle, AudioThreadPriorityError> {
     if audio_samplerate_hz == 0 {
-        return Err(AudioThreadPriorityError::new("sample rate is zero"));
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::InvalidArgument,
+            "sample rate is zero",
+        ));
+    }
+    cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            promote_current_thread_to_real_time_internal(
+                audio_buffer_frames,
+                audio_samplerate_hz,
+                options,
+            )
+        } else {
+            let _ = options;
+            promote_current_thread_to_real_time_internal(audio_buffer_frames, audio_samplerate_hz)
+        }
     }
-    promote_current_thread_to_real_time_internal(audio_buffer_frames, audio_samplerate_hz)
 }
 
 /// Demotes the calling thread from real-time priority.
 ///
+/// On Linux, `handle` identifies the promoted thread by its system-wide tid, so this can be called
+/// from any thread of the process, for example a cleanup thread. It fails if the promoted thread
+/// has exited. On the other platforms, this must be called on the promoted thread.
+///
 /// # Arguments
 ///
 /// * `handle` - An opaque struct returned from a successful call to
@@ -532,6 +1084,46 @@ pub fn demote_current_thread_from_real_time(
     demote_current_thread_from_real_time_internal(handle)
 }
 
+/// Adapt a promotion to a new buffer size or sample rate, without demoting the thread.
+///
+/// When an audio device renegotiates its buffer size or sample rate, this recomputes what the
+/// promotion derived from the buffer duration, without a window at normal priority: the time
+/// constraint policy on macOS, and on Linux the `RLIMIT_RTTIME` budget of a fixed priority or the
+/// `SCHED_DEADLINE` reservation of the native backend. A budget set with `PromotionOptions::budget`
+/// is kept. Elsewhere, nothing depends on the buffer duration, so this does nothing.
+///
+/// # Arguments
+///
+/// * `handle` - An opaque struct returned from a successful promotion.
+/// * `audio_buffer_frames` - the exact or an upper limit on the number of frames that have to be
+///   rendered each callback, or 0 for a sensible default value.
+/// * `audio_samplerate_hz` - the sample-rate for this audio stream, in Hz.
+///
+/// # Return value
+///
+/// `Ok` in case of success, `Err` otherwise, in which case the thread keeps its previous
+/// parameters.
+pub fn update_real_time_parameters(
+    handle: &mut RtPriorityHandle,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    if audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::InvalidArgument,
+            "sample rate is zero",
+        ));
+    }
+    cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "macos", target_os = "ios"))] {
+            update_real_time_parameters_internal(handle, audio_buffer_frames, audio_samplerate_hz)
+        } else {
+            let _ = (handle, audio_buffer_frames);
+            Ok(())
+        }
+    }
+}
+
 /// Opaque handle for the C API
 #[allow(non_camel_case_types)]
 pub struct atp_handle(RtPriorityHandle);
@@ -556,6 +1148,8 @@ pub struct atp_handle(RtPriorityHandle);
 /// feature it happens when the process lacks permission to set real-time scheduling. In that case,
 /// gather the thread's information with `atp_get_current_thread_info` and have another (privileged)
 /// process promote it via `atp_promote_thread_to_real_time`.
+///
+/// In case of error, `atp_last_error_code` and `atp_last_error_message` tell why.
 #[no_mangle]
 pub extern "C" fn atp_promote_current_thread_to_real_time(
     audio_buffer_frames: u32,
@@ -563,11 +1157,49 @@ pub extern "C" fn atp_promote_current_thread_to_real_time(
 ) -> *mut atp_handle {
     match promote_current_thread_to_real_time(audio_buffer_frames, audio_samplerate_hz) {
         Ok(handle) => Box::into_raw(Box::new(atp_handle(handle))),
-        _ => std::ptr::null_mut(),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
     }
 }
+/// Promote the calling thread to real-time priority, with options, with a C API. This is
+/// `promote_current_thread_to_real_time_with`, for C.
+///
+/// # Arguments
+///
+/// * `options` - the buffer size, sample rate and options of the promotion. The options are only
+///   used on Linux.
+///
+/// # Return value
+///
+/// An opaque handle, to be passed to `atp_demote_current_thread_from_real_time` to demote the
+/// thread, or NULL in case of error, see `atp_last_error_code` and `atp_last_error_message`.
+///
+/// # Safety
+///
+/// `options` must be null, or point to a valid `atp_promotion_options` of `struct_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn atp_promote_current_thread_to_real_time_ex(
+    options: *const atp_promotion_options,
+) -> *mut atp_handle {
+    let promotion = read_promotion_options(options).and_then(|(frames, rate, options)| {
+        promote_current_thread_to_real_time_with(frames, rate, &options)
+    });
+    match promotion {
+        Ok(handle) => Box::into_raw(Box::new(atp_handle(handle))),
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
 /// Demotes the calling thread from real-time priority, with a C API.
 ///
+/// On Linux, this can be called from any thread of the process, like
+/// `demote_current_thread_from_real_time`.
+///
 /// # Arguments
 ///
 /// * `atp_handle` - An opaque struct returned from a successful call to
@@ -575,28 +1207,31 @@ pub extern "C" fn atp_promote_current_thread_to_real_time(
 ///
 /// # Return value
 ///
-/// 0 in case of success, non-zero in case of error.
+/// 0 in case of success, a negative `ATP_ERROR_*` code in case of error.
 ///
 /// # Safety
 ///
-/// Only to be used with a valid pointer from this library -- not after having released it via
-/// atp_free_handle.
+/// Only to be used with a valid pointer from this library, or null -- not after having released it
+/// via atp_free_handle.
 #[no_mangle]
 pub unsafe extern "C" fn atp_demote_current_thread_from_real_time(handle: *mut atp_handle) -> i32 {
-    assert!(!handle.is_null());
+    if handle.is_null() {
+        return invalid_argument("handle is NULL");
+    }
     let handle = Box::from_raw(handle);
 
     match demote_current_thread_from_real_time(handle.0) {
         Ok(_) => 0,
-        _ => 1,
+        Err(e) => set_last_error(&e),
     }
 }
 
 /// Frees a handle, with a C API.
 ///
 /// This is useful when it is impractical to call `atp_demote_current_thread_from_real_time` on the
-/// right thread. Access to the handle must be synchronized externally, or the thread that was
-/// promoted to real-time priority must have exited.
+/// right thread. The thread is not demoted: it keeps its real-time priority. Access to the handle
+/// must be synchronized externally, or the thread that was promoted to real-time priority must have
+/// exited.
 ///
 /// # Arguments
 ///
@@ -605,7 +1240,7 @@ pub unsafe extern "C" fn atp_demote_current_thread_from_real_time(handle: *mut a
 ///
 /// # Return value
 ///
-/// 0 in case of success, non-zero in case of error.
+/// 0 in case of success, `ATP_ERROR_INVALID_ARGUMENT` in case of error (if `handle` is NULL).
 ///
 /// # Safety
 ///
@@ -613,7 +1248,7 @@ pub unsafe extern "C" fn atp_demote_current_thread_from_real_time(handle: *mut a
 #[no_mangle]
 pub unsafe extern "C" fn atp_free_handle(handle: *mut atp_handle) -> i32 {
     if handle.is_null() {
-        return 1;
+        return invalid_argument("handle is NULL");
     }
     let _handle = Box::from_raw(handle);
     0
@@ -629,7 +1264,10 @@ mod tests {
     // permission to request real-time scheduling. When the environment does not grant it (no
     // RLIMIT_RTPRIO budget and not privileged), the promotion tests have nothing to exercise and
     // skip rather than fail; CI raises the limit so they run for real.
-    #[cfg(all(target_os = "linux", not(feature = "dbus")))]
+    #[cfg(all(
+        target_os = "linux",
+        not(any(feature = "dbus", feature = "with_rust_dbus"))
+    ))]
     fn rt_scheduling_available() -> bool {
         match promote_current_thread_to_real_time(0, 44100) {
             Ok(handle) => {
@@ -643,17 +1281,108 @@ mod tests {
         }
     }
 
+    // With a D-Bus backend, run `checks` in a child whose system bus is a private one, served by
+    // the stand-in rtkit, so that the tests do not depend on the rtkit of the machine. Without
+    // dbus-daemon, the child uses the system bus. Without a D-Bus backend, `checks` runs here.
+    #[cfg(all(target_os = "linux", any(feature = "dbus", feature = "with_rust_dbus")))]
+    fn with_stand_in_rtkit(name: &str, checks: impl FnOnce()) {
+        use crate::test_child::PASSED;
+        let (_, code) = match rt_linux_fake_rtkit::PrivateBus::start() {
+            Ok(bus) => rt_linux::run_with_stand_in(rt_linux::RealtimeService::Rtkit, &bus, |_| {
+                checks();
+                PASSED
+            }),
+            Err(e) => {
+                eprintln!("{name}: using the system bus: {e}");
+                rt_linux::run_in_child(|| {
+                    checks();
+                    PASSED
+                })
+            }
+        };
+        assert_eq!(code, PASSED, "{name} failed in its child process");
+    }
+
+    #[cfg(not(all(target_os = "linux", any(feature = "dbus", feature = "with_rust_dbus"))))]
+    fn with_stand_in_rtkit(_name: &str, checks: impl FnOnce()) {
+        checks();
+    }
+
+    // The kind, OS error code and D-Bus error name follow an error through the layers that wrap it.
+    #[test]
+    fn test_error_kind() {
+        let denied = AudioThreadPriorityError::from_dbus(
+            "org.freedesktop.DBus.Error.AccessDenied",
+            "Operation not permitted",
+        );
+        assert_eq!(denied.kind(), ErrorKind::PermissionDenied);
+        let wrapped = AudioThreadPriorityError::new_with_inner("promotion", Box::new(denied));
+        assert_eq!(wrapped.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(
+            wrapped.dbus_error_name(),
+            Some("org.freedesktop.DBus.Error.AccessDenied")
+        );
+
+        let unknown =
+            AudioThreadPriorityError::from_dbus("org.freedesktop.DBus.Error.ServiceUnknown", "?");
+        assert_eq!(unknown.kind(), ErrorKind::ServiceUnavailable);
+        let timeout =
+            AudioThreadPriorityError::from_dbus("org.freedesktop.DBus.Error.NoReply", "?");
+        assert_eq!(timeout.kind(), ErrorKind::Timeout);
+
+        let io_error = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let error = AudioThreadPriorityError::new_with_inner("setrlimit", Box::new(io_error));
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(error.dbus_error_name(), None);
+
+        assert_eq!(AudioThreadPriorityError::new("?").kind(), ErrorKind::Other);
+    }
+
+    // The C API records why a call failed, for the calling thread only.
+    #[test]
+    fn test_c_api_last_error() {
+        std::thread::spawn(|| {
+            assert_eq!(atp_last_error_code(), ATP_OK);
+            assert!(atp_last_error_message().is_null());
+
+            assert!(atp_promote_current_thread_to_real_time(512, 0).is_null());
+            assert_eq!(atp_last_error_code(), ATP_ERROR_INVALID_ARGUMENT);
+            let message = unsafe { std::ffi::CStr::from_ptr(atp_last_error_message()) };
+            assert!(message.to_str().unwrap().contains("sample rate is zero"));
+
+            assert_eq!(
+                unsafe { atp_free_handle(std::ptr::null_mut()) },
+                ATP_ERROR_INVALID_ARGUMENT
+            );
+            std::thread::spawn(|| assert_eq!(atp_last_error_code(), ATP_OK))
+                .join()
+                .unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
     #[test]
     fn it_works() {
         #[cfg(feature = "terminal-logging")]
         simple_logger::init().unwrap();
-        #[cfg(all(target_os = "linux", not(feature = "dbus")))]
+        with_stand_in_rtkit("it_works", promotion_checks);
+    }
+
+    fn promotion_checks() {
+        #[cfg(all(
+            target_os = "linux",
+            not(any(feature = "dbus", feature = "with_rust_dbus"))
+        ))]
         if !rt_scheduling_available() {
-            eprintln!("skipping it_works: real-time scheduling is not permitted here");
+            eprintln!("skipping the promotion checks: real-time scheduling is not permitted here");
             return;
         }
         {
-            assert!(promote_current_thread_to_real_time(0, 0).is_err());
+            match promote_current_thread_to_real_time(0, 0) {
+                Ok(_) => panic!("a sample rate of zero should be refused"),
+                Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidArgument),
+            }
         }
         {
             match promote_current_thread_to_real_time(0, 44100) {
@@ -714,117 +1443,544 @@ mod tests {
 
     #[test]
     fn it_works_in_different_threads() {
-        let handles: Vec<_> = (0..32).map(|_| std::thread::spawn(it_works)).collect();
-        for handle in handles {
-            handle.join().unwrap()
-        }
+        with_stand_in_rtkit("it_works_in_different_threads", || {
+            let handles: Vec<_> = (0..32)
+                .map(|_| std::thread::spawn(promotion_checks))
+                .collect();
+            for handle in handles {
+                handle.join().unwrap()
+            }
+        });
     }
 
     cfg_if! {
         if #[cfg(target_os = "linux")] {
             use nix::unistd::*;
             use nix::sys::signal::*;
-            #[cfg(not(feature = "dbus"))]
-            use nix::sys::wait::*;
 
             #[test]
             fn test_linux_api() {
-                #[cfg(not(feature = "dbus"))]
-                if !rt_scheduling_available() {
-                    eprintln!("skipping test_linux_api: real-time scheduling is not permitted here");
-                    return;
+                with_stand_in_rtkit("test_linux_api", || {
+                    #[cfg(not(any(feature = "dbus", feature = "with_rust_dbus")))]
+                    if !rt_scheduling_available() {
+                        eprintln!("skipping test_linux_api: real-time scheduling is not permitted here");
+                        return;
+                    }
+                    {
+                        let info = get_current_thread_info().unwrap();
+                        match promote_thread_to_real_time(info, 512, 44100) {
+                            Ok(_) => { }
+                            Err(e) => {
+                              panic!("{}", e);
+                            }
+                        }
+                    }
+                    {
+                        let info = get_current_thread_info().unwrap();
+                        let bytes = info.serialize();
+                        let info2 = RtPriorityThreadInfo::try_deserialize(&bytes).unwrap();
+                        assert!(info == info2);
+                    }
+                    {
+                        let info = get_current_thread_info().unwrap();
+                        let bytes = thread_info_serialize(info);
+                        let info2 = thread_info_try_deserialize(&bytes).unwrap();
+                        assert!(info == info2);
+                    }
+                });
+            }
+            // A serialized thread info that is truncated, corrupted or from another format version
+            // is rejected, rather than promoting whatever thread its bytes happen to name.
+            #[test]
+            fn test_thread_info_wire_format() {
+                let info = get_current_thread_info().unwrap();
+                let bytes = info.serialize();
+                assert_eq!(bytes.len(), ATP_THREAD_INFO_SIZE);
+                assert_eq!(&bytes[..4], b"ATPI");
+                assert!(thread_info_try_deserialize(&bytes).unwrap() == info);
+
+                let truncated = thread_info_try_deserialize(&bytes[..bytes.len() - 1]);
+                assert_eq!(truncated.err().unwrap().kind(), ErrorKind::InvalidArgument);
+                let mut longer = bytes.to_vec();
+                longer.push(0);
+                assert!(thread_info_try_deserialize(&longer).is_err());
+                assert!(thread_info_try_deserialize(&[]).is_err());
+                for (at, kind) in [
+                    (0, ErrorKind::InvalidArgument), // magic
+                    (4, ErrorKind::Unsupported),     // version
+                    (6, ErrorKind::InvalidArgument), // length
+                    (8, ErrorKind::InvalidArgument), // payload, caught by the checksum
+                    (bytes.len() - 1, ErrorKind::InvalidArgument), // checksum
+                ] {
+                    let mut corrupted = bytes;
+                    corrupted[at] ^= 0x40;
+                    let e = thread_info_try_deserialize(&corrupted).err().unwrap();
+                    assert_eq!(e.kind(), kind, "{}", e);
                 }
-                {
-                    let info = get_current_thread_info().unwrap();
-                    match promote_thread_to_real_time(info, 512, 44100) {
-                        Ok(_) => { }
+
+                // The deprecated `thread_info_deserialize` cannot fail: it returns a thread info
+                // that cannot be promoted.
+                let mut corrupted = bytes;
+                corrupted[8] ^= 0x40;
+                #[allow(deprecated)]
+                let invalid = thread_info_deserialize(corrupted);
+                let e = promote_thread_to_real_time(invalid, 512, 44100).err().unwrap();
+                assert_eq!(e.kind(), ErrorKind::InvalidArgument);
+
+                unsafe {
+                    let info = atp_try_deserialize_thread_info(bytes.as_ptr(), bytes.len());
+                    assert!(!info.is_null());
+                    assert_eq!(atp_free_thread_info(info), 0);
+                    let info = atp_try_deserialize_thread_info(bytes.as_ptr(), bytes.len() - 1);
+                    assert!(info.is_null());
+                    assert_eq!(atp_last_error_code(), ATP_ERROR_INVALID_ARGUMENT);
+                }
+            }
+            // The encoding is the same on all Linux targets, so that a 32-bit process can hand its
+            // thread info to a 64-bit one. The `pthread_t` only survives in its own process.
+            #[test]
+            fn test_thread_info_portable_encoding() {
+                #[rustfmt::skip]
+                const ENCODED: [u8; 40] = [
+                    0x41, 0x54, 0x50, 0x49, 0x02, 0x00, 0x1c, 0x00, // "ATPI", version 2, 28 bytes
+                    0xd2, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // thread id 1234
+                    0xff, 0xff, 0xff, 0x7f, // pid i32::MAX, which no process has
+                    0x01, 0x00, 0x00, 0x00, // SCHED_FIFO
+                    0x0a, 0x00, 0x00, 0x00, // priority 10
+                    0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, // pthread_t
+                    0x9d, 0x1a, 0x07, 0xb5, // CRC-32
+                ];
+                let info = RtPriorityThreadInfoInternal {
+                    thread_id: 1234,
+                    pthread_id: 0x1122_3344,
+                    pid: i32::MAX,
+                    policy: libc::SCHED_FIFO,
+                    priority: 10,
+                };
+                assert_eq!(info.serialize(), ENCODED);
+                assert_eq!(RtPriorityThreadInfo::SERIALIZED_SIZE, ENCODED.len());
+
+                let remote = thread_info_try_deserialize(&ENCODED).unwrap();
+                assert_eq!(
+                    (remote.thread_id, remote.pid, remote.policy, remote.priority),
+                    (1234, i32::MAX, libc::SCHED_FIFO, 10)
+                );
+                assert_eq!(remote.pthread_id, 0);
+
+                let local = get_current_thread_info().unwrap();
+                let round_trip = thread_info_try_deserialize(&local.serialize()).unwrap();
+                assert_eq!(round_trip.pthread_id, local.pthread_id);
+            }
+            // SCHED_DEADLINE needs CAP_SYS_NICE and enough free CPU bandwidth, so this skips when
+            // the promotion is refused, but checks the policy is really in place when it succeeds.
+            #[test]
+            fn test_deadline_promotion() {
+                with_stand_in_rtkit("test_deadline_promotion", || {
+                    const SCHED_DEADLINE: u32 = 6;
+                    assert!(promote_current_thread_to_real_time_deadline(512, 0).is_err());
+                    let handle = match promote_current_thread_to_real_time_deadline(512, 48000) {
+                        Ok(handle) => handle,
                         Err(e) => {
-                          panic!("{}", e);
+                            eprintln!("skipping test_deadline_promotion: {e}");
+                            return;
                         }
+                    };
+                    let attr = rt_linux_deadline::current_attributes(0).unwrap();
+                    assert_eq!(attr.policy(), SCHED_DEADLINE);
+                    assert_eq!(attr.period(), 10_666_666);
+
+                    // The reservation follows the buffer duration, 128 frames at 96kHz is 1.33ms.
+                    let mut handle = handle;
+                    update_real_time_parameters(&mut handle, 128, 96000).unwrap();
+                    let attr = rt_linux_deadline::current_attributes(0).unwrap();
+                    assert_eq!(attr.policy(), SCHED_DEADLINE);
+                    assert_eq!(attr.period(), 1_333_333);
+                    demote_current_thread_from_real_time(handle).unwrap();
+                    let attr = rt_linux_deadline::current_attributes(0).unwrap();
+                    assert_ne!(attr.policy(), SCHED_DEADLINE);
+                });
+            }
+            // The promotion happens on the helper thread, but applies to the calling thread, which
+            // can demote itself with the handle as usual.
+            #[test]
+            fn test_async_promotion() {
+                with_stand_in_rtkit("test_async_promotion", || {
+                    #[cfg(not(any(feature = "dbus", feature = "with_rust_dbus")))]
+                    if !rt_scheduling_available() {
+                        eprintln!("skipping test_async_promotion: real-time scheduling is not permitted here");
+                        return;
+                    }
+                    let options = PromotionOptions::new();
+                    assert!(promote_current_thread_to_real_time_async(512, 0, &options).is_err());
+
+                    let mut pending = promote_current_thread_to_real_time_async(512, 44100, &options).unwrap();
+                    let handle = loop {
+                        match pending.poll() {
+                            Some(result) => break result.unwrap(),
+                            None => std::thread::sleep(std::time::Duration::from_millis(1)),
+                        }
+                    };
+                    assert!(pending.poll().is_none());
+                    demote_current_thread_from_real_time(handle).unwrap();
+
+                    let pending = promote_current_thread_to_real_time_async(512, 44100, &options).unwrap();
+                    demote_current_thread_from_real_time(pending.wait().unwrap()).unwrap();
+
+                    let (sender, receiver) = std::sync::mpsc::channel();
+                    promote_current_thread_to_real_time_with_callback(512, 44100, &options, move |result| {
+                        sender.send(result).unwrap();
+                    }).unwrap();
+                    demote_current_thread_from_real_time(receiver.recv().unwrap().unwrap()).unwrap();
+
+                    // A panicking callback does not take the helper thread down with it.
+                    promote_current_thread_to_real_time_with_callback(512, 44100, &options, |result| {
+                        if let Ok(handle) = result {
+                            let _ = demote_current_thread_from_real_time(handle);
+                        }
+                        panic!("callback panicked");
+                    }).unwrap();
+                    let pending = promote_current_thread_to_real_time_async(512, 44100, &options).unwrap();
+                    demote_current_thread_from_real_time(pending.wait().unwrap()).unwrap();
+                });
+            }
+            // Options a backend cannot honour are refused before anything is changed.
+            #[test]
+            fn test_promotion_options_refused() {
+                with_stand_in_rtkit("test_promotion_options_refused", || {
+                    let kind = |options: &PromotionOptions| {
+                        match promote_current_thread_to_real_time_with(512, 44100, options) {
+                            Ok(_) => panic!("the promotion options should be refused"),
+                            Err(e) => e.kind(),
+                        }
+                    };
+                    for priority in [0, 100] {
+                        let options = PromotionOptions::new().priority(priority);
+                        assert_eq!(kind(&options), ErrorKind::InvalidArgument);
+                    }
+                    let rtkit = PromotionOptions::new().backends(&[LinuxBackend::Rtkit]);
+                    let fifo = rtkit.clone().policy(SchedulingPolicy::Fifo);
+                    assert_eq!(kind(&fifo), ErrorKind::Unsupported);
+                    let inherit = rtkit.reset_on_fork(false);
+                    assert_eq!(kind(&inherit), ErrorKind::Unsupported);
+                    let deadline = PromotionOptions::new()
+                        .policy(SchedulingPolicy::Deadline)
+                        .reset_on_fork(false)
+                        .backends(&[LinuxBackend::Native]);
+                    assert_eq!(kind(&deadline), ErrorKind::Unsupported);
+                });
+            }
+            // rtkit cannot grant SCHED_FIFO, so the native backend is tried next. It succeeds when
+            // real-time scheduling is permitted, otherwise the error says why both failed.
+            #[test]
+            fn test_backend_fallback() {
+                with_stand_in_rtkit("test_backend_fallback", || {
+                    let options = PromotionOptions::new()
+                        .policy(SchedulingPolicy::Fifo)
+                        .backends(&[LinuxBackend::Rtkit, LinuxBackend::Native]);
+                    match promote_current_thread_to_real_time_with(512, 44100, &options) {
+                        Ok(handle) => {
+                            assert_eq!(handle.backend(), LinuxBackend::Native);
+                            demote_current_thread_from_real_time(handle).unwrap();
+                        }
+                        Err(e) => {
+                            let message = e.to_string();
+                            assert!(message.contains("rtkit: ") && message.contains("native: "), "{}", message);
+                        }
+                    }
+                    let none = PromotionOptions::new().backends(&[]);
+                    assert!(promote_current_thread_to_real_time_with(512, 44100, &none).is_err());
+                });
+            }
+            // A backend registered by the application is used when named in the chain, and gets the
+            // promotion back on demotion.
+            #[test]
+            fn test_custom_backend() {
+                use std::sync::{Arc, Mutex};
+
+                struct Recording(Mutex<Vec<String>>);
+                impl PriorityBackend for Recording {
+                    fn name(&self) -> &'static str {
+                        "recording"
+                    }
+                    fn promote_current_thread(&self, _: RtPriorityThreadInfo, frames: u32, rate: u32, _: &PromotionOptions) -> Result<BackendPromotion, AudioThreadPriorityError> {
+                        self.0.lock().unwrap().push(format!("promote {frames} {rate}"));
+                        Ok(BackendPromotion::new(frames, Some(42)))
+                    }
+                    fn demote_current_thread(&self, promotion: BackendPromotion) -> Result<(), AudioThreadPriorityError> {
+                        let frames: u32 = promotion.state()?;
+                        if frames == 64 {
+                            return Err(AudioThreadPriorityError::new("demotion refused"));
+                        }
+                        self.0.lock().unwrap().push(format!("demote {frames}"));
+                        Ok(())
+                    }
+                    fn promote_thread(&self, thread_info: RtPriorityThreadInfo, _: u32, _: u32, _: &PromotionOptions) -> Result<BackendPromotion, AudioThreadPriorityError> {
+                        Err(AudioThreadPriorityError::new(&format!("remote promotion of thread {} refused", thread_info.thread_id())))
+                    }
+                    fn demote_thread(&self, _: RtPriorityThreadInfo) -> Result<(), AudioThreadPriorityError> {
+                        self.0.lock().unwrap().push("demote thread".to_string());
+                        Ok(())
+                    }
+                    fn update_parameters(&self, promotion: &mut BackendPromotion, frames: u32, rate: u32) -> Result<(), AudioThreadPriorityError> {
+                        *promotion.state_mut::<u32>()? = frames;
+                        self.0.lock().unwrap().push(format!("update {frames} {rate}"));
+                        Ok(())
                     }
                 }
-                {
-                    let info = get_current_thread_info().unwrap();
-                    let bytes = info.serialize();
-                    let info2 = RtPriorityThreadInfo::deserialize(bytes);
-                    assert!(info == info2);
-                }
-                {
-                    let info = get_current_thread_info().unwrap();
-                    let bytes = thread_info_serialize(info);
-                    let info2 = thread_info_deserialize(bytes);
-                    assert!(info == info2);
+
+                let recording = Arc::new(Recording(Mutex::new(Vec::new())));
+                register_priority_backend(recording.clone());
+                let options = PromotionOptions::new().backends(&[LinuxBackend::Custom("recording")]);
+                let mut handle = promote_current_thread_to_real_time_with(512, 44100, &options).unwrap();
+                assert_eq!(handle.backend(), LinuxBackend::Custom("recording"));
+                assert_eq!(handle.granted_priority(), Some(42));
+                assert!(update_real_time_parameters(&mut handle, 1024, 0).is_err());
+                update_real_time_parameters(&mut handle, 1024, 48000).unwrap();
+                demote_current_thread_from_real_time(handle).unwrap();
+                assert_eq!(
+                    *recording.0.lock().unwrap(),
+                    ["promote 512 44100", "update 1024 48000", "demote 1024"]
+                );
+
+                let info = get_current_thread_info().unwrap();
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+                assert_eq!(info.thread_id(), tid);
+                assert_eq!(info.pid(), unsafe { libc::getpid() });
+                assert_eq!(info.policy(), unsafe { libc::sched_getscheduler(0) });
+                let e = match promote_thread_to_real_time_with(info, 512, 44100, &options) {
+                    Ok(_) => panic!("the remote promotion should be refused"),
+                    Err(e) => e,
+                };
+                let refused = format!("recording: remote promotion of thread {tid} refused");
+                assert!(e.to_string().contains(&refused), "{}", e);
+                let unknown = PromotionOptions::new().backends(&[LinuxBackend::Custom("unknown")]);
+                assert!(promote_current_thread_to_real_time_with(512, 44100, &unknown).is_err());
+
+                // The same, with the C API, naming the backend.
+                let backends = [b"recording\0".as_ptr() as *const std::os::raw::c_char];
+                let c_options = atp_promotion_options {
+                    struct_size: std::mem::size_of::<atp_promotion_options>(),
+                    audio_buffer_frames: 256,
+                    audio_samplerate_hz: 48000,
+                    priority: 0,
+                    policy: ATP_POLICY_DEFAULT,
+                    flags: 0,
+                    budget_us: 0,
+                    backends: backends.as_ptr(),
+                    backends_count: backends.len(),
+                };
+                let handle = unsafe { atp_promote_current_thread_to_real_time_ex(&c_options) };
+                assert!(!handle.is_null());
+                assert_eq!(unsafe { atp_demote_current_thread_from_real_time(handle) }, 0);
+                assert_eq!(recording.0.lock().unwrap()[3..], ["promote 256 48000", "demote 256"]);
+                let info = atp_get_current_thread_info();
+                assert!(unsafe { atp_promote_thread_to_real_time_ex(info, &c_options) }.is_null());
+                assert_eq!(atp_last_error_code(), ATP_ERROR_OTHER);
+                assert_eq!(unsafe { atp_free_thread_info(info) }, 0);
+
+                // Demoting by thread info goes to the backend that promoted the thread, rather
+                // than to the first backend of the chain.
+                let _handle = promote_current_thread_to_real_time_with(128, 48000, &options).unwrap();
+                demote_thread_from_real_time(get_current_thread_info().unwrap()).unwrap();
+                assert_eq!(recording.0.lock().unwrap()[5..], ["promote 128 48000", "demote thread"]);
+
+                // A failed demotion is not forgotten: a retry by thread info still goes to the
+                // backend that promoted the thread first.
+                let handle = promote_current_thread_to_real_time_with(64, 48000, &options).unwrap();
+                assert!(demote_current_thread_from_real_time(handle).is_err());
+                demote_thread_from_real_time(get_current_thread_info().unwrap()).unwrap();
+                assert_eq!(recording.0.lock().unwrap()[7..], ["promote 64 48000", "demote thread"]);
+            }
+            // A guard demotes on drop, unless it is leaked or turned into a handle.
+            #[test]
+            fn test_guard() {
+                use std::sync::{Arc, Mutex};
+
+                struct Recording(Mutex<Vec<&'static str>>);
+                impl PriorityBackend for Recording {
+                    fn name(&self) -> &'static str {
+                        "guard"
+                    }
+                    fn promote_current_thread(&self, _: RtPriorityThreadInfo, _: u32, _: u32, _: &PromotionOptions) -> Result<BackendPromotion, AudioThreadPriorityError> {
+                        self.0.lock().unwrap().push("promote");
+                        Ok(BackendPromotion::new((), Some(10)))
+                    }
+                    fn demote_current_thread(&self, _: BackendPromotion) -> Result<(), AudioThreadPriorityError> {
+                        self.0.lock().unwrap().push("demote current");
+                        Ok(())
+                    }
+                    fn promote_thread(&self, thread_info: RtPriorityThreadInfo, _: u32, _: u32, _: &PromotionOptions) -> Result<BackendPromotion, AudioThreadPriorityError> {
+                        Err(AudioThreadPriorityError::new(&format!("remote promotion of thread {} refused", thread_info.thread_id())))
+                    }
+                    fn demote_thread(&self, _: RtPriorityThreadInfo) -> Result<(), AudioThreadPriorityError> {
+                        Ok(())
+                    }
                 }
+
+                let recording = Arc::new(Recording(Mutex::new(Vec::new())));
+                register_priority_backend(recording.clone());
+                let options = PromotionOptions::new().backends(&[LinuxBackend::Custom("guard")]);
+
+                let guard = promote_current_thread_to_real_time_guarded(512, 44100, &options).unwrap();
+                assert_eq!(guard.handle().backend(), LinuxBackend::Custom("guard"));
+                drop(guard);
+                promote_current_thread_to_real_time_guarded(512, 44100, &options).unwrap().leak();
+                let handle = promote_current_thread_to_real_time_guarded(512, 44100, &options)
+                    .unwrap()
+                    .into_raw();
+                demote_current_thread_from_real_time(handle).unwrap();
+                assert_eq!(
+                    *recording.0.lock().unwrap(),
+                    ["promote", "demote current", "promote", "promote", "demote current"]
+                );
+                recording.0.lock().unwrap().clear();
+
+                // On Linux, the handle can be demoted from any thread.
+                let guard = promote_current_thread_to_real_time_guarded(512, 44100, &options).unwrap();
+                std::thread::spawn(move || drop(guard)).join().unwrap();
+                assert_eq!(*recording.0.lock().unwrap(), ["promote", "demote current"]);
+            }
+            // A handle can be demoted from another thread than the promoted one, which fails once
+            // that thread has exited. This needs permission to use the native backend, so it skips
+            // when promotion is refused.
+            #[test]
+            fn test_cross_thread_demotion() {
+                use std::sync::mpsc::channel;
+
+                let options = PromotionOptions::new().backends(&[LinuxBackend::Native]);
+                let thread_options = options.clone();
+                let (promoted_tx, promoted_rx) = channel();
+                let (demoted_tx, demoted_rx) = channel::<()>();
+                let thread = std::thread::spawn(move || {
+                    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+                    let result = promote_current_thread_to_real_time_with(512, 44100, &thread_options);
+                    promoted_tx.send((tid, result)).unwrap();
+                    demoted_rx.recv().unwrap();
+                });
+                let (tid, result) = promoted_rx.recv().unwrap();
+                let handle = match result {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        eprintln!("skipping test_cross_thread_demotion: {e}");
+                        demoted_tx.send(()).unwrap();
+                        thread.join().unwrap();
+                        return;
+                    }
+                };
+                let policy = || unsafe { libc::sched_getscheduler(tid) } & !0x4000_0000;
+                assert_eq!(policy(), libc::SCHED_FIFO);
+                demote_current_thread_from_real_time(handle).unwrap();
+                assert_eq!(policy(), libc::SCHED_OTHER);
+                demoted_tx.send(()).unwrap();
+                thread.join().unwrap();
+
+                let handle = std::thread::spawn(move || {
+                    promote_current_thread_to_real_time_with(512, 44100, &options)
+                })
+                .join()
+                .unwrap()
+                .unwrap();
+                let e = match demote_current_thread_from_real_time(handle) {
+                    Ok(_) => panic!("the promoted thread has exited"),
+                    Err(e) => e,
+                };
+                assert!(e.to_string().contains("the promoted thread has exited"), "{}", e);
+                assert_eq!(e.kind(), ErrorKind::ThreadNotFound);
+                assert_eq!(e.raw_os_error(), Some(libc::ESRCH));
+            }
+            // Lowering the nice level needs CAP_SYS_NICE or an RLIMIT_NICE limit, so this skips
+            // when it is refused.
+            #[test]
+            fn test_nice_backend() {
+                let options = PromotionOptions::new().backends(&[LinuxBackend::Nice]);
+                let refused = PromotionOptions::new()
+                    .backends(&[LinuxBackend::Nice])
+                    .policy(SchedulingPolicy::RoundRobin);
+                assert!(promote_current_thread_to_real_time_with(512, 44100, &refused).is_err());
+
+                let before = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+                let handle = match promote_current_thread_to_real_time_with(512, 44100, &options) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        eprintln!("skipping test_nice_backend: {e}");
+                        return;
+                    }
+                };
+                assert_eq!(handle.backend(), LinuxBackend::Nice);
+                assert_eq!(handle.granted_priority(), None);
+                assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }, before.min(-11));
+                demote_current_thread_from_real_time(handle).unwrap();
+                assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }, before);
             }
             #[test]
             fn test_remote_promotion() {
-                let (rd, wr) = pipe().unwrap();
-
-                match unsafe { fork().expect("fork failed") } {
-                    ForkResult::Parent{ child } => {
-                        eprintln!("Parent PID: {}", getpid());
-                        let mut bytes = [0_u8; std::mem::size_of::<RtPriorityThreadInfo>()];
-                        match read(rd, &mut bytes) {
-                             Ok(_) => {
-                                let info = RtPriorityThreadInfo::deserialize(bytes);
-                                match promote_thread_to_real_time(info, 0, 44100) {
-                                    Ok(_) => {
-                                        eprintln!("thread promotion in the child from the parent succeeded");
-                                    }
-                                    Err(e) => {
-                                        kill(child, SIGKILL).expect("Could not kill the child?");
-                                        // Promoting a thread in another process can need privilege
-                                        // beyond RLIMIT_RTPRIO (CAP_SYS_NICE) that an unprivileged
-                                        // CI does not grant. With the native (no-dbus) backend,
-                                        // treat that as a skip rather than a failure.
-                                        if cfg!(feature = "dbus") {
-                                            panic!("{}", e);
-                                        } else {
-                                            eprintln!("skipping test_remote_promotion: promoting a thread in another process needs elevated privilege ({e})");
-                                            return;
+                with_stand_in_rtkit("test_remote_promotion", || {
+                    let (rd, wr) = pipe().unwrap();
+
+                    match unsafe { fork().expect("fork failed") } {
+                        ForkResult::Parent{ child } => {
+                            eprintln!("Parent PID: {}", getpid());
+                            let mut bytes = [0_u8; RtPriorityThreadInfo::SERIALIZED_SIZE];
+                            match read(rd, &mut bytes) {
+                                 Ok(_) => {
+                                    let info = RtPriorityThreadInfo::try_deserialize(&bytes).unwrap();
+                                    match promote_thread_to_real_time(info, 0, 44100) {
+                                        Ok(_) => {
+                                            eprintln!("thread promotion in the child from the parent succeeded");
+                                        }
+                                        Err(e) => {
+                                            kill(child, SIGKILL).expect("Could not kill the child?");
+                                            // Promoting a thread in another process can need privilege
+                                            // beyond RLIMIT_RTPRIO (CAP_SYS_NICE) that an unprivileged
+                                            // CI does not grant. With the native (no-dbus) backend,
+                                            // treat that as a skip rather than a failure.
+                                            if cfg!(any(feature = "dbus", feature = "with_rust_dbus")) {
+                                                panic!("{}", e);
+                                            } else {
+                                                eprintln!("skipping test_remote_promotion: promoting a thread in another process needs elevated privilege ({e})");
+                                                return;
+                                            }
                                         }
                                     }
                                 }
+                                Err(e) => {
+                                    eprintln!("could not read from the pipe: {}", e);
+                                }
                             }
-                            Err(e) => {
-                                eprintln!("could not read from the pipe: {}", e);
-                            }
+                            kill(child, SIGKILL).expect("Could not kill the child?");
                         }
-                        kill(child, SIGKILL).expect("Could not kill the child?");
-                    }
-                    ForkResult::Child => {
-                        let r = set_real_time_hard_limit(0, 44100);
-                        if r.is_err() {
-                            eprintln!("Could not set RT limit, the test will fail.");
-                        }
-                        eprintln!("Child pid: {}", getpid());
-                        let info = get_current_thread_info().unwrap();
-                        let bytes = info.serialize();
-                        match write(wr, &bytes) {
-                            Ok(_) => {
-                                loop {
-                                    std::thread::sleep(std::time::Duration::from_millis(1000));
-                                    eprintln!("child sleeping, waiting to be promoted...");
-                                }
+                        ForkResult::Child => {
+                            let r = set_real_time_hard_limit(0, 44100);
+                            if r.is_err() {
+                                eprintln!("Could not set RT limit, the test will fail.");
                             }
-                            Err(_) => {
-                                eprintln!("write error on the pipe.");
+                            eprintln!("Child pid: {}", getpid());
+                            let info = get_current_thread_info().unwrap();
+                            let bytes = info.serialize();
+                            match write(wr, &bytes) {
+                                Ok(_) => {
+                                    loop {
+                                        std::thread::sleep(std::time::Duration::from_millis(1000));
+                                        eprintln!("child sleeping, waiting to be promoted...");
+                                    }
+                                }
+                                Err(_) => {
+                                    eprintln!("write error on the pipe.");
+                                }
                             }
                         }
                     }
-                }
+                });
             }
 
             // Native (no-dbus) path only. These tests change the process-wide RLIMIT_RTPRIO and, for
             // the override test, the priority set via `set_rt_priority`, so they run in a forked
             // child to avoid racing with the other (parallel) promotion tests.
             cfg_if! {
-                if #[cfg(not(feature = "dbus"))] {
+                if #[cfg(not(any(feature = "dbus", feature = "with_rust_dbus")))] {
+                    use crate::test_child::{fork_and_wait, FAILED, PASSED, SKIPPED};
+
                     const SCHED_RESET_ON_FORK: libc::c_int = 0x4000_0000;
-                    // Exit codes the forked child reports back to the parent.
-                    const PASSED: i32 = 0;
-                    const FAILED: i32 = 1;
-                    const SKIPPED: i32 = 2;
 
                     fn rtprio_limit() -> libc::rlimit {
                         let mut lim = unsafe { std::mem::zeroed::<libc::rlimit>() };
@@ -838,19 +1994,13 @@ mod tests {
                         assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_RTPRIO, &lim) }, 0);
                     }
 
+                    // Read from the kernel: glibc's `pthread_getschedparam` returns what it
+                    // cached on its first call, not the scheduling set by tid since.
                     fn current_scheduler() -> (libc::c_int, libc::c_int) {
-                        let mut policy = 0;
+                        let policy = unsafe { libc::sched_getscheduler(0) };
+                        assert!(policy >= 0);
                         let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
-                        assert_eq!(
-                            unsafe {
-                                libc::pthread_getschedparam(
-                                    libc::pthread_self(),
-                                    &mut policy,
-                                    &mut param,
-                                )
-                            },
-                            0
-                        );
+                        assert_eq!(unsafe { libc::sched_getparam(0, &mut param) }, 0);
                         (policy, param.sched_priority)
                     }
 
@@ -858,28 +2008,24 @@ mod tests {
                     // do not affect the other tests, and turn the child's exit code into a pass, a
                     // skip, or a panic.
                     fn run_in_child(name: &str, checks: impl FnOnce() -> i32) {
-                        match unsafe { fork().expect("fork failed") } {
-                            ForkResult::Parent { child } => {
-                                match waitpid(child, None).expect("waitpid") {
-                                    WaitStatus::Exited(_, PASSED) => {}
-                                    WaitStatus::Exited(_, SKIPPED) => {
-                                        eprintln!("skipping {}: needs an unprivileged process with a real-time budget", name);
-                                    }
-                                    other => panic!("{} child reported a failure: {:?}", name, other),
-                                }
+                        match fork_and_wait(checks) {
+                            (_, PASSED) => {}
+                            (_, SKIPPED) => {
+                                eprintln!("skipping {}: needs an unprivileged process with a real-time budget", name);
                             }
-                            ForkResult::Child => std::process::exit(checks()),
+                            (_, code) => panic!("{} child reported a failure: {}", name, code),
                         }
                     }
 
                     // Promotion honours RLIMIT_RTPRIO: with the soft limit below the requested
-                    // priority it must be denied, and at the priority it must succeed and actually
-                    // move the thread to SCHED_FIFO. Skipped as root (which bypasses RLIMIT_RTPRIO)
-                    // or when no real-time budget was granted (a plain developer machine; CI raises
-                    // it with `prlimit`).
+                    // priority, it is raised to the priority and the thread actually moves to
+                    // SCHED_FIFO; with the hard limit below, the priority is lowered to it. Skipped
+                    // as root (which bypasses RLIMIT_RTPRIO) or when no real-time budget was
+                    // granted (a plain developer machine; CI raises it with `prlimit`).
                     #[test]
                     fn test_native_promotion_honours_rlimit() {
                         const RT_PRIO: libc::c_int = 10;
+                        const HARD: libc::c_int = 4;
                         run_in_child("test_native_promotion_honours_rlimit", || {
                             if unsafe { libc::geteuid() } == 0 {
                                 return SKIPPED;
@@ -888,19 +2034,12 @@ mod tests {
                                 return SKIPPED;
                             }
 
-                            // Below the requested priority: promotion must be denied.
+                            // Soft limit below the requested priority: it is raised.
                             set_rtprio_soft(RT_PRIO as libc::rlim_t - 1);
-                            if promote_current_thread_to_real_time(0, 44100).is_ok() {
-                                eprintln!("promotion succeeded below the RLIMIT_RTPRIO ceiling");
-                                return FAILED;
-                            }
-
-                            // At the requested priority: promotion must succeed and take effect.
-                            set_rtprio_soft(RT_PRIO as libc::rlim_t);
                             let handle = match promote_current_thread_to_real_time(0, 44100) {
                                 Ok(handle) => handle,
                                 Err(e) => {
-                                    eprintln!("promotion denied at the RLIMIT_RTPRIO ceiling: {e}");
+                                    eprintln!("promotion denied below the RLIMIT_RTPRIO soft limit: {e}");
                                     return FAILED;
                                 }
                             };
@@ -909,10 +2048,43 @@ mod tests {
                                 eprintln!("unexpected scheduler after promotion: policy={policy} prio={prio}");
                                 return FAILED;
                             }
+                            if rtprio_limit().rlim_cur != RT_PRIO as libc::rlim_t {
+                                eprintln!("soft limit not raised: {}", rtprio_limit().rlim_cur);
+                                return FAILED;
+                            }
+                            if demote_current_thread_from_real_time(handle).is_err() {
+                                eprintln!("demotion failed");
+                                return FAILED;
+                            }
+                            // Once the thread is demoted, the soft limit is restored.
+                            if rtprio_limit().rlim_cur != RT_PRIO as libc::rlim_t - 1 {
+                                eprintln!("soft limit not restored: {}", rtprio_limit().rlim_cur);
+                                return FAILED;
+                            }
+
+                            // Hard limit below the requested priority: the priority is lowered.
+                            let limit = libc::rlimit { rlim_cur: 1, rlim_max: HARD as libc::rlim_t };
+                            assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_RTPRIO, &limit) }, 0);
+                            let handle = match promote_current_thread_to_real_time(0, 44100) {
+                                Ok(handle) => handle,
+                                Err(e) => {
+                                    eprintln!("promotion denied above the RLIMIT_RTPRIO hard limit: {e}");
+                                    return FAILED;
+                                }
+                            };
+                            let (_, prio) = current_scheduler();
+                            if prio != HARD || handle.granted_priority() != Some(HARD as u32) {
+                                eprintln!("expected priority {HARD}, got {prio}");
+                                return FAILED;
+                            }
                             if demote_current_thread_from_real_time(handle).is_err() {
                                 eprintln!("demotion failed");
                                 return FAILED;
                             }
+                            if rtprio_limit().rlim_cur != 1 {
+                                eprintln!("soft limit not restored: {}", rtprio_limit().rlim_cur);
+                                return FAILED;
+                            }
                             PASSED
                         });
                     }
@@ -952,6 +2124,85 @@ mod tests {
                             result
                         });
                     }
+
+                    // A fixed-priority promotion sets the RLIMIT_RTTIME soft limit of the process
+                    // to the buffer duration, and follows it when the buffer size changes. With
+                    // several real-time threads, the limit is the largest of their budgets, and the
+                    // original limit is restored once they are all demoted.
+                    #[test]
+                    fn test_native_rttime_budget() {
+                        fn rttime_soft_limit() -> libc::rlim_t {
+                            let mut lim = unsafe { std::mem::zeroed::<libc::rlimit>() };
+                            assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_RTTIME, &mut lim) }, 0);
+                            lim.rlim_cur
+                        }
+                        run_in_child("test_native_rttime_budget", || {
+                            if !rt_scheduling_available() {
+                                return SKIPPED;
+                            }
+                            let original = rttime_soft_limit();
+                            let mut handle = match promote_current_thread_to_real_time(512, 44100) {
+                                Ok(handle) => handle,
+                                Err(e) => {
+                                    eprintln!("promotion denied: {e}");
+                                    return FAILED;
+                                }
+                            };
+                            if rttime_soft_limit() != 11_609 {
+                                eprintln!("unexpected RLIMIT_RTTIME after promotion: {}", rttime_soft_limit());
+                                return FAILED;
+                            }
+                            update_real_time_parameters(&mut handle, 1024, 48000).unwrap();
+                            if rttime_soft_limit() != 21_333 {
+                                eprintln!("unexpected RLIMIT_RTTIME after update: {}", rttime_soft_limit());
+                                return FAILED;
+                            }
+                            // A thread with a smaller budget does not lower the limit, and its
+                            // demotion leaves it.
+                            let limit = std::thread::spawn(|| {
+                                let handle = promote_current_thread_to_real_time(128, 48000).unwrap();
+                                let limit = rttime_soft_limit();
+                                demote_current_thread_from_real_time(handle).unwrap();
+                                limit
+                            })
+                            .join()
+                            .unwrap();
+                            if limit != 21_333 || rttime_soft_limit() != 21_333 {
+                                eprintln!("unexpected RLIMIT_RTTIME with two threads: {limit}, then {}", rttime_soft_limit());
+                                return FAILED;
+                            }
+                            demote_current_thread_from_real_time(handle).unwrap();
+                            if rttime_soft_limit() != original {
+                                eprintln!("RLIMIT_RTTIME not restored after demotion: {}", rttime_soft_limit());
+                                return FAILED;
+                            }
+                            PASSED
+                        });
+                    }
+
+                    // Per-call options select the policy and priority, without touching the
+                    // process-wide default, and demotion restores the previous scheduler.
+                    #[test]
+                    fn test_native_promotion_options() {
+                        const PRIO: libc::c_int = 7;
+                        if !rt_scheduling_available() {
+                            eprintln!("skipping test_native_promotion_options: real-time scheduling is not permitted here");
+                            return;
+                        }
+                        let before = current_scheduler();
+                        let options = PromotionOptions::new()
+                            .priority(PRIO as u8)
+                            .policy(SchedulingPolicy::RoundRobin);
+                        let handle = promote_current_thread_to_real_time_with(0, 44100, &options)
+                            .expect("promotion with options");
+                        let (policy, prio) = current_scheduler();
+                        assert_eq!(policy & !SCHED_RESET_ON_FORK, libc::SCHED_RR);
+                        assert_eq!(prio, PRIO);
+                        assert_eq!(handle.granted_priority(), Some(PRIO as u32));
+                        demote_current_thread_from_real_time(handle).unwrap();
+                        let (policy, prio) = current_scheduler();
+                        assert_eq!((policy & !SCHED_RESET_ON_FORK, prio), (before.0 & !SCHED_RESET_ON_FORK, before.1));
+                    }
                 }
             }
         }
@@ -0,0 +1,221 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The resource limits of the calling process that matter for real-time audio threads, on Linux.
+//!
+//! - `RLIMIT_RTTIME`: the CPU time a real-time thread may use without blocking, see [`rttime`].
+//!   rtkit refuses to promote a process without one, and the promotion backends set it from the
+//!   buffer duration, see [`recommended_rttime`].
+//! - `RLIMIT_RTPRIO`: the highest real-time priority an unprivileged process may request, see
+//!   [`rtprio`].
+//! - `RLIMIT_NICE`: the lowest nice level an unprivileged process may request, see [`nice`].
+//! - `RLIMIT_MEMLOCK`: the memory a process may lock with `mlock`, to keep the memory touched by
+//!   the audio callback from being paged out, see [`memlock`].
+//!
+//! Any process may lower its limits, or raise a soft limit up to the hard limit. Raising a hard
+//! limit needs `CAP_SYS_RESOURCE`.
+
+extern crate libc;
+
+use std::convert::TryFrom;
+use std::io::Error as OSError;
+use std::time::Duration;
+
+use crate::{AudioThreadPriorityError, ErrorKind};
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+/// The nice level `RLIMIT_NICE` is relative to: a limit of `n` allows nice levels down to `20 - n`.
+const NICE_RLIMIT_BASE: i32 = 20;
+
+/// The soft and hard values of a resource limit. `None` means unlimited.
+///
+/// The kernel enforces the soft limit, which a process may raise up to the hard limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limit<T> {
+    /// The value enforced.
+    pub soft: Option<T>,
+    /// The ceiling of the soft limit.
+    pub hard: Option<T>,
+}
+
+impl<T> Limit<T> {
+    fn map<U>(self, f: impl Fn(T) -> U) -> Limit<U> {
+        Limit {
+            soft: self.soft.map(&f),
+            hard: self.hard.map(&f),
+        }
+    }
+}
+
+fn get(resource: Resource, name: &str) -> Result<Limit<libc::rlim_t>, AudioThreadPriorityError> {
+    let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+    if unsafe { libc::getrlimit(resource, &mut limit) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            &format!("getrlimit({name})"),
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    let finite = |value| Some(value).filter(|&value| value != libc::RLIM_INFINITY);
+    Ok(Limit {
+        soft: finite(limit.rlim_cur),
+        hard: finite(limit.rlim_max),
+    })
+}
+
+fn set(
+    resource: Resource,
+    name: &str,
+    limit: Limit<libc::rlim_t>,
+) -> Result<(), AudioThreadPriorityError> {
+    let limit = libc::rlimit {
+        rlim_cur: limit.soft.unwrap_or(libc::RLIM_INFINITY),
+        rlim_max: limit.hard.unwrap_or(libc::RLIM_INFINITY),
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+        return Err(AudioThreadPriorityError::new_with_inner(
+            &format!("setrlimit({name})"),
+            Box::new(OSError::last_os_error()),
+        ));
+    }
+    Ok(())
+}
+
+/// A limit value from a `u64`, saturating to unlimited where `rlim_t` is narrower.
+fn rlim(value: u64) -> libc::rlim_t {
+    libc::rlim_t::try_from(value).unwrap_or(libc::RLIM_INFINITY)
+}
+
+/// The `RLIMIT_RTTIME` limit: the CPU time a real-time thread may use without blocking. Past the
+/// soft limit, the thread gets `SIGXCPU` (see `install_sigxcpu_handler`), and past the hard limit,
+/// `SIGKILL`.
+#[allow(clippy::unnecessary_cast)]
+pub fn rttime() -> Result<Limit<Duration>, AudioThreadPriorityError> {
+    Ok(get(libc::RLIMIT_RTTIME, "RLIMIT_RTTIME")?.map(|us| Duration::from_micros(us as u64)))
+}
+
+/// Set the `RLIMIT_RTTIME` limit, with a microsecond resolution.
+pub fn set_rttime(limit: Limit<Duration>) -> Result<(), AudioThreadPriorityError> {
+    let us = |duration: Duration| rlim(u64::try_from(duration.as_micros()).unwrap_or(u64::MAX));
+    set(libc::RLIMIT_RTTIME, "RLIMIT_RTTIME", limit.map(us))
+}
+
+/// The `RLIMIT_RTPRIO` limit: the highest real-time priority an unprivileged process may request,
+/// 0 if none. The native backend raises the soft limit up to the hard limit when it is in the way,
+/// until the threads it promoted meanwhile are demoted.
+pub fn rtprio() -> Result<Limit<u32>, AudioThreadPriorityError> {
+    Ok(get(libc::RLIMIT_RTPRIO, "RLIMIT_RTPRIO")?
+        .map(|priority| u32::try_from(priority).unwrap_or(u32::MAX)))
+}
+
+/// Set the `RLIMIT_RTPRIO` limit.
+pub fn set_rtprio(limit: Limit<u32>) -> Result<(), AudioThreadPriorityError> {
+    set(
+        libc::RLIMIT_RTPRIO,
+        "RLIMIT_RTPRIO",
+        limit.map(|priority| rlim(priority as u64)),
+    )
+}
+
+/// The `RLIMIT_NICE` limit, as the lowest nice level (-20 to 19) an unprivileged process may
+/// request, or 20 if it may not lower its nice level at all. `None` allows all of them, like -20.
+pub fn nice() -> Result<Limit<i32>, AudioThreadPriorityError> {
+    Ok(get(libc::RLIMIT_NICE, "RLIMIT_NICE")?.map(|value| NICE_RLIMIT_BASE - value.min(40) as i32))
+}
+
+/// Set the `RLIMIT_NICE` limit, as the lowest nice level (-20 to 19) an unprivileged process may
+/// request, or 20 to allow none.
+pub fn set_nice(limit: Limit<i32>) -> Result<(), AudioThreadPriorityError> {
+    for nice in limit.soft.iter().chain(&limit.hard) {
+        if !(-20..=20).contains(nice) {
+            return Err(AudioThreadPriorityError::with_kind(
+                ErrorKind::InvalidArgument,
+                &format!("invalid nice level {nice}, expected -20 to 20"),
+            ));
+        }
+    }
+    set(
+        libc::RLIMIT_NICE,
+        "RLIMIT_NICE",
+        limit.map(|nice| (NICE_RLIMIT_BASE - nice) as libc::rlim_t),
+    )
+}
+
+/// The `RLIMIT_MEMLOCK` limit: the memory the process may lock, in bytes.
+#[allow(clippy::unnecessary_cast)]
+pub fn memlock() -> Result<Limit<u64>, AudioThreadPriorityError> {
+    Ok(get(libc::RLIMIT_MEMLOCK, "RLIMIT_MEMLOCK")?.map(|bytes| bytes as u64))
+}
+
+/// Set the `RLIMIT_MEMLOCK` limit, in bytes.
+pub fn set_memlock(limit: Limit<u64>) -> Result<(), AudioThreadPriorityError> {
+    set(libc::RLIMIT_MEMLOCK, "RLIMIT_MEMLOCK", limit.map(rlim))
+}
+
+/// The `RLIMIT_RTTIME` soft limit the promotion backends set for a stream: the duration of one
+/// buffer, or 50ms when the buffer size is 0 (not known).
+pub fn recommended_rttime(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<Duration, AudioThreadPriorityError> {
+    if audio_samplerate_hz == 0 {
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::InvalidArgument,
+            "sample rate is zero",
+        ));
+    }
+    Ok(rttime_budget(audio_buffer_frames, audio_samplerate_hz))
+}
+
+/// [`recommended_rttime`], for a sample rate already checked.
+pub(crate) fn rttime_budget(audio_buffer_frames: u32, audio_samplerate_hz: u32) -> Duration {
+    let buffer_frames = if audio_buffer_frames > 0 {
+        audio_buffer_frames
+    } else {
+        // 50ms slice. This "ought to be enough for anybody".
+        audio_samplerate_hz / 20
+    };
+    Duration::from_micros(buffer_frames as u64 * 1_000_000 / audio_samplerate_hz as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recommended_rttime() {
+        assert_eq!(
+            recommended_rttime(512, 44100).unwrap(),
+            Duration::from_micros(11_609)
+        );
+        assert_eq!(
+            recommended_rttime(0, 48000).unwrap(),
+            Duration::from_millis(50)
+        );
+        assert!(recommended_rttime(512, 0).is_err());
+    }
+
+    // Setting a limit to its current value changes nothing, so this can run alongside the other
+    // tests, and checks that the conversions round-trip. `RLIMIT_RTTIME` and `RLIMIT_RTPRIO` are
+    // left alone: the promotion tests change them.
+    #[test]
+    fn test_limits_round_trip() {
+        let nice = nice().unwrap();
+        set_nice(nice).unwrap();
+        assert_eq!(super::nice().unwrap(), nice);
+
+        let memlock = memlock().unwrap();
+        set_memlock(memlock).unwrap();
+        assert_eq!(super::memlock().unwrap(), memlock);
+
+        assert!(set_nice(Limit {
+            soft: Some(-21),
+            hard: None
+        })
+        .is_err());
+    }
+}
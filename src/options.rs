@@ -0,0 +1,161 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Per-call promotion options, see [`PromotionOptions`].
+
+use std::fmt;
+use std::time::Duration;
+
+/// The real-time scheduling policy to request on Linux.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    /// `SCHED_FIFO`: the thread runs until it blocks or yields, or a higher priority thread wakes up.
+    Fifo,
+    /// `SCHED_RR`: like `SCHED_FIFO`, but threads of the same priority share the CPU in turns. This
+    /// is the only fixed-priority policy rtkit grants.
+    RoundRobin,
+    /// `SCHED_DEADLINE`: a CPU reservation derived from the buffer duration instead of a fixed
+    /// priority. Only the native backend can set it, and it always needs `CAP_SYS_NICE`.
+    Deadline,
+}
+
+/// A mechanism to promote a thread on Linux. The backends are tried in turn until one succeeds,
+/// see `set_linux_backends`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LinuxBackend {
+    /// rtkit on the system bus, which changes the scheduling of unprivileged processes on their
+    /// behalf. Not used inside a Flatpak or Snap sandbox, where it cannot be reached and process
+    /// and thread ids are namespaced. Needs the `dbus` or `with_rust_dbus` feature.
+    Rtkit,
+    /// The `org.freedesktop.portal.Realtime` interface of xdg-desktop-portal on the session bus,
+    /// which forwards requests from sandboxed processes to rtkit. Needs the `dbus` or
+    /// `with_rust_dbus` feature.
+    Portal,
+    /// `sched_setscheduler`, directly. This needs the process to be allowed to request real-time
+    /// scheduling: running as root, holding `CAP_SYS_NICE`, or with an `RLIMIT_RTPRIO` limit
+    /// configured.
+    Native,
+    /// A lower nice level, which is not real-time scheduling, but makes the thread preferred by
+    /// the default scheduler. This is a last resort, and is not used unless configured. It needs
+    /// `CAP_SYS_NICE` or an `RLIMIT_NICE` limit.
+    Nice,
+    /// A backend implemented by the application, registered under this name with
+    /// `register_priority_backend`.
+    Custom(&'static str),
+}
+
+impl fmt::Display for LinuxBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LinuxBackend::Rtkit => "rtkit",
+            LinuxBackend::Portal => "portal",
+            LinuxBackend::Native => "native",
+            LinuxBackend::Nice => "nice",
+            LinuxBackend::Custom(name) => name,
+        })
+    }
+}
+
+/// Options for a single promotion, passed to `promote_current_thread_to_real_time_with` and
+/// `promote_thread_to_real_time_with`.
+///
+/// Unlike `set_rt_priority`, which changes a process-wide default, these only apply to the call
+/// they are passed to, so different parts of a process can ask for different settings. Options that
+/// are not set keep the backend's defaults.
+///
+/// ```rust
+/// use audio_thread_priority::{PromotionOptions, SchedulingPolicy};
+///
+/// let options = PromotionOptions::new()
+///     .priority(5)
+///     .policy(SchedulingPolicy::RoundRobin);
+/// ```
+///
+/// The options are only used on Linux, and ignored on the other platforms.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PromotionOptions {
+    pub(crate) priority: Option<u8>,
+    pub(crate) policy: Option<SchedulingPolicy>,
+    pub(crate) reset_on_fork: bool,
+    pub(crate) budget: Option<Duration>,
+    pub(crate) backends: Option<Vec<LinuxBackend>>,
+}
+
+impl Default for PromotionOptions {
+    fn default() -> Self {
+        PromotionOptions {
+            priority: None,
+            policy: None,
+            reset_on_fork: true,
+            budget: None,
+            backends: None,
+        }
+    }
+}
+
+impl PromotionOptions {
+    /// Options that keep every default of the backend.
+    pub fn new() -> PromotionOptions {
+        Self::default()
+    }
+
+    /// The real-time priority to request, 1-99. Defaults to 10, or to the value set with
+    /// `set_rt_priority` on the native backend. Ignored by `SchedulingPolicy::Deadline`.
+    pub fn priority(mut self, priority: u8) -> PromotionOptions {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// The scheduling policy to request. Defaults to `SchedulingPolicy::RoundRobin` with rtkit and
+    /// the portal, and `SchedulingPolicy::Fifo` with the native backend. rtkit cannot grant
+    /// `SchedulingPolicy::Fifo` or `SchedulingPolicy::Deadline`, and the nice level boost refuses
+    /// any policy, so those backends are skipped when one is set.
+    pub fn policy(mut self, policy: SchedulingPolicy) -> PromotionOptions {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Whether children forked by the promoted thread start with the default scheduling policy,
+    /// rather than inheriting real-time scheduling. Defaults to `true`. rtkit always sets this, so
+    /// disabling it skips the rtkit and portal backends. It cannot be disabled for
+    /// `SchedulingPolicy::Deadline`, which the kernel does not let a thread fork without.
+    pub fn reset_on_fork(mut self, reset_on_fork: bool) -> PromotionOptions {
+        self.reset_on_fork = reset_on_fork;
+        self
+    }
+
+    /// The CPU time the thread may use without blocking, instead of the value derived from the
+    /// buffer size and sample rate. This is the `RLIMIT_RTTIME` budget for `SchedulingPolicy::Fifo`
+    /// and `SchedulingPolicy::RoundRobin`, and the runtime reserved each period (capped to the
+    /// period) for `SchedulingPolicy::Deadline`.
+    pub fn budget(mut self, budget: Duration) -> PromotionOptions {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// The backends to try for this promotion, in order, instead of the ones set with
+    /// `set_linux_backends`.
+    pub fn backends(mut self, backends: &[LinuxBackend]) -> PromotionOptions {
+        self.backends = Some(backends.to_vec());
+        self
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PromotionOptions {
+    /// The requested priority, if any, checked to be a valid real-time priority.
+    pub(crate) fn checked_priority(
+        &self,
+    ) -> Result<Option<libc::c_int>, crate::AudioThreadPriorityError> {
+        match self.priority {
+            Some(priority) if !(1..=99).contains(&priority) => {
+                Err(crate::AudioThreadPriorityError::with_kind(
+                    crate::ErrorKind::InvalidArgument,
+                    &format!("invalid real-time priority {priority}, expected an integer 1-99"),
+                ))
+            }
+            priority => Ok(priority.map(libc::c_int::from)),
+        }
+    }
+}
@@ -31,15 +31,17 @@ pub fn promote_current_thread_to_real_time_internal(
     unsafe { (*libc::__errno()) = 0 };
     let previous_priority = unsafe { libc::getpriority(libc::PRIO_PROCESS, who) };
     if previous_priority == -1 && unsafe { *libc::__errno() } != 0 {
-        return Err(AudioThreadPriorityError::new(
+        return Err(AudioThreadPriorityError::from_os_error(
             "Failed to get current thread priority",
+            &std::io::Error::last_os_error(),
         ));
     }
 
     let r = unsafe { libc::setpriority(libc::PRIO_PROCESS, who, THREAD_PRIORITY_URGENT_AUDIO) };
     if r < 0 {
-        return Err(AudioThreadPriorityError::new(
+        return Err(AudioThreadPriorityError::from_os_error(
             "Failed to set current thread priority",
+            &std::io::Error::last_os_error(),
         ));
     }
 
@@ -52,8 +54,9 @@ pub fn demote_current_thread_from_real_time_internal(
     let who = unsafe { libc::gettid().try_into().unwrap() };
     let r = unsafe { libc::setpriority(libc::PRIO_PROCESS, who, h.previous_priority) };
     if r < 0 {
-        return Err(AudioThreadPriorityError::new(
+        return Err(AudioThreadPriorityError::from_os_error(
             "Failed to demote thread priority",
+            &std::io::Error::last_os_error(),
         ));
     }
     Ok(())
@@ -2,357 +2,894 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this file,
  * You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-/* Widely copied from dbus-rs/dbus/examples/rtkit.rs */
+//! The rtkit and desktop portal backends, which ask a D-Bus service to make threads real-time,
+//! over the D-Bus client of `rt_linux_bus`.
 
-extern crate dbus;
 extern crate libc;
 
-use std::cmp;
-use std::convert::TryInto;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::io::Error as OSError;
-
-use dbus::{BusType, Connection, Message, MessageItem, Props};
-
-use crate::AudioThreadPriorityError;
-
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use log::info;
+
+use crate::limits;
+use crate::rt_linux_bus::{Argument, Bus, BusConnection};
+use crate::rt_linux_native::{self, RtPriorityThreadInfoInternal};
+use crate::rt_linux_rttime::{set_rttime_limit, RttimeBudgets};
+use crate::{
+    AudioThreadPriorityError, BackendPromotion, ErrorKind, PriorityBackend, PromotionOptions,
+    SchedulingPolicy,
+};
+
+/// Default timeout for rtkit calls, in milliseconds, unless overridden with [`set_rtkit_timeout`].
 const DBUS_SOCKET_TIMEOUT: i32 = 10_000;
 const RT_PRIO_DEFAULT: u32 = 10;
-// This is different from libc::pid_t, which is 32 bits, and is defined in sys/types.h.
-#[allow(non_camel_case_types)]
-type kernel_pid_t = libc::c_long;
-
-impl From<dbus::Error> for AudioThreadPriorityError {
-    fn from(error: dbus::Error) -> Self {
-        AudioThreadPriorityError::new(&format!(
-            "{}:{}",
-            error.name().unwrap_or("?"),
-            error.message().unwrap_or("?")
-        ))
-    }
+/// The default `RTTimeUSecMax` of rtkit.
+const RTKIT_DEFAULT_RTTIME_MAX: Duration = Duration::from_millis(200);
+
+/// The timeout for rtkit calls in milliseconds, or 0 to use `DBUS_SOCKET_TIMEOUT`. Set via
+/// [`set_rtkit_timeout`].
+static RTKIT_TIMEOUT_MS: AtomicI32 = AtomicI32::new(0);
+
+/// Set how long to wait for rtkit to answer, overriding the default of 10 seconds. Pass `None` to
+/// restore the default.
+///
+/// This applies to every rtkit call made by the process from then on. It is specific to the Linux
+/// build with the `dbus` or `with_rust_dbus` feature.
+pub fn set_rtkit_timeout(timeout: Option<Duration>) {
+    let timeout_ms = match timeout {
+        Some(timeout) => i32::try_from(timeout.as_millis())
+            .unwrap_or(i32::MAX)
+            .max(1),
+        None => 0,
+    };
+    RTKIT_TIMEOUT_MS.store(timeout_ms, Ordering::Relaxed);
 }
 
+/// The timeout for rtkit calls: the value set via [`set_rtkit_timeout`], or the default.
+fn rtkit_timeout() -> i32 {
+    match RTKIT_TIMEOUT_MS.load(Ordering::Relaxed) {
+        0 => DBUS_SOCKET_TIMEOUT,
+        timeout_ms => timeout_ms,
+    }
+}
 impl From<Box<dyn Error>> for AudioThreadPriorityError {
     fn from(error: Box<dyn Error>) -> Self {
         AudioThreadPriorityError::new(&error.to_string())
     }
 }
 
-#[repr(C)]
+/*#[derive(Debug)]*/
+pub struct RtPriorityHandleInternal {
+    thread_info: RtPriorityThreadInfoInternal,
+    /// The real-time priority rtkit granted, after clamping to its `MaxRealtimePriority`.
+    priority: u32,
+    /// Whether the `RLIMIT_RTTIME` budget came from the promotion options rather than the buffer
+    /// duration, in which case it is kept when the buffer duration changes.
+    explicit_budget: bool,
+}
+
+impl RtPriorityHandleInternal {
+    /// The real-time priority the thread was granted. This can be lower than the requested
+    /// priority when rtkit's `MaxRealtimePriority` is lower.
+    pub fn granted_priority(&self) -> Option<u32> {
+        Some(self.priority)
+    }
+}
+
+/// rtkit's limits, read once per process.
 #[derive(Clone, Copy)]
-pub struct RtPriorityThreadInfoInternal {
-    /// System-wise thread id, use to promote the thread via dbus.
-    thread_id: kernel_pid_t,
-    /// Process-local thread id, used to restore scheduler characteristics. This information is not
-    /// useful in another process, but is useful tied to the `thread_id`, when back into the first
-    /// process.
-    pthread_id: libc::pthread_t,
-    /// The PID of the process containing `thread_id` below.
-    pid: libc::pid_t,
-    /// The scheduling policy in place before promotion, to restore on demotion.
-    policy: libc::c_int,
-    /// The scheduling priority in place before promotion, to restore on demotion.
-    priority: libc::c_int,
+struct RtkitProperties {
+    max_realtime_priority: i64,
+    rttime_usec_max: u64,
+}
+
+/// The D-Bus service that grants real-time scheduling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RealtimeService {
+    /// rtkit, on the system bus.
+    Rtkit,
+    /// The `org.freedesktop.portal.Realtime` interface of xdg-desktop-portal, on the session bus.
+    /// It forwards requests from sandboxed processes to rtkit, translating their namespaced process
+    /// and thread ids, and has the same limits as rtkit.
+    Portal,
 }
 
-impl RtPriorityThreadInfoInternal {
-    /// Serialize to a byte buffer. The fields are packed explicitly rather than transmuting the
-    /// struct, so no uninitialized padding bytes are ever read. Any trailing padding stays zero.
-    pub fn serialize(&self) -> [u8; std::mem::size_of::<Self>()] {
-        let thread_id = self.thread_id.to_ne_bytes();
-        let pthread_id = self.pthread_id.to_ne_bytes();
-        let pid = self.pid.to_ne_bytes();
-        let policy = self.policy.to_ne_bytes();
-        let priority = self.priority.to_ne_bytes();
-
-        let mut bytes = [0u8; std::mem::size_of::<Self>()];
-        let fields = thread_id
-            .iter()
-            .chain(&pthread_id)
-            .chain(&pid)
-            .chain(&policy)
-            .chain(&priority);
-        for (dst, &src) in bytes.iter_mut().zip(fields) {
-            *dst = src;
+impl RealtimeService {
+    /// Fail if this service cannot be used by this process: rtkit is not reachable from a Flatpak
+    /// or Snap sandbox, where the system bus is not available and process and thread ids are
+    /// namespaced. The portal is meant for those.
+    fn check_available(self) -> Result<(), AudioThreadPriorityError> {
+        if self == RealtimeService::Rtkit
+            && (std::path::Path::new("/.flatpak-info").exists()
+                || std::env::var_os("SNAP").is_some())
+        {
+            return Err(AudioThreadPriorityError::with_kind(
+                ErrorKind::ServiceUnavailable,
+                "rtkit cannot be reached from a Flatpak or Snap sandbox",
+            ));
         }
-        bytes
-    }
-    /// Reconstruct from a byte buffer produced by `serialize`.
-    pub fn deserialize(bytes: [u8; std::mem::size_of::<Self>()]) -> Self {
-        fn take<const N: usize>(src: &mut impl Iterator<Item = u8>) -> [u8; N] {
-            let mut chunk = [0u8; N];
-            for slot in &mut chunk {
-                *slot = src.next().unwrap();
-            }
-            chunk
+        Ok(())
+    }
+
+    /// The process-wide state of this service. Only lock it to take or return the client, or read
+    /// the properties, never for a D-Bus call.
+    fn state(self) -> MutexGuard<'static, ServiceState> {
+        let mut state = match self {
+            RealtimeService::Rtkit => &RTKIT_STATE,
+            RealtimeService::Portal => &PORTAL_STATE,
         }
-        let mut src = bytes.iter().copied();
-        RtPriorityThreadInfoInternal {
-            thread_id: kernel_pid_t::from_ne_bytes(take(&mut src)),
-            pthread_id: libc::pthread_t::from_ne_bytes(take(&mut src)),
-            pid: libc::pid_t::from_ne_bytes(take(&mut src)),
-            policy: libc::c_int::from_ne_bytes(take(&mut src)),
-            priority: libc::c_int::from_ne_bytes(take(&mut src)),
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+        let pid = unsafe { libc::getpid() };
+        if state.pid != pid {
+            // A connection inherited from the parent process shares its socket with the parent,
+            // which may be using it: let go of it without sending anything.
+            if let Some(client) = state.client.take() {
+                client.connection.close_inherited();
+            }
+            *state = ServiceState {
+                pid,
+                client: None,
+                properties: None,
+            };
         }
+        state
     }
-    /// Returns the PID of the process containing the thread.
-    pub fn pid(&self) -> libc::pid_t {
-        self.pid
+
+    fn bus(self) -> Bus {
+        match self {
+            RealtimeService::Rtkit => Bus::System,
+            RealtimeService::Portal => Bus::Session,
+        }
     }
-}
 
-impl PartialEq for RtPriorityThreadInfoInternal {
-    fn eq(&self, other: &Self) -> bool {
-        self.thread_id == other.thread_id && self.pthread_id == other.pthread_id
+    fn destination(self) -> &'static str {
+        match self {
+            RealtimeService::Rtkit => "org.freedesktop.RealtimeKit1",
+            RealtimeService::Portal => "org.freedesktop.portal.Desktop",
+        }
     }
-}
 
-/*#[derive(Debug)]*/
-pub struct RtPriorityHandleInternal {
-    thread_info: RtPriorityThreadInfoInternal,
-}
+    fn path(self) -> &'static str {
+        match self {
+            RealtimeService::Rtkit => "/org/freedesktop/RealtimeKit1",
+            RealtimeService::Portal => "/org/freedesktop/portal/desktop",
+        }
+    }
 
-fn item_as_i64(i: MessageItem) -> Result<i64, AudioThreadPriorityError> {
-    match i {
-        MessageItem::Int32(i) => Ok(i as i64),
-        MessageItem::Int64(i) => Ok(i),
-        _ => Err(AudioThreadPriorityError::new(&format!(
-            "Property is not integer ({i:?})"
-        ))),
+    fn interface(self) -> &'static str {
+        match self {
+            RealtimeService::Rtkit => "org.freedesktop.RealtimeKit1",
+            RealtimeService::Portal => "org.freedesktop.portal.Realtime",
+        }
     }
 }
 
-fn rtkit_set_realtime(thread: u64, pid: u64, prio: u32) -> Result<(), Box<dyn Error>> {
-    let m = if unsafe { libc::getpid() as u64 } == pid {
-        let mut m = Message::new_method_call(
-            "org.freedesktop.RealtimeKit1",
-            "/org/freedesktop/RealtimeKit1",
-            "org.freedesktop.RealtimeKit1",
-            "MakeThreadRealtime",
-        )?;
-        m.append_items(&[thread.into(), prio.into()]);
-        m
-    } else {
-        let mut m = Message::new_method_call(
-            "org.freedesktop.RealtimeKit1",
-            "/org/freedesktop/RealtimeKit1",
-            "org.freedesktop.RealtimeKit1",
-            "MakeThreadRealtimeWithPID",
-        )?;
-        m.append_items(&[pid.into(), thread.into(), prio.into()]);
-        m
-    };
-    let c = Connection::get_private(BusType::System)?;
-    c.send_with_reply_and_block(m, DBUS_SOCKET_TIMEOUT)?;
-    Ok(())
+/// A connection to rtkit, directly or through the portal, shared by the whole process so that
+/// promotions do not pay for connecting and authenticating each time.
+struct RtkitClient {
+    service: RealtimeService,
+    connection: BusConnection,
+    /// The timeout `connection` was opened with, to reconnect if `set_rtkit_timeout` changes it.
+    timeout_ms: i32,
 }
 
-/// Returns the maximum priority, maximum real-time time slice, and the current real-time time
-/// slice for this process.
-fn get_limits() -> Result<(i64, u64, libc::rlimit), AudioThreadPriorityError> {
-    let c = Connection::get_private(BusType::System)?;
-
-    let p = Props::new(
-        &c,
-        "org.freedesktop.RealtimeKit1",
-        "/org/freedesktop/RealtimeKit1",
-        "org.freedesktop.RealtimeKit1",
-        DBUS_SOCKET_TIMEOUT,
-    );
-    let mut current_limit = libc::rlimit {
-        rlim_cur: 0,
-        rlim_max: 0,
-    };
+/// The state of a service shared by the whole process.
+struct ServiceState {
+    /// The process the state belongs to: a child forked since must not use the connection of its
+    /// parent.
+    pid: libc::pid_t,
+    /// The client, unless a call is using it. Concurrent calls open their own connection, and only
+    /// one is kept.
+    client: Option<RtkitClient>,
+    properties: Option<RtkitProperties>,
+}
 
-    let max_prio = item_as_i64(p.get("MaxRealtimePriority")?)?;
-    if max_prio < 0 {
-        return Err(AudioThreadPriorityError::new(
-            "invalid negative MaxRealtimePriority",
-        ));
+static RTKIT_STATE: Mutex<ServiceState> = Mutex::new(ServiceState {
+    pid: 0,
+    client: None,
+    properties: None,
+});
+static PORTAL_STATE: Mutex<ServiceState> = Mutex::new(ServiceState {
+    pid: 0,
+    client: None,
+    properties: None,
+});
+
+impl RtkitClient {
+    fn connect(service: RealtimeService) -> Result<RtkitClient, AudioThreadPriorityError> {
+        let timeout_ms = rtkit_timeout();
+        Ok(RtkitClient {
+            service,
+            connection: BusConnection::open(
+                service.bus(),
+                Duration::from_millis(timeout_ms as u64),
+            )?,
+            timeout_ms,
+        })
     }
 
-    let max_rttime = item_as_i64(p.get("RTTimeUSecMax")?)?;
-    if max_rttime < 0 {
-        return Err(AudioThreadPriorityError::new(
-            "invalid negative RTTimeUSecMax",
-        ));
+    fn make_thread_realtime(
+        &self,
+        thread: u64,
+        pid: u64,
+        prio: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let service = self.service;
+        // The portal only has the variant with a PID.
+        if service == RealtimeService::Rtkit && unsafe { libc::getpid() as u64 } == pid {
+            self.connection.call(
+                service.destination(),
+                service.path(),
+                service.interface(),
+                "MakeThreadRealtime",
+                &[Argument::U64(thread), Argument::U32(prio)],
+            )
+        } else {
+            self.connection.call(
+                service.destination(),
+                service.path(),
+                service.interface(),
+                "MakeThreadRealtimeWithPID",
+                &[
+                    Argument::U64(pid),
+                    Argument::U64(thread),
+                    Argument::U32(prio),
+                ],
+            )
+        }
     }
 
-    if unsafe { libc::getrlimit(libc::RLIMIT_RTTIME, &mut current_limit) } < 0 {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "getrlimit",
-            Box::new(OSError::last_os_error()),
-        ));
-    }
+    /// rtkit's maximum priority and maximum real-time time slice.
+    fn properties(&self) -> Result<RtkitProperties, AudioThreadPriorityError> {
+        let service = self.service;
+        let get = |name| {
+            self.connection.get_i64_property(
+                service.destination(),
+                service.path(),
+                service.interface(),
+                name,
+            )
+        };
+
+        let max_prio = get("MaxRealtimePriority")?;
+        if max_prio < 0 {
+            return Err(AudioThreadPriorityError::new(
+                "invalid negative MaxRealtimePriority",
+            ));
+        }
 
-    Ok((max_prio, (max_rttime as u64), current_limit))
+        let max_rttime = get("RTTimeUSecMax")?;
+        if max_rttime < 0 {
+            return Err(AudioThreadPriorityError::new(
+                "invalid negative RTTimeUSecMax",
+            ));
+        }
+
+        Ok(RtkitProperties {
+            max_realtime_priority: max_prio,
+            rttime_usec_max: max_rttime as u64,
+        })
+    }
 }
 
-fn set_limits(request: u64, max: u64) -> Result<(), AudioThreadPriorityError> {
-    // Set a soft limit to the limit requested, to be able to handle going over the limit using
-    // SIGXCPU. Set the hard limit to the maximum slice to prevent getting SIGKILL.
-    #[allow(clippy::useless_conversion)]
-    let new_limit = libc::rlimit {
-        rlim_cur: request
-            .try_into()
-            .map_err(|_| AudioThreadPriorityError::new("setrlimit"))?,
-        rlim_max: max
-            .try_into()
-            .map_err(|_| AudioThreadPriorityError::new("setrlimit"))?,
-    };
-    if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &new_limit) } < 0 {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "setrlimit",
-            Box::new(OSError::last_os_error()),
-        ));
+/// Run `f` with the process-wide client of `service`, connecting first if needed. If `f` fails
+/// because the bus connection dropped, reconnect and try once more.
+///
+/// The client is taken out of the state of the service for the call, which can block for up to the
+/// rtkit timeout, so that other threads are not blocked meanwhile.
+fn with_rtkit<T, E: From<AudioThreadPriorityError>>(
+    service: RealtimeService,
+    mut f: impl FnMut(&RtkitClient) -> Result<T, E>,
+) -> Result<T, E> {
+    service.check_available()?;
+    let mut client =
+        service.state().client.take().filter(|client| {
+            client.connection.is_connected() && client.timeout_ms == rtkit_timeout()
+        });
+
+    let mut reconnected = false;
+    loop {
+        let current = match client.take() {
+            Some(current) => current,
+            None => {
+                reconnected = true;
+                RtkitClient::connect(service)?
+            }
+        };
+        let result = f(&current);
+        if !current.connection.is_connected() {
+            if result.is_err() && !reconnected {
+                info!(
+                    "D-Bus connection to {:?} lost, reconnecting.",
+                    current.service
+                );
+                continue;
+            }
+            return result;
+        }
+        let mut state = service.state();
+        if state.client.is_none() {
+            state.client = Some(current);
+        }
+        return result;
     }
+}
 
-    Ok(())
+/// Returns rtkit's maximum priority and maximum real-time time slice, read once per process.
+fn rtkit_limits(service: RealtimeService) -> Result<(i64, Duration), AudioThreadPriorityError> {
+    let cached = service.state().properties;
+    let properties = match cached {
+        Some(properties) => properties,
+        None => {
+            let properties = with_rtkit(service, |client| client.properties())?;
+            service.state().properties = Some(properties);
+            properties
+        }
+    };
+    Ok((
+        properties.max_realtime_priority,
+        Duration::from_micros(properties.rttime_usec_max),
+    ))
 }
 
-pub fn promote_current_thread_to_real_time_internal(
-    audio_buffer_frames: u32,
-    audio_samplerate_hz: u32,
-) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
-    let thread_info = get_current_thread_info_internal()?;
-    promote_thread_to_real_time_internal(thread_info, audio_buffer_frames, audio_samplerate_hz)
+/// rtkit's maximum real-time time slice if it was read already, or its default otherwise: setting
+/// the limit must work without rtkit, in a process that is promoted by another.
+fn known_rttime_max(service: RealtimeService) -> Duration {
+    service
+        .state()
+        .properties
+        .map_or(RTKIT_DEFAULT_RTTIME_MAX, |properties| {
+            Duration::from_micros(properties.rttime_usec_max)
+        })
 }
 
+/// Restore the thread promoted by `rt_priority_handle` to the scheduling policy it had before
+/// promotion. rtkit only promotes, so this is the native demotion, by tid: it works from any thread
+/// of the process. Once no thread of the process promoted through rtkit is left, its
+/// `RLIMIT_RTTIME` limit is restored.
 pub fn demote_current_thread_from_real_time_internal(
+    service: RealtimeService,
     rt_priority_handle: RtPriorityHandleInternal,
 ) -> Result<(), AudioThreadPriorityError> {
-    assert!(unsafe { libc::pthread_self() } == rt_priority_handle.thread_info.pthread_id);
-
-    let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
-    param.sched_priority = rt_priority_handle.thread_info.priority;
-
-    if unsafe {
-        libc::pthread_setschedparam(
-            rt_priority_handle.thread_info.pthread_id,
-            rt_priority_handle.thread_info.policy,
-            &param,
-        )
-    } < 0
-    {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "could not demote thread",
-            Box::new(OSError::last_os_error()),
-        ));
-    }
-    Ok(())
+    let thread_info = rt_priority_handle.thread_info;
+    let demotion = rt_linux_native::demote_thread_from_real_time_internal(thread_info);
+    forget_budget(service, thread_info, demotion)
 }
 
-/// This can be called by sandboxed code, it only restores priority to what they were.
-pub fn demote_thread_from_real_time_internal(
-    thread_info: RtPriorityThreadInfoInternal,
+/// Adapt a promotion to a new buffer duration: the `RLIMIT_RTTIME` budget is derived from it, unless
+/// the promotion options set one. rtkit is not asked again, the thread keeps its priority.
+///
+/// The limit is the one of the calling process: for a thread of another process, that process
+/// updates its own with `set_real_time_hard_limit`.
+pub fn update_real_time_parameters_internal(
+    service: RealtimeService,
+    rt_priority_handle: &RtPriorityHandleInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
 ) -> Result<(), AudioThreadPriorityError> {
-    let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
-    param.sched_priority = thread_info.priority;
-
-    // https://github.com/rust-lang/libc/issues/1511
-    const SCHED_RESET_ON_FORK: libc::c_int = 0x40000000;
-
-    if unsafe {
-        libc::pthread_setschedparam(
-            thread_info.pthread_id,
-            thread_info.policy | SCHED_RESET_ON_FORK,
-            &param,
-        )
-    } < 0
+    if rt_priority_handle.explicit_budget
+        || rt_priority_handle.thread_info.pid != unsafe { libc::getpid() }
     {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "could not demote thread",
-            Box::new(OSError::last_os_error()),
-        ));
+        return Ok(());
     }
-    Ok(())
+    let max_rttime = known_rttime_max(service);
+    RttimeBudgets::lock().set(
+        rt_priority_handle.thread_info.thread_id,
+        limits::rttime_budget(audio_buffer_frames, audio_samplerate_hz),
+        Some(max_rttime),
+    )
 }
 
-/// Get the current thread information, as an opaque struct, that can be serialized and sent
-/// accross processes. This is enough to capture the current state of the scheduling policy, and
-/// an identifier to have another thread promoted to real-time.
-pub fn get_current_thread_info_internal(
-) -> Result<RtPriorityThreadInfoInternal, AudioThreadPriorityError> {
-    let thread_id = unsafe { libc::syscall(libc::SYS_gettid) };
-    let pthread_id = unsafe { libc::pthread_self() };
-    let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
-    let mut policy = 0;
-
-    if unsafe { libc::pthread_getschedparam(pthread_id, &mut policy, &mut param) } < 0 {
-        return Err(AudioThreadPriorityError::new_with_inner(
-            "pthread_getschedparam",
-            Box::new(OSError::last_os_error()),
-        ));
+/// Forget the budget of `thread_info` if it is a thread of this process, restoring the limit of the
+/// process if it was the last real-time thread, and return the result of its `demotion`.
+fn forget_budget(
+    service: RealtimeService,
+    thread_info: RtPriorityThreadInfoInternal,
+    demotion: Result<(), AudioThreadPriorityError>,
+) -> Result<(), AudioThreadPriorityError> {
+    if thread_info.pid != unsafe { libc::getpid() } {
+        return demotion;
     }
-
-    let pid = unsafe { libc::getpid() };
-
-    Ok(RtPriorityThreadInfoInternal {
-        pid,
-        thread_id,
-        pthread_id,
-        policy,
-        priority: param.sched_priority,
-    })
+    let max_rttime = known_rttime_max(service);
+    let restored = RttimeBudgets::lock().remove(thread_info.thread_id, Some(max_rttime));
+    demotion.and(restored)
 }
 
-/// This set the RLIMIT_RTTIME resource to something other than "unlimited". It's necessary for the
-/// rtkit request to succeed, and needs to hapen in the child. We can't get the real limit here,
-/// because we don't have access to DBUS, so it is hardcoded to 200ms, which is the default in the
-/// rtkit package.
+/// This sets the RLIMIT_RTTIME resource to something other than "unlimited". It's necessary for
+/// the rtkit request to succeed, and needs to happen in the process to promote, which may not be
+/// able to reach rtkit: unless it was read already, the maximum is rtkit's default of 200ms.
 pub fn set_real_time_hard_limit_internal(
+    service: RealtimeService,
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
 ) -> Result<(), AudioThreadPriorityError> {
-    let buffer_frames = if audio_buffer_frames > 0 {
-        audio_buffer_frames
-    } else {
-        // 50ms slice. This "ought to be enough for anybody".
-        audio_samplerate_hz / 20
-    };
-    let budget_us = buffer_frames as u64 * 1_000_000 / audio_samplerate_hz as u64;
-
-    // It's only necessary to set RLIMIT_RTTIME to something when in the child, skip it if it's a
-    // remoting call.
-    let (_, max_rttime, _) = get_limits()?;
-
-    // Only take what we need, or cap at the system limit, no further.
-    let rttime_request = cmp::min(budget_us, max_rttime);
-    set_limits(rttime_request, max_rttime)?;
+    set_rttime_limit(
+        limits::rttime_budget(audio_buffer_frames, audio_samplerate_hz),
+        Some(known_rttime_max(service)),
+    )
+}
 
-    Ok(())
+/// Clamp the `requested` priority to rtkit's `MaxRealtimePriority`, which rtkit refuses to exceed.
+fn clamp_priority(requested: u32, max_prio: i64) -> Result<u32, AudioThreadPriorityError> {
+    if max_prio < 1 {
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::LimitExceeded,
+            "rtkit does not allow any real-time priority (MaxRealtimePriority is 0)",
+        ));
+    }
+    let max_prio = u32::try_from(max_prio).unwrap_or(u32::MAX);
+    if requested > max_prio {
+        info!("real-time priority {requested} clamped to rtkit's maximum of {max_prio}.");
+        return Ok(max_prio);
+    }
+    Ok(requested)
 }
 
-/// Promote a thread (possibly in another process) identified by its tid, to real-time.
+/// Promote a thread (possibly in another process) identified by its tid, to real-time, through
+/// `service`.
+///
+/// rtkit always grants `SCHED_RR` with `SCHED_RESET_ON_FORK`, so `options` cannot ask for
+/// `SCHED_FIFO` or `SCHED_DEADLINE`, or disable reset-on-fork.
 pub fn promote_thread_to_real_time_internal(
+    service: RealtimeService,
     thread_info: RtPriorityThreadInfoInternal,
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
+    options: &PromotionOptions,
 ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    if !options.reset_on_fork {
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::Unsupported,
+            "rtkit always sets SCHED_RESET_ON_FORK, it cannot be disabled",
+        ));
+    }
+    match options.policy {
+        Some(SchedulingPolicy::Deadline) => {
+            return Err(AudioThreadPriorityError::with_kind(
+                ErrorKind::Unsupported,
+                "rtkit only grants SCHED_RR, SCHED_DEADLINE cannot be requested",
+            ));
+        }
+        Some(SchedulingPolicy::Fifo) => {
+            return Err(AudioThreadPriorityError::with_kind(
+                ErrorKind::Unsupported,
+                "rtkit only grants SCHED_RR, SCHED_FIFO cannot be requested",
+            ));
+        }
+        Some(SchedulingPolicy::RoundRobin) | None => {}
+    }
+    let requested = options
+        .checked_priority()?
+        .map_or(RT_PRIO_DEFAULT, |priority| priority as u32);
+
+    let (max_prio, max_rttime) = rtkit_limits(service)?;
+    let priority = clamp_priority(requested, max_prio)?;
+
     let RtPriorityThreadInfoInternal { pid, thread_id, .. } = thread_info;
 
-    let handle = RtPriorityHandleInternal { thread_info };
+    let handle = RtPriorityHandleInternal {
+        thread_info,
+        priority,
+        explicit_budget: options.budget.is_some(),
+    };
 
-    set_real_time_hard_limit_internal(audio_buffer_frames, audio_samplerate_hz)?;
+    // rtkit checks the limit of the process of the thread: for a thread of another process, that
+    // process sets its own with `set_real_time_hard_limit`.
+    let local = pid == unsafe { libc::getpid() };
+    if local {
+        let budget = options
+            .budget
+            .unwrap_or_else(|| limits::rttime_budget(audio_buffer_frames, audio_samplerate_hz));
+        RttimeBudgets::lock().set(thread_id, budget, Some(max_rttime))?;
+    }
 
-    let r = rtkit_set_realtime(thread_id as u64, pid as u64, RT_PRIO_DEFAULT);
+    let r = with_rtkit(service, |client| {
+        client.make_thread_realtime(thread_id as u64, pid as u64, priority)
+    });
 
     match r {
         Ok(_) => Ok(handle),
         Err(e) => {
-            let (_, _, limits) = get_limits()?;
-            if limits.rlim_cur != libc::RLIM_INFINITY
-                && unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limits) } < 0
-            {
-                return Err(AudioThreadPriorityError::new_with_inner(
-                    "setrlimit",
-                    Box::new(OSError::last_os_error()),
-                ));
+            if local {
+                RttimeBudgets::lock().remove(thread_id, Some(max_rttime))?;
             }
             Err(AudioThreadPriorityError::new_with_inner(
                 "Thread promotion error",
-                e,
+                Box::new(e),
             ))
         }
     }
 }
+
+impl PriorityBackend for RealtimeService {
+    fn name(&self) -> &'static str {
+        match self {
+            RealtimeService::Rtkit => "rtkit",
+            RealtimeService::Portal => "portal",
+        }
+    }
+
+    fn promote_current_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        self.promote_thread(
+            thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            options,
+        )
+    }
+
+    fn demote_current_thread(
+        &self,
+        promotion: BackendPromotion,
+    ) -> Result<(), AudioThreadPriorityError> {
+        demote_current_thread_from_real_time_internal(*self, promotion.state()?)
+    }
+
+    fn promote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let handle = promote_thread_to_real_time_internal(
+            *self,
+            thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            options,
+        )?;
+        let granted_priority = handle.granted_priority();
+        Ok(BackendPromotion::new(handle, granted_priority))
+    }
+
+    /// rtkit does not demote threads: lowering the scheduling of a thread needs no privilege, so
+    /// this is done directly.
+    fn demote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let demotion = rt_linux_native::demote_thread_from_real_time_internal(thread_info);
+        forget_budget(*self, thread_info, demotion)
+    }
+
+    fn update_parameters(
+        &self,
+        promotion: &mut BackendPromotion,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        update_real_time_parameters_internal(
+            *self,
+            promotion.state_mut()?,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+        )
+    }
+
+    fn set_real_time_limit(
+        &self,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        set_real_time_hard_limit_internal(*self, audio_buffer_frames, audio_samplerate_hz)
+    }
+}
+
+/// Run `f` in a forked child, see `test_child::fork_and_wait`, and return the pid of the child and
+/// its exit code. No D-Bus connection is ever made by the test process: the children of a process
+/// that used `zbus` cannot use it.
+#[cfg(test)]
+pub(crate) fn run_in_child(f: impl FnOnce() -> i32) -> (u64, i32) {
+    // Holding the state locks while forking makes sure no other thread holds them, which would
+    // leave them locked in the child.
+    let rtkit_guard = RTKIT_STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    let portal_guard = PORTAL_STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    let (child, code) = crate::test_child::fork_and_wait(move || {
+        drop(portal_guard);
+        drop(rtkit_guard);
+        f()
+    });
+    (child as u64, code)
+}
+
+/// Run `f` in a forked child, see `run_in_child`, with a stand-in for `service` serving `bus`, which
+/// is the bus of `service` in the child.
+#[cfg(test)]
+pub(crate) fn run_with_stand_in(
+    service: RealtimeService,
+    bus: &crate::rt_linux_fake_rtkit::PrivateBus,
+    f: impl FnOnce(&crate::rt_linux_fake_rtkit::FakeRtkit) -> i32,
+) -> (u64, i32) {
+    use crate::rt_linux_fake_rtkit::FakeRtkit;
+    run_in_child(|| {
+        // The child has a single thread until the stand-in starts.
+        let variable = match service.bus() {
+            Bus::System => "DBUS_SYSTEM_BUS_ADDRESS",
+            Bus::Session => "DBUS_SESSION_BUS_ADDRESS",
+        };
+        std::env::set_var(variable, bus.address());
+        let stand_in = match service {
+            RealtimeService::Rtkit => FakeRtkit::start(bus.address()),
+            RealtimeService::Portal => FakeRtkit::start_portal(bus.address()),
+        };
+        f(&stand_in.unwrap())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        clamp_priority, rtkit_limits, run_with_stand_in, set_rtkit_timeout, with_rtkit,
+        RealtimeService,
+    };
+    use crate::limits;
+    use crate::rt_linux_chain::{
+        demote_current_thread_from_real_time_internal, promote_current_thread_to_real_time_internal,
+    };
+    use crate::rt_linux_fake_rtkit::{PrivateBus, RtkitRefusal, RtkitRequest};
+    use crate::test_child::{fork_and_wait, PASSED};
+    use crate::{ErrorKind, LinuxBackend, PromotionOptions};
+    use std::time::Duration;
+
+    const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+    const FAILED: &str = "org.freedesktop.DBus.Error.Failed";
+    const SCHED_RESET_ON_FORK: libc::c_int = 0x4000_0000;
+
+    #[test]
+    fn test_clamp_priority() {
+        assert_eq!(clamp_priority(10, 20).unwrap(), 10);
+        assert_eq!(clamp_priority(10, 10).unwrap(), 10);
+        // Below the default of 10, as on some distributions.
+        assert_eq!(clamp_priority(10, 4).unwrap(), 4);
+        assert!(clamp_priority(10, 0).is_err());
+    }
+
+    /// The private bus for a test, or `None` to skip `test` without `dbus-daemon`.
+    fn private_bus(test: &str) -> Option<PrivateBus> {
+        match PrivateBus::start() {
+            Ok(bus) => Some(bus),
+            Err(e) => {
+                eprintln!("skipping {test}: {e}");
+                None
+            }
+        }
+    }
+
+    /// The pid of the calling process, which is also the thread id of its first thread.
+    fn pid() -> u64 {
+        unsafe { libc::getpid() as u64 }
+    }
+
+    #[test]
+    fn test_rtkit_stand_in() {
+        let bus = match private_bus("test_rtkit_stand_in") {
+            Some(bus) => bus,
+            None => return,
+        };
+        let (_, code) = run_with_stand_in(RealtimeService::Rtkit, &bus, |rtkit| {
+            let options = PromotionOptions::new().backends(&[LinuxBackend::Rtkit]);
+
+            // The default priority of 10 is clamped to MaxRealtimePriority.
+            rtkit.set_max_realtime_priority(5);
+            let handle =
+                promote_current_thread_to_real_time_internal(512, 44100, &options).unwrap();
+            assert_eq!(handle.backend(), LinuxBackend::Rtkit);
+            assert_eq!(handle.granted_priority(), Some(5));
+
+            rtkit.set_refusal(Some(RtkitRefusal::AccessDenied));
+            let e = promote_current_thread_to_real_time_internal(512, 44100, &options)
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+            assert_eq!(e.dbus_error_name(), Some(ACCESS_DENIED));
+
+            // The promoted thread is the one that forked, its thread id is the process id.
+            let request = |granted| RtkitRequest {
+                pid: None,
+                thread: pid(),
+                priority: 5,
+                granted,
+            };
+            assert_eq!(rtkit.requests(), [request(true), request(false)]);
+            PASSED
+        });
+        assert_eq!(code, PASSED);
+    }
+
+    // The stand-in answers as it is set up to: with its properties, refusing the threads whose
+    // RLIMIT_RTTIME is too high, with any D-Bus error, or not at all.
+    #[test]
+    fn test_rtkit_stand_in_settings() {
+        let bus = match private_bus("test_rtkit_stand_in_settings") {
+            Some(bus) => bus,
+            None => return,
+        };
+        let (_, code) = run_with_stand_in(RealtimeService::Rtkit, &bus, |rtkit| {
+            let service = RealtimeService::Rtkit;
+            let options = PromotionOptions::new().backends(&[LinuxBackend::Rtkit]);
+            let promote = || promote_current_thread_to_real_time_internal(512, 44100, &options);
+
+            rtkit.set_min_nice_level(-5);
+            let nice_level = with_rtkit(service, |client| {
+                client.connection.get_i64_property(
+                    service.destination(),
+                    service.path(),
+                    service.interface(),
+                    "MinNiceLevel",
+                )
+            });
+            assert_eq!(nice_level.unwrap(), -5);
+
+            // The limits of rtkit are read once: the RLIMIT_RTTIME set for the promotion is now
+            // above RTTimeUSecMax.
+            assert!(rtkit_limits(service).is_ok());
+            rtkit.set_rttime_usec_max(1_000);
+            assert_eq!(promote().err().unwrap().kind(), ErrorKind::PermissionDenied);
+            rtkit.set_check_rttime(false);
+            assert!(promote().is_ok());
+
+            let name = "org.freedesktop.DBus.Error.LimitsExceeded";
+            rtkit.set_refusal(Some(RtkitRefusal::Error {
+                name: name.into(),
+                message: "quota exhausted".into(),
+            }));
+            let e = promote().err().unwrap();
+            assert_eq!(e.dbus_error_name(), Some(name));
+            assert!(e.to_string().contains("quota exhausted"), "{}", e);
+
+            // The timeout is for the whole call.
+            rtkit.set_refusal(Some(RtkitRefusal::NoReply));
+            set_rtkit_timeout(Some(Duration::from_millis(200)));
+            let start = std::time::Instant::now();
+            assert_eq!(promote().err().unwrap().kind(), ErrorKind::Timeout);
+            assert!(start.elapsed() < Duration::from_secs(5));
+            set_rtkit_timeout(None);
+
+            // Really applying the scheduling needs privilege: the stand-in fails otherwise.
+            rtkit.set_refusal(None);
+            rtkit.set_apply_scheduling(true);
+            match promote() {
+                Ok(_) => {
+                    let policy = unsafe { libc::sched_getscheduler(0) };
+                    assert_eq!(policy & !SCHED_RESET_ON_FORK, libc::SCHED_RR);
+                }
+                Err(e) => assert_eq!(e.dbus_error_name(), Some(FAILED)),
+            }
+            PASSED
+        });
+        assert_eq!(code, PASSED);
+    }
+
+    #[test]
+    fn test_portal_promotion() {
+        let bus = match private_bus("test_portal_promotion") {
+            Some(bus) => bus,
+            None => return,
+        };
+        let (_, code) = run_with_stand_in(RealtimeService::Portal, &bus, |portal| {
+            let options = PromotionOptions::new().backends(&[LinuxBackend::Portal]);
+            let handle =
+                promote_current_thread_to_real_time_internal(512, 44100, &options).unwrap();
+            assert_eq!(handle.backend(), LinuxBackend::Portal);
+            assert_eq!(handle.granted_priority(), Some(10));
+            assert_eq!(
+                portal.requests(),
+                [RtkitRequest {
+                    pid: Some(pid()),
+                    thread: pid(),
+                    priority: 10,
+                    granted: true,
+                }]
+            );
+            PASSED
+        });
+        assert_eq!(code, PASSED);
+    }
+
+    // A child forked after the connection to rtkit was opened leaves its copy alone, while the
+    // parent keeps using the connection. With libdbus, the child closes its copy of the socket and
+    // opens its own. zbus cannot be used in the child at all, which is refused rather than hanging.
+    #[test]
+    fn test_inherited_connection_closed() {
+        let bus = match private_bus("test_inherited_connection_closed") {
+            Some(bus) => bus,
+            None => return,
+        };
+        let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+
+        let (_, code) = run_with_stand_in(RealtimeService::Rtkit, &bus, |_| {
+            assert!(rtkit_limits(RealtimeService::Rtkit).is_ok());
+            let (_, code) = fork_and_wait(|| {
+                let inherited = open_fds();
+                drop(RealtimeService::Rtkit.state());
+                let left = open_fds();
+                let properties = with_rtkit(RealtimeService::Rtkit, |client| client.properties());
+                if cfg!(feature = "dbus") {
+                    assert_eq!(left + 1, inherited);
+                    assert!(properties.is_ok());
+                } else {
+                    assert_eq!(left, inherited);
+                    assert_eq!(
+                        properties.err().unwrap().kind(),
+                        ErrorKind::ServiceUnavailable
+                    );
+                }
+                PASSED
+            });
+            assert_eq!(code, PASSED);
+            assert!(with_rtkit(RealtimeService::Rtkit, |client| client.properties()).is_ok());
+            PASSED
+        });
+        assert_eq!(code, PASSED);
+    }
+
+    // The RLIMIT_RTTIME limit follows the largest budget of the threads promoted through rtkit, and
+    // is restored once they are all demoted.
+    #[test]
+    fn test_rttime_restored_after_demotion() {
+        let bus = match private_bus("test_rttime_restored_after_demotion") {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        let (_, code) = run_with_stand_in(RealtimeService::Rtkit, &bus, |_| {
+            let soft = || limits::rttime().unwrap().soft;
+            let options = PromotionOptions::new().backends(&[LinuxBackend::Rtkit]);
+            // A limit within rtkit's maximum: restoring an unlimited hard limit needs privilege.
+            let original = limits::Limit {
+                soft: Some(Duration::from_millis(150)),
+                hard: Some(Duration::from_millis(200)),
+            };
+            limits::set_rttime(original).unwrap();
+            let handle = match promote_current_thread_to_real_time_internal(512, 44100, &options) {
+                Ok(handle) => handle,
+                Err(_) => return 1,
+            };
+            if soft() != Some(Duration::from_micros(11_609)) {
+                return 2;
+            }
+            let code = std::thread::spawn(move || {
+                let handle =
+                    match promote_current_thread_to_real_time_internal(1024, 48000, &options) {
+                        Ok(handle) => handle,
+                        Err(_) => return 3,
+                    };
+                if soft() != Some(Duration::from_micros(21_333)) {
+                    return 4;
+                }
+                if demote_current_thread_from_real_time_internal(handle).is_err() {
+                    return 5;
+                }
+                0
+            })
+            .join()
+            .unwrap();
+            if code != 0 {
+                return code;
+            }
+            if soft() != Some(Duration::from_micros(11_609)) {
+                return 6;
+            }
+            if demote_current_thread_from_real_time_internal(handle).is_err() {
+                return 7;
+            }
+            if limits::rttime().unwrap() != original {
+                return 8;
+            }
+            0
+        });
+        assert_eq!(code, 0);
+    }
+}
@@ -0,0 +1,286 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The D-Bus calls needed to talk to rtkit, over either D-Bus implementation:
+//!
+//! - the `dbus` feature uses the `dbus` crate, which links the C `libdbus-1`;
+//! - the `with_rust_dbus` feature uses `zbus`, written in Rust, which makes static linking and
+//!   cross-compiling simpler.
+//!
+//! `dbus` is used if both are enabled.
+
+/// A method call argument. rtkit only takes unsigned integers.
+#[derive(Clone, Copy, Debug)]
+pub enum Argument {
+    U32(u32),
+    U64(u64),
+}
+
+/// The bus to connect to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bus {
+    System,
+    Session,
+}
+
+#[cfg(feature = "dbus")]
+pub use self::libdbus::BusConnection;
+#[cfg(not(feature = "dbus"))]
+pub use self::rust_dbus::BusConnection;
+
+#[cfg(feature = "dbus")]
+mod libdbus {
+    use super::{Argument, Bus};
+    use crate::AudioThreadPriorityError;
+    use dbus::{BusType, Connection, Message, MessageItem, Props};
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    impl From<dbus::Error> for AudioThreadPriorityError {
+        fn from(error: dbus::Error) -> Self {
+            let message = error.message().unwrap_or("?");
+            match error.name() {
+                Some(name) => AudioThreadPriorityError::from_dbus(name, message),
+                None => AudioThreadPriorityError::new(&format!("?:{message}")),
+            }
+        }
+    }
+
+    impl From<Argument> for MessageItem {
+        fn from(argument: Argument) -> Self {
+            match argument {
+                Argument::U32(value) => value.into(),
+                Argument::U64(value) => value.into(),
+            }
+        }
+    }
+
+    extern "C" {
+        fn dbus_threads_init_default() -> u32;
+    }
+
+    /// A private connection to a bus, through `libdbus-1`.
+    pub struct BusConnection {
+        connection: Connection,
+        timeout_ms: i32,
+    }
+
+    // `dbus::Connection` is not `Send`, but moving it to another thread is sound here: `open` makes
+    // libdbus thread-safe before creating the connection, and the connection is only ever used by
+    // one thread at a time, as it is taken out of the service state for each call, and
+    // `BusConnection` is not `Sync`. The callbacks the `dbus` crate registers on it only run on
+    // the thread making a call.
+    unsafe impl Send for BusConnection {}
+
+    impl BusConnection {
+        pub fn open(
+            bus: Bus,
+            timeout: Duration,
+        ) -> Result<BusConnection, AudioThreadPriorityError> {
+            // libdbus only locks its global state and its connections once its threads are
+            // initialized. The `dbus` crate does it when connecting, this makes sure of it.
+            if unsafe { dbus_threads_init_default() } == 0 {
+                return Err(AudioThreadPriorityError::new(
+                    "could not initialize the threads of libdbus",
+                ));
+            }
+            let bus = match bus {
+                Bus::System => BusType::System,
+                Bus::Session => BusType::Session,
+            };
+            Ok(BusConnection {
+                connection: Connection::get_private(bus)?,
+                timeout_ms: i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX),
+            })
+        }
+
+        pub fn is_connected(&self) -> bool {
+            self.connection.is_connected()
+        }
+
+        /// Close a connection inherited from the parent process, without sending anything on it.
+        /// Closing it through libdbus could flush messages, or wait for a lock held by a thread of
+        /// the parent: only its socket is closed, and the rest of it is left alone.
+        pub fn close_inherited(self) {
+            let mut fds: Vec<_> = self
+                .connection
+                .watch_fds()
+                .iter()
+                .map(|watch| watch.fd())
+                .collect();
+            fds.sort_unstable();
+            fds.dedup();
+            for fd in fds {
+                unsafe { libc::close(fd) };
+            }
+            // Freeing the connection takes the global locks of libdbus, which another thread of the
+            // parent may have held while forking, and which would then never be released in the
+            // child. Leaking it is sound, as it is never used again: only its memory is lost, once
+            // per fork.
+            std::mem::forget(self);
+        }
+
+        pub fn call(
+            &self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            method: &str,
+            arguments: &[Argument],
+        ) -> Result<(), AudioThreadPriorityError> {
+            let mut m = Message::new_method_call(destination, path, interface, method)
+                .map_err(|e| AudioThreadPriorityError::new(&e))?;
+            let items: Vec<MessageItem> = arguments.iter().map(|&a| a.into()).collect();
+            m.append_items(&items);
+            self.connection
+                .send_with_reply_and_block(m, self.timeout_ms)?;
+            Ok(())
+        }
+
+        pub fn get_i64_property(
+            &self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            name: &str,
+        ) -> Result<i64, AudioThreadPriorityError> {
+            let p = Props::new(
+                &self.connection,
+                destination,
+                path,
+                interface,
+                self.timeout_ms,
+            );
+            match p.get(name)? {
+                MessageItem::Int32(i) => Ok(i as i64),
+                MessageItem::Int64(i) => Ok(i),
+                i => Err(AudioThreadPriorityError::new(&format!(
+                    "Property is not integer ({i:?})"
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+mod rust_dbus {
+    use super::{Argument, Bus};
+    use crate::{AudioThreadPriorityError, ErrorKind};
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::time::Duration;
+    use zbus::blocking::{connection, Connection};
+    use zbus::zvariant::{OwnedValue, StructureBuilder, Value};
+
+    /// The process that made the first connection, or 0. zbus does its I/O on threads of its own,
+    /// which a forked child does not have: it would wait for them forever.
+    static CONNECTED_PID: AtomicI32 = AtomicI32::new(0);
+
+    impl From<zbus::Error> for AudioThreadPriorityError {
+        fn from(error: zbus::Error) -> Self {
+            match error {
+                zbus::Error::MethodError(name, message, _) => AudioThreadPriorityError::from_dbus(
+                    name.as_str(),
+                    message.as_deref().unwrap_or("?"),
+                ),
+                zbus::Error::InputOutput(io_error) => AudioThreadPriorityError::from_os_error(
+                    &format!("D-Bus: {io_error}"),
+                    &io_error,
+                ),
+                error => AudioThreadPriorityError::new(&format!("D-Bus: {error}")),
+            }
+        }
+    }
+
+    /// A private connection to a bus, through `zbus`.
+    ///
+    /// It cannot be used in a child forked from a process that connected to a bus, where
+    /// `open` fails with `ErrorKind::ServiceUnavailable`.
+    pub struct BusConnection {
+        connection: Connection,
+    }
+
+    impl BusConnection {
+        /// Connect to `bus`. Method calls fail with `ErrorKind::Timeout` once they have waited
+        /// `timeout` for their reply.
+        pub fn open(
+            bus: Bus,
+            timeout: Duration,
+        ) -> Result<BusConnection, AudioThreadPriorityError> {
+            let pid = unsafe { libc::getpid() };
+            match CONNECTED_PID.compare_exchange(0, pid, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {}
+                Err(connected) if connected == pid => {}
+                Err(_) => {
+                    return Err(AudioThreadPriorityError::with_kind(
+                        ErrorKind::ServiceUnavailable,
+                        "zbus cannot be used in a child forked from a process that used it",
+                    ))
+                }
+            }
+            let builder = match bus {
+                Bus::System => connection::Builder::system()?,
+                Bus::Session => connection::Builder::session()?,
+            };
+            Ok(BusConnection {
+                connection: builder.method_timeout(timeout).build()?,
+            })
+        }
+
+        pub fn is_connected(&self) -> bool {
+            !self.connection.is_closed()
+        }
+
+        /// Leave a connection inherited from the parent process alone: closing or dropping it
+        /// would wait for the threads of zbus, which the child does not have. The socket is
+        /// closed when the child execs or exits, and is never used meanwhile.
+        pub fn close_inherited(self) {
+            std::mem::forget(self);
+        }
+
+        pub fn call(
+            &self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            method: &str,
+            arguments: &[Argument],
+        ) -> Result<(), AudioThreadPriorityError> {
+            let body = arguments
+                .iter()
+                .fold(StructureBuilder::new(), |body, &argument| match argument {
+                    Argument::U32(value) => body.add_field(value),
+                    Argument::U64(value) => body.add_field(value),
+                })
+                .build()
+                .map_err(zbus::Error::from)?;
+            self.connection
+                .call_method(Some(destination), path, Some(interface), method, &body)?;
+            Ok(())
+        }
+
+        pub fn get_i64_property(
+            &self,
+            destination: &str,
+            path: &str,
+            interface: &str,
+            name: &str,
+        ) -> Result<i64, AudioThreadPriorityError> {
+            let reply = self.connection.call_method(
+                Some(destination),
+                path,
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &(interface, name),
+            )?;
+            let value: OwnedValue = reply.body().deserialize()?;
+            match *value {
+                Value::I32(i) => Ok(i as i64),
+                Value::I64(i) => Ok(i),
+                ref i => Err(AudioThreadPriorityError::new(&format!(
+                    "Property is not integer ({i:?})"
+                ))),
+            }
+        }
+    }
+}
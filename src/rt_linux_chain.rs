@@ -0,0 +1,438 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The Linux promotion backends, tried in turn until one succeeds.
+//!
+//! Which backends work is only known at run time: rtkit may not be installed, the process may run
+//! in a sandbox, or hold `CAP_SYS_NICE`. Rather than choosing one at build time, promotion walks a
+//! list of `LinuxBackend`s, set for the process with [`set_linux_backends`] or for a single
+//! promotion with `PromotionOptions::backends`. If they all fail, the error lists why each one did.
+//!
+//! Each backend implements `PriorityBackend`. Besides the built-in ones, the application can
+//! register its own with [`register_priority_backend`], and refer to it as `LinuxBackend::Custom`.
+
+extern crate libc;
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use log::info;
+
+#[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+use crate::rt_linux::RealtimeService;
+use crate::rt_linux_native::{kernel_pid_t, NativeBackend, RtPriorityThreadInfoInternal};
+use crate::rt_linux_nice::NiceBackend;
+use crate::{
+    AudioThreadPriorityError, BackendPromotion, ErrorKind, LinuxBackend, PriorityBackend,
+    PromotionOptions,
+};
+
+pub use crate::rt_linux_native::get_current_thread_info_internal;
+
+/// The backends tried when none are set: the D-Bus services first, because they work for
+/// unprivileged processes, then the native backend. The nice level boost is not real-time
+/// scheduling, so it is only used if asked for.
+#[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+const DEFAULT_BACKENDS: &[LinuxBackend] = &[
+    LinuxBackend::Rtkit,
+    LinuxBackend::Portal,
+    LinuxBackend::Native,
+];
+#[cfg(not(any(feature = "dbus", feature = "with_rust_dbus")))]
+const DEFAULT_BACKENDS: &[LinuxBackend] = &[LinuxBackend::Native];
+
+/// The backends set via [`set_linux_backends`], or `None` to use `DEFAULT_BACKENDS`.
+static BACKENDS: Mutex<Option<Vec<LinuxBackend>>> = Mutex::new(None);
+
+/// Set the backends to try, in order, when promoting a thread on Linux. Pass `None` to restore the
+/// default: rtkit, then the desktop portal, then the native backend, or only the native backend
+/// when built without the `dbus` and `with_rust_dbus` features.
+///
+/// `LinuxBackend::Nice` is never in the default: it is not real-time scheduling, so it is only
+/// tried if it is in `backends`, typically last.
+///
+/// This is process-wide: to choose the backends of a single promotion, use
+/// `PromotionOptions::backends` instead, which takes precedence.
+pub fn set_linux_backends(backends: Option<&[LinuxBackend]>) {
+    *BACKENDS.lock().unwrap_or_else(PoisonError::into_inner) = backends.map(<[_]>::to_vec);
+}
+
+/// The backends to try for a promotion with `options`.
+fn backends(options: &PromotionOptions) -> Vec<LinuxBackend> {
+    if let Some(backends) = &options.backends {
+        return backends.clone();
+    }
+    BACKENDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| DEFAULT_BACKENDS.to_vec())
+}
+
+/// A thread promoted by this process, with the backend that promoted it, so that
+/// `demote_thread_from_real_time_internal` demotes it with the same backend.
+struct Promoted {
+    pid: libc::pid_t,
+    tid: kernel_pid_t,
+    /// When the thread started, to tell it from a later thread that reused its tid.
+    start_time: u64,
+    backend: LinuxBackend,
+}
+
+/// The threads promoted by this process and not demoted since. Their handles may have been dropped
+/// or leaked: threads that have exited are pruned whenever the list is used, so that it does not
+/// grow with thread churn, and a record is never trusted for a later thread with the same tid.
+static PROMOTED: Mutex<Vec<Promoted>> = Mutex::new(Vec::new());
+
+/// When the thread `tid` of the process `pid` started, in clock ticks since boot, or `None` if it
+/// does not exist.
+fn thread_start_time(pid: libc::pid_t, tid: kernel_pid_t) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/stat")).ok()?;
+    // The name of the thread, in parentheses, can contain spaces and parentheses: the fields are
+    // counted from the last one. `starttime` is the 22nd field, the 20th after the name.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The threads promoted by this process that still run, after pruning the others.
+fn promoted_threads() -> MutexGuard<'static, Vec<Promoted>> {
+    let mut promoted = PROMOTED.lock().unwrap_or_else(PoisonError::into_inner);
+    promoted.retain(|thread| thread_start_time(thread.pid, thread.tid) == Some(thread.start_time));
+    promoted
+}
+
+/// Record that `backend` promoted the thread of `thread_info`.
+fn record_promotion(thread_info: &RtPriorityThreadInfoInternal, backend: LinuxBackend) {
+    let (pid, tid) = (thread_info.pid, thread_info.thread_id);
+    let mut promoted = promoted_threads();
+    promoted.retain(|thread| (thread.pid, thread.tid) != (pid, tid));
+    // A thread that already exited cannot be demoted: there is nothing to record.
+    if let Some(start_time) = thread_start_time(pid, tid) {
+        promoted.push(Promoted {
+            pid,
+            tid,
+            start_time,
+            backend,
+        });
+    }
+}
+
+/// The backend that promoted the thread of `thread_info`, if this process did.
+fn promoted_with(thread_info: &RtPriorityThreadInfoInternal) -> Option<LinuxBackend> {
+    promoted_threads()
+        .iter()
+        .find(|thread| (thread.pid, thread.tid) == (thread_info.pid, thread_info.thread_id))
+        .map(|thread| thread.backend)
+}
+
+/// Forget the promotion of the thread of `thread_info`, once it is demoted.
+fn forget_promotion(thread_info: &RtPriorityThreadInfoInternal) {
+    promoted_threads()
+        .retain(|thread| (thread.pid, thread.tid) != (thread_info.pid, thread_info.thread_id));
+}
+
+/// The backends registered with [`register_priority_backend`].
+static REGISTRY: Mutex<Vec<Arc<dyn PriorityBackend>>> = Mutex::new(Vec::new());
+
+/// Register a backend implemented by the application, to be tried when `LinuxBackend::Custom` with
+/// its name is in the backends set via `set_linux_backends` or `PromotionOptions::backends`. This
+/// replaces a backend registered earlier under the same name.
+pub fn register_priority_backend(backend: Arc<dyn PriorityBackend>) {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    registry.retain(|registered| registered.name() != backend.name());
+    registry.push(backend);
+}
+
+/// The backend called `name`: a built-in backend, by the name it is displayed with, or a backend
+/// registered with [`register_priority_backend`].
+pub(crate) fn backend_named(name: &str) -> Option<LinuxBackend> {
+    let builtin = [
+        LinuxBackend::Rtkit,
+        LinuxBackend::Portal,
+        LinuxBackend::Native,
+        LinuxBackend::Nice,
+    ];
+    builtin
+        .iter()
+        .copied()
+        .find(|backend| backend.to_string() == name)
+        .or_else(|| {
+            REGISTRY
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .find(|registered| registered.name() == name)
+                .map(|registered| LinuxBackend::Custom(registered.name()))
+        })
+}
+
+/// The implementation of `backend`.
+fn resolve(backend: LinuxBackend) -> Result<Arc<dyn PriorityBackend>, AudioThreadPriorityError> {
+    match backend {
+        #[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+        LinuxBackend::Rtkit => Ok(Arc::new(RealtimeService::Rtkit)),
+        #[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+        LinuxBackend::Portal => Ok(Arc::new(RealtimeService::Portal)),
+        #[cfg(not(any(feature = "dbus", feature = "with_rust_dbus")))]
+        LinuxBackend::Rtkit | LinuxBackend::Portal => Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::Unsupported,
+            "built without D-Bus support",
+        )),
+        LinuxBackend::Native => Ok(Arc::new(NativeBackend)),
+        LinuxBackend::Nice => Ok(Arc::new(NiceBackend)),
+        LinuxBackend::Custom(name) => REGISTRY
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|registered| registered.name() == name)
+            .cloned()
+            .ok_or_else(|| {
+                AudioThreadPriorityError::with_kind(
+                    ErrorKind::InvalidArgument,
+                    "no backend is registered with this name",
+                )
+            }),
+    }
+}
+
+/// The error of a promotion that no backend could perform, listing why each one failed. Its kind,
+/// error code and D-Bus error name are those of the first backend that was available and refused,
+/// rather than unavailable or unable to do what was asked, if any.
+fn chain_error(
+    what: &str,
+    failures: Vec<(LinuxBackend, AudioThreadPriorityError)>,
+) -> AudioThreadPriorityError {
+    if failures.is_empty() {
+        return AudioThreadPriorityError::with_kind(
+            ErrorKind::Unsupported,
+            &format!("no backend could {what}: no backend is configured"),
+        );
+    }
+    let cause = failures
+        .iter()
+        .map(|(_, error)| error)
+        .find(|error| {
+            !matches!(
+                error.kind,
+                ErrorKind::ServiceUnavailable | ErrorKind::Unsupported
+            )
+        })
+        .unwrap_or(&failures[0].1);
+    let (kind, raw_os_error, dbus_error_name) = (
+        cause.kind,
+        cause.raw_os_error,
+        cause.dbus_error_name.clone(),
+    );
+    let reasons: Vec<String> = failures
+        .iter()
+        .map(|(backend, error)| match &error.inner {
+            Some(inner) => format!("{}: {} ({})", backend, error.message, inner),
+            None => format!("{}: {}", backend, error.message),
+        })
+        .collect();
+    AudioThreadPriorityError {
+        raw_os_error,
+        dbus_error_name,
+        ..AudioThreadPriorityError::with_kind(
+            kind,
+            &format!("no backend could {} ({})", what, reasons.join("; ")),
+        )
+    }
+}
+
+pub struct RtPriorityHandleInternal {
+    thread_info: RtPriorityThreadInfoInternal,
+    backend: LinuxBackend,
+    implementation: Arc<dyn PriorityBackend>,
+    promotion: BackendPromotion,
+}
+
+impl RtPriorityHandleInternal {
+    /// The backend that promoted the thread.
+    pub fn backend(&self) -> LinuxBackend {
+        self.backend
+    }
+
+    /// The real-time priority the thread was granted, which can be lower than the one requested.
+    /// `None` for `SCHED_DEADLINE` and for the nice level boost, which have no priority.
+    pub fn granted_priority(&self) -> Option<u32> {
+        self.promotion.granted_priority()
+    }
+}
+
+/// Call `f` with each backend in `backends` in turn, until it succeeds. Returns the backend it
+/// succeeded with and its result, or an error listing why each backend failed, with `what` the
+/// operation that was attempted.
+fn first_success<T>(
+    backends: Vec<LinuxBackend>,
+    what: &str,
+    mut f: impl FnMut(&dyn PriorityBackend) -> Result<T, AudioThreadPriorityError>,
+) -> Result<(LinuxBackend, Arc<dyn PriorityBackend>, T), AudioThreadPriorityError> {
+    let mut failures = Vec::new();
+    for backend in backends {
+        match resolve(backend)
+            .and_then(|implementation| f(&*implementation).map(|result| (implementation, result)))
+        {
+            Ok((implementation, result)) => return Ok((backend, implementation, result)),
+            Err(e) => {
+                info!("Could not {what} with the {backend} backend: {e}");
+                failures.push((backend, e));
+            }
+        }
+    }
+    Err(chain_error(what, failures))
+}
+
+/// Promote the calling thread to real-time priority, with the first backend that succeeds.
+pub fn promote_current_thread_to_real_time_internal(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    let thread_info = get_current_thread_info_internal()?;
+    let (backend, implementation, promotion) =
+        first_success(backends(options), "promote the thread", |implementation| {
+            implementation.promote_current_thread(
+                thread_info,
+                audio_buffer_frames,
+                audio_samplerate_hz,
+                options,
+            )
+        })?;
+    record_promotion(&thread_info, backend);
+    Ok(RtPriorityHandleInternal {
+        thread_info,
+        backend,
+        implementation,
+        promotion,
+    })
+}
+
+/// Promote a thread (possibly in another process) identified by its tid, with the first backend
+/// that succeeds.
+pub fn promote_thread_to_real_time_internal(
+    thread_info: RtPriorityThreadInfoInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    thread_info.check()?;
+    let (backend, implementation, promotion) =
+        first_success(backends(options), "promote the thread", |implementation| {
+            implementation.promote_thread(
+                thread_info,
+                audio_buffer_frames,
+                audio_samplerate_hz,
+                options,
+            )
+        })?;
+    record_promotion(&thread_info, backend);
+    Ok(RtPriorityHandleInternal {
+        thread_info,
+        backend,
+        implementation,
+        promotion,
+    })
+}
+
+/// Undo a promotion, with the backend that performed it. The built-in backends identify the
+/// promoted thread by its tid, so this can be called from any thread of the process.
+pub fn demote_current_thread_from_real_time_internal(
+    rt_priority_handle: RtPriorityHandleInternal,
+) -> Result<(), AudioThreadPriorityError> {
+    rt_priority_handle
+        .implementation
+        .demote_current_thread(rt_priority_handle.promotion)?;
+    // Only once it is demoted: a retry with `demote_thread_from_real_time_internal` after a failure
+    // tries the backend that promoted the thread first.
+    forget_promotion(&rt_priority_handle.thread_info);
+    Ok(())
+}
+
+/// Adapt a promotion to a new buffer size or sample rate, with the backend that performed it.
+pub fn update_real_time_parameters_internal(
+    rt_priority_handle: &mut RtPriorityHandleInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    rt_priority_handle.implementation.update_parameters(
+        &mut rt_priority_handle.promotion,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+    )
+}
+
+/// Restore a thread (possibly in another process) identified by its tid. If this process promoted
+/// it, the backend that did is tried first, then the others, in order, until one succeeds: a thread
+/// promoted by another process is demoted by the first backend that succeeds, which may not be the
+/// one that promoted it.
+pub fn demote_thread_from_real_time_internal(
+    thread_info: RtPriorityThreadInfoInternal,
+) -> Result<(), AudioThreadPriorityError> {
+    thread_info.check()?;
+    let mut backends = backends(&PromotionOptions::default());
+    if let Some(promoted_with) = promoted_with(&thread_info) {
+        backends.retain(|&backend| backend != promoted_with);
+        backends.insert(0, promoted_with);
+    }
+    first_success(backends, "demote the thread", |implementation| {
+        implementation.demote_thread(thread_info)
+    })?;
+    forget_promotion(&thread_info);
+    Ok(())
+}
+
+/// Prepare the calling process to be promoted from another process, with the first backend that
+/// succeeds. The D-Bus backends need this: rtkit refuses to promote a process without an
+/// `RLIMIT_RTTIME` limit, which is set here without contacting rtkit, within its default maximum
+/// unless it was read already. The native backend sets the budget of
+/// the process, which a promoting process without privilege cannot do itself. With the `nice`
+/// backend, there is nothing to do.
+pub fn set_real_time_hard_limit_internal(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    first_success(
+        backends(&PromotionOptions::default()),
+        "set the real-time limit",
+        |implementation| {
+            implementation.set_real_time_limit(audio_buffer_frames, audio_samplerate_hz)
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        forget_promotion, get_current_thread_info_internal, promoted_with, record_promotion,
+    };
+    use crate::LinuxBackend;
+    use std::time::{Duration, Instant};
+
+    // The record of a thread that exited is pruned, rather than kept forever and trusted for a later
+    // thread with the same tid.
+    #[test]
+    fn test_exited_threads_pruned() {
+        let exited = std::thread::spawn(|| {
+            let thread_info = get_current_thread_info_internal().unwrap();
+            record_promotion(&thread_info, LinuxBackend::Nice);
+            assert_eq!(promoted_with(&thread_info), Some(LinuxBackend::Nice));
+            thread_info
+        })
+        .join()
+        .unwrap();
+        // The thread is joined once it stopped running, which can be before its /proc entry goes.
+        let task = format!("/proc/self/task/{}", exited.thread_id);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::path::Path::new(&task).exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(promoted_with(&exited), None);
+
+        let current = get_current_thread_info_internal().unwrap();
+        record_promotion(&current, LinuxBackend::Nice);
+        forget_promotion(&current);
+        assert_eq!(promoted_with(&current), None);
+    }
+}
@@ -0,0 +1,240 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! `SCHED_DEADLINE` promotion, set by the native backend.
+//!
+//! Instead of a fixed real-time priority, the thread gets a CPU reservation derived from the audio
+//! callback: `runtime` nanoseconds of CPU time in every `period`, to be used before `deadline`. The
+//! kernel's admission control refuses the reservation when the bandwidth already reserved on the
+//! system leaves no room for it. Setting `SCHED_DEADLINE` always needs `CAP_SYS_NICE`: an
+//! `RLIMIT_RTPRIO` limit does not grant it, and rtkit cannot request it on the caller's behalf.
+
+extern crate libc;
+
+use std::io::Error as OSError;
+use std::time::Duration;
+
+use crate::AudioThreadPriorityError;
+
+/// Not exposed by all libc versions.
+const SCHED_DEADLINE: u32 = 6;
+/// The `SCHED_RESET_ON_FORK` equivalent for `sched_setattr`. A `SCHED_DEADLINE` thread cannot fork
+/// at all unless this is set.
+const SCHED_FLAG_RESET_ON_FORK: u64 = 0x01;
+/// The kernel refuses periods shorter than `sched_deadline_period_min_us`, 100us by default.
+const MIN_PERIOD_NS: u64 = 100_000;
+
+/// The `struct sched_attr` of `sched_setattr(2)`, in its original (`SCHED_ATTR_SIZE_VER0`) layout.
+/// Declared here rather than taken from libc, which only has it in recent versions.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+#[cfg(test)]
+impl SchedAttr {
+    /// The scheduling policy, without flags.
+    pub fn policy(&self) -> u32 {
+        self.sched_policy
+    }
+
+    /// The period of a `SCHED_DEADLINE` reservation, in nanoseconds.
+    pub fn period(&self) -> u64 {
+        self.sched_period
+    }
+}
+
+/// The `SCHED_DEADLINE` reservation for an audio callback, as `(runtime, deadline, period)` in
+/// nanoseconds.
+///
+/// Like the macOS time constraint policy, the period and the deadline are the callback duration, and
+/// the runtime (the computation budget) is half of it, unless a `budget` is given.
+fn deadline_parameters(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    budget: Option<Duration>,
+) -> (u64, u64, u64) {
+    let buffer_frames = if audio_buffer_frames > 0 {
+        audio_buffer_frames
+    } else {
+        // 50ms slice, as for the other backends.
+        audio_samplerate_hz / 20
+    };
+    let period = buffer_frames as u64 * 1_000_000_000 / audio_samplerate_hz as u64;
+    let period = period.max(MIN_PERIOD_NS);
+    let runtime = match budget {
+        Some(budget) => (budget.as_nanos().min(period as u128)) as u64,
+        None => period / 2,
+    };
+    (runtime, period, period)
+}
+
+fn sched_getattr(tid: libc::pid_t) -> Result<SchedAttr, AudioThreadPriorityError> {
+    let mut attr = SchedAttr::default();
+    let rv = unsafe {
+        libc::syscall(
+            libc::SYS_sched_getattr,
+            tid,
+            &mut attr as *mut SchedAttr,
+            std::mem::size_of::<SchedAttr>() as libc::c_uint,
+            0 as libc::c_uint,
+        )
+    };
+    if rv < 0 {
+        let error = OSError::last_os_error();
+        return Err(AudioThreadPriorityError::from_os_error(
+            &format!("sched_getattr: {error}"),
+            &error,
+        ));
+    }
+    Ok(attr)
+}
+
+fn sched_setattr(tid: libc::pid_t, attr: &SchedAttr) -> Result<(), OSError> {
+    let rv = unsafe {
+        libc::syscall(
+            libc::SYS_sched_setattr,
+            tid,
+            attr as *const SchedAttr,
+            0 as libc::c_uint,
+        )
+    };
+    if rv < 0 {
+        return Err(OSError::last_os_error());
+    }
+    Ok(())
+}
+
+/// Move the thread `tid` to `SCHED_DEADLINE`, with a reservation derived from the buffer duration.
+/// `budget` overrides the runtime reserved each period.
+///
+/// Returns the scheduling attributes in place before promotion, to be passed to [`restore`] on
+/// demotion.
+pub fn promote(
+    tid: libc::pid_t,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    budget: Option<Duration>,
+) -> Result<SchedAttr, AudioThreadPriorityError> {
+    let saved = sched_getattr(tid)?;
+    update(tid, audio_buffer_frames, audio_samplerate_hz, budget)?;
+    Ok(saved)
+}
+
+/// Set the `SCHED_DEADLINE` reservation of the thread `tid` for this buffer duration, also to
+/// change it once the thread is promoted. The kernel checks the reservation with admission
+/// control, and leaves the thread alone if it does not fit.
+pub fn update(
+    tid: libc::pid_t,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    budget: Option<Duration>,
+) -> Result<(), AudioThreadPriorityError> {
+    let (runtime, deadline, period) =
+        deadline_parameters(audio_buffer_frames, audio_samplerate_hz, budget);
+    let attr = SchedAttr {
+        size: std::mem::size_of::<SchedAttr>() as u32,
+        sched_policy: SCHED_DEADLINE,
+        sched_flags: SCHED_FLAG_RESET_ON_FORK,
+        sched_runtime: runtime,
+        sched_deadline: deadline,
+        sched_period: period,
+        ..Default::default()
+    };
+
+    match sched_setattr(tid, &attr) {
+        Ok(()) => {
+            log::info!(
+                "thread {tid} promoted to SCHED_DEADLINE (runtime {runtime}ns, period {period}ns)."
+            );
+            Ok(())
+        }
+        // EBUSY is how admission control reports that the reservation does not fit.
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+            Err(AudioThreadPriorityError::from_os_error(
+                &format!(
+                    "SCHED_DEADLINE admission control rejected a reservation of {runtime}ns every \
+                     {period}ns: not enough CPU bandwidth left ({e})"
+                ),
+                &e,
+            ))
+        }
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+            Err(AudioThreadPriorityError::from_os_error(
+                &format!(
+                    "could not promote thread to SCHED_DEADLINE, CAP_SYS_NICE is required ({e})"
+                ),
+                &e,
+            ))
+        }
+        Err(e) => Err(AudioThreadPriorityError::from_os_error(
+            &format!("could not promote thread to SCHED_DEADLINE: {e}"),
+            &e,
+        )),
+    }
+}
+
+/// Restore the scheduling attributes `saved` by [`promote`] on the thread `tid`.
+pub fn restore(tid: libc::pid_t, saved: &SchedAttr) -> Result<(), AudioThreadPriorityError> {
+    // Keep the reset-on-fork flag set, like the other demotion paths: an unprivileged thread cannot
+    // clear it.
+    let attr = SchedAttr {
+        size: std::mem::size_of::<SchedAttr>() as u32,
+        sched_flags: saved.sched_flags | SCHED_FLAG_RESET_ON_FORK,
+        ..*saved
+    };
+    sched_setattr(tid, &attr).map_err(|e| {
+        AudioThreadPriorityError::from_os_error(
+            &format!("could not demote thread from SCHED_DEADLINE: {e}"),
+            &e,
+        )
+    })
+}
+
+/// The current scheduling attributes of the thread `tid`.
+#[cfg(test)]
+pub fn current_attributes(tid: libc::pid_t) -> Result<SchedAttr, AudioThreadPriorityError> {
+    sched_getattr(tid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline_parameters() {
+        // 512 frames at 48kHz: a 10.67ms period, half of it as runtime.
+        assert_eq!(
+            deadline_parameters(512, 48000, None),
+            (5_333_333, 10_666_666, 10_666_666)
+        );
+        // No buffer size: a 50ms slice.
+        assert_eq!(
+            deadline_parameters(0, 44100, None),
+            (25_000_000, 50_000_000, 50_000_000)
+        );
+        // Tiny buffers are raised to the kernel's minimum period.
+        assert_eq!(
+            deadline_parameters(1, 192000, None),
+            (MIN_PERIOD_NS / 2, MIN_PERIOD_NS, MIN_PERIOD_NS)
+        );
+        // A budget overrides the runtime, but cannot exceed the period.
+        assert_eq!(
+            deadline_parameters(512, 48000, Some(Duration::from_millis(2))),
+            (2_000_000, 10_666_666, 10_666_666)
+        );
+        assert_eq!(
+            deadline_parameters(512, 48000, Some(Duration::from_secs(1))),
+            (10_666_666, 10_666_666, 10_666_666)
+        );
+    }
+}
@@ -0,0 +1,457 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A stand-in for rtkit, or for the `Realtime` interface of the desktop portal, on a private
+//! `dbus-daemon`, to exercise the D-Bus backends without a desktop session.
+//!
+//! Like rtkit, [`FakeRtkit`] answers `MakeThreadRealtime` and `MakeThreadRealtimeWithPID`, exposes
+//! the `MaxRealtimePriority`, `MinNiceLevel` and `RTTimeUSecMax` properties, refuses priorities
+//! above its maximum, and refuses threads of processes without an `RLIMIT_RTTIME` limit. Unless
+//! asked to, it does not change the scheduling of the threads, so it needs no privilege.
+
+extern crate libc;
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+
+use zbus::blocking::{connection, Connection, MessageIterator};
+use zbus::message::{Header, Type};
+use zbus::zvariant::Value;
+use zbus::Message;
+
+use crate::AudioThreadPriorityError;
+
+/// Prevents threads forked from a real-time thread from inheriting real-time scheduling, as rtkit
+/// always sets it.
+const SCHED_RESET_ON_FORK: libc::c_int = 0x4000_0000;
+
+const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+const FAILED: &str = "org.freedesktop.DBus.Error.Failed";
+const INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+
+/// A private `dbus-daemon`, stopped when dropped.
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    /// Start a `dbus-daemon` listening on a socket in `/tmp`. This fails if `dbus-daemon` is not
+    /// installed.
+    pub fn start() -> Result<PrivateBus, AudioThreadPriorityError> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args([
+                "--session",
+                "--nofork",
+                "--print-address",
+                "--address=unix:tmpdir=/tmp",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| {
+                AudioThreadPriorityError::new(&format!("could not start dbus-daemon: {e}"))
+            })?;
+        let mut address = String::new();
+        let read = BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address);
+        if read.is_err() || address.trim().is_empty() {
+            let _ = daemon.kill();
+            let _ = daemon.wait();
+            return Err(AudioThreadPriorityError::new(
+                "dbus-daemon did not print its address",
+            ));
+        }
+        Ok(PrivateBus {
+            daemon,
+            address: address.trim().into(),
+        })
+    }
+
+    /// The address of the bus, to connect to it as the system bus through
+    /// `DBUS_SYSTEM_BUS_ADDRESS`, or as the session bus through `DBUS_SESSION_BUS_ADDRESS`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// A request to make a thread real-time, received by a [`FakeRtkit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtkitRequest {
+    /// The process of the thread, for `MakeThreadRealtimeWithPID`. `None` for
+    /// `MakeThreadRealtime`, which is about a thread of the caller.
+    pub pid: Option<u64>,
+    /// The system-wide id of the thread.
+    pub thread: u64,
+    /// The requested real-time priority.
+    pub priority: u32,
+    /// Whether the request was granted.
+    pub granted: bool,
+}
+
+/// How a [`FakeRtkit`] refuses every request, set with [`FakeRtkit::set_refusal`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RtkitRefusal {
+    /// Answer with `org.freedesktop.DBus.Error.AccessDenied`, as rtkit does when the caller is not
+    /// allowed real-time scheduling, or has exhausted its quota.
+    AccessDenied,
+    /// Answer with the D-Bus error `name` and `message`.
+    Error {
+        /// The D-Bus error name.
+        name: String,
+        /// The error message.
+        message: String,
+    },
+    /// Do not answer, so that the caller times out.
+    NoReply,
+}
+
+/// The D-Bus name, object path and interface of the service.
+#[derive(Clone, Copy)]
+struct Service {
+    name: &'static str,
+    path: &'static str,
+    interface: &'static str,
+    /// Whether the service has `MakeThreadRealtime`, which the portal does not.
+    without_pid: bool,
+}
+
+const RTKIT: Service = Service {
+    name: "org.freedesktop.RealtimeKit1",
+    path: "/org/freedesktop/RealtimeKit1",
+    interface: "org.freedesktop.RealtimeKit1",
+    without_pid: true,
+};
+
+const PORTAL: Service = Service {
+    name: "org.freedesktop.portal.Desktop",
+    path: "/org/freedesktop/portal/desktop",
+    interface: "org.freedesktop.portal.Realtime",
+    without_pid: false,
+};
+
+struct State {
+    max_realtime_priority: i32,
+    min_nice_level: i32,
+    rttime_usec_max: i64,
+    refusal: Option<RtkitRefusal>,
+    check_rttime: bool,
+    apply: bool,
+    requests: Vec<RtkitRequest>,
+}
+
+/// A stand-in rtkit service on a bus, answering requests on a thread of its own until dropped.
+///
+/// It starts with the defaults of the rtkit package: a `MaxRealtimePriority` of 20, a
+/// `MinNiceLevel` of -15 and an `RTTimeUSecMax` of 200ms.
+///
+/// The bus address is read from the environment by the promotions, so it has to be set while the
+/// process has a single thread, for example at the start of `main`, or in a child forked for the
+/// test. The stand-in runs on `zbus`, which does not survive `fork`: a process forked after it was
+/// started cannot use the `with_rust_dbus` backends.
+///
+/// ```no_run
+/// use audio_thread_priority::testing::{FakeRtkit, PrivateBus};
+///
+/// // While the process has a single thread, before the first promotion:
+/// let bus = PrivateBus::start().unwrap();
+/// std::env::set_var("DBUS_SYSTEM_BUS_ADDRESS", bus.address());
+/// let rtkit = FakeRtkit::start(bus.address()).unwrap();
+///
+/// audio_thread_priority::promote_current_thread_to_real_time(512, 44100).unwrap();
+/// assert_eq!(rtkit.requests().len(), 1);
+/// ```
+pub struct FakeRtkit {
+    state: Arc<Mutex<State>>,
+    connection: Connection,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeRtkit {
+    /// Serve rtkit, `org.freedesktop.RealtimeKit1`, on the bus at `address`.
+    pub fn start(address: &str) -> Result<FakeRtkit, AudioThreadPriorityError> {
+        FakeRtkit::start_service(address, RTKIT)
+    }
+
+    /// Serve the `org.freedesktop.portal.Realtime` interface of the desktop portal on the bus at
+    /// `address`, which is normally the session bus.
+    pub fn start_portal(address: &str) -> Result<FakeRtkit, AudioThreadPriorityError> {
+        FakeRtkit::start_service(address, PORTAL)
+    }
+
+    fn start_service(
+        address: &str,
+        service: Service,
+    ) -> Result<FakeRtkit, AudioThreadPriorityError> {
+        let bus_error = |e: zbus::Error| {
+            AudioThreadPriorityError::new(&format!("could not serve {}: {e}", service.name))
+        };
+        let connection = connection::Builder::address(address)
+            .and_then(|builder| builder.build())
+            .map_err(bus_error)?;
+        // Listen before taking the name, so that no call is missed.
+        let calls = MessageIterator::from(&connection);
+        connection.request_name(service.name).map_err(bus_error)?;
+        let state = Arc::new(Mutex::new(State {
+            max_realtime_priority: 20,
+            min_nice_level: -15,
+            rttime_usec_max: 200_000,
+            refusal: None,
+            check_rttime: true,
+            apply: false,
+            requests: Vec::new(),
+        }));
+        let thread_state = state.clone();
+        let thread_connection = connection.clone();
+        let thread = std::thread::Builder::new()
+            .name("atp_fake_rtkit".into())
+            .spawn(move || serve(&thread_connection, calls, service, &thread_state))
+            .map_err(|e| {
+                AudioThreadPriorityError::new(&format!("could not start the rtkit thread: {e}"))
+            })?;
+        Ok(FakeRtkit {
+            state,
+            connection,
+            thread: Some(thread),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Set the `MaxRealtimePriority` property. Higher priorities are refused.
+    pub fn set_max_realtime_priority(&self, priority: i32) {
+        self.state().max_realtime_priority = priority;
+    }
+
+    /// Set the `MinNiceLevel` property.
+    pub fn set_min_nice_level(&self, nice_level: i32) {
+        self.state().min_nice_level = nice_level;
+    }
+
+    /// Set the `RTTimeUSecMax` property. Threads of processes with a higher `RLIMIT_RTTIME` are
+    /// refused.
+    pub fn set_rttime_usec_max(&self, rttime_usec_max: i64) {
+        self.state().rttime_usec_max = rttime_usec_max;
+    }
+
+    /// Refuse every request as `refusal`, or stop refusing them with `None`.
+    pub fn set_refusal(&self, refusal: Option<RtkitRefusal>) {
+        self.state().refusal = refusal;
+    }
+
+    /// Whether to refuse threads of processes whose `RLIMIT_RTTIME` is unlimited or above
+    /// `RTTimeUSecMax`, as rtkit does. Enabled by default.
+    pub fn set_check_rttime(&self, check_rttime: bool) {
+        self.state().check_rttime = check_rttime;
+    }
+
+    /// Whether to really switch the threads of granted requests to `SCHED_RR`, as rtkit does. This
+    /// needs the privilege to change their scheduling, and is disabled by default.
+    pub fn set_apply_scheduling(&self, apply: bool) {
+        self.state().apply = apply;
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<RtkitRequest> {
+        self.state().requests.clone()
+    }
+}
+
+impl Drop for FakeRtkit {
+    fn drop(&mut self) {
+        // Closing the connection ends the calls the service thread is waiting for.
+        let _ = self.connection.clone().close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The soft `RLIMIT_RTTIME` of the thread `thread`, in microseconds, or `None` if unlimited.
+fn rttime_limit(thread: u64) -> Result<Option<u64>, String> {
+    let limits = std::fs::read_to_string(format!("/proc/{thread}/limits"))
+        .map_err(|e| format!("could not read the limits of thread {thread}: {e}"))?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max realtime timeout"))
+        .ok_or("no RLIMIT_RTTIME in /proc limits")?;
+    match line["Max realtime timeout".len()..]
+        .split_whitespace()
+        .next()
+    {
+        Some("unlimited") => Ok(None),
+        Some(soft) => soft
+            .parse()
+            .map(Some)
+            .map_err(|_| "invalid RLIMIT_RTTIME".to_string()),
+        None => Err("invalid RLIMIT_RTTIME".into()),
+    }
+}
+
+/// Decide on a request to make `thread` real-time at `priority`: `Ok` to grant it, or the D-Bus
+/// error to answer with. `Err(None)` means not to answer at all.
+fn decide(state: &State, thread: u64, priority: u32) -> Result<(), Option<(String, String)>> {
+    let refuse = |name: &str, message: String| Err(Some((name.to_string(), message)));
+    match &state.refusal {
+        Some(RtkitRefusal::AccessDenied) => {
+            return refuse(ACCESS_DENIED, "refused by the stand-in rtkit".into())
+        }
+        Some(RtkitRefusal::Error { name, message }) => return refuse(name, message.clone()),
+        Some(RtkitRefusal::NoReply) => return Err(None),
+        None => {}
+    }
+    if priority == 0 || i64::from(priority) > i64::from(state.max_realtime_priority) {
+        return refuse(
+            ACCESS_DENIED,
+            format!(
+                "priority {priority} above MaxRealtimePriority {}",
+                state.max_realtime_priority
+            ),
+        );
+    }
+    if state.check_rttime {
+        match rttime_limit(thread) {
+            Ok(Some(limit)) if limit as i64 <= state.rttime_usec_max => {}
+            Ok(_) => {
+                return refuse(
+                    ACCESS_DENIED,
+                    "RLIMIT_RTTIME is unlimited or above RTTimeUSecMax".into(),
+                )
+            }
+            Err(message) => return refuse(FAILED, message),
+        }
+    }
+    if state.apply {
+        let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
+        param.sched_priority = priority as libc::c_int;
+        let rc = unsafe {
+            libc::sched_setscheduler(
+                thread as libc::pid_t,
+                libc::SCHED_RR | SCHED_RESET_ON_FORK,
+                &param,
+            )
+        };
+        if rc < 0 {
+            return refuse(
+                FAILED,
+                format!("sched_setscheduler: {}", std::io::Error::last_os_error()),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Answer a `MakeThreadRealtime` or `MakeThreadRealtimeWithPID` call.
+fn make_thread_realtime(
+    connection: &Connection,
+    call: &Message,
+    state: &Mutex<State>,
+    with_pid: bool,
+) -> zbus::Result<()> {
+    let header = call.header();
+    let (pid, thread, priority) = if with_pid {
+        let (pid, thread, priority): (u64, u64, u32) = call.body().deserialize()?;
+        (Some(pid), thread, priority)
+    } else {
+        let (thread, priority): (u64, u32) = call.body().deserialize()?;
+        (None, thread, priority)
+    };
+
+    let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+    let decision = decide(&state, thread, priority);
+    state.requests.push(RtkitRequest {
+        pid,
+        thread,
+        priority,
+        granted: decision.is_ok(),
+    });
+    drop(state);
+
+    match decision {
+        Ok(()) => connection.reply(&header, &()),
+        Err(Some((name, message))) => connection.reply_error(&header, name.as_str(), &message),
+        Err(None) => Ok(()),
+    }
+}
+
+/// Answer a `Properties.Get` call.
+fn get_property(
+    connection: &Connection,
+    call: &Message,
+    state: &Mutex<State>,
+    service: Service,
+) -> zbus::Result<()> {
+    let header = call.header();
+    let (interface, name): (String, String) = call.body().deserialize()?;
+    let state = state.lock().unwrap_or_else(PoisonError::into_inner);
+    let value = match name.as_str() {
+        _ if interface != service.interface => None,
+        "MaxRealtimePriority" => Some(Value::from(state.max_realtime_priority)),
+        "MinNiceLevel" => Some(Value::from(state.min_nice_level)),
+        "RTTimeUSecMax" => Some(Value::from(state.rttime_usec_max)),
+        _ => None,
+    };
+    drop(state);
+    match value {
+        Some(value) => connection.reply(&header, &value),
+        None => connection.reply_error(&header, INVALID_ARGS, &"no such property"),
+    }
+}
+
+/// Answer a call to `service`.
+fn answer(
+    connection: &Connection,
+    call: &Message,
+    header: &Header<'_>,
+    service: Service,
+    state: &Mutex<State>,
+) -> zbus::Result<()> {
+    let interface = header.interface().map(|interface| interface.as_str());
+    let member = header.member().map(|member| member.as_str());
+    match (interface, member) {
+        _ if header.path().map(|path| path.as_str()) != Some(service.path) => {
+            connection.reply_error(header, UNKNOWN_METHOD, &"no such object")
+        }
+        (Some("org.freedesktop.DBus.Properties"), Some("Get")) => {
+            get_property(connection, call, state, service)
+        }
+        (Some(interface), Some("MakeThreadRealtime"))
+            if interface == service.interface && service.without_pid =>
+        {
+            make_thread_realtime(connection, call, state, false)
+        }
+        (Some(interface), Some("MakeThreadRealtimeWithPID")) if interface == service.interface => {
+            make_thread_realtime(connection, call, state, true)
+        }
+        _ => connection.reply_error(header, UNKNOWN_METHOD, &"no such method"),
+    }
+}
+
+/// Answer the calls to `service` until the connection is closed or the bus goes away.
+fn serve(connection: &Connection, calls: MessageIterator, service: Service, state: &Mutex<State>) {
+    for message in calls {
+        let call = match message {
+            Ok(call) => call,
+            Err(_) => break,
+        };
+        let header = call.header();
+        if header.message_type() != Type::MethodCall {
+            continue;
+        }
+        if answer(connection, &call, &header, service, state).is_err() && connection.is_closed() {
+            break;
+        }
+    }
+}
@@ -2,21 +2,33 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this file,
  * You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Native Linux real-time promotion, used when the crate is built without the `dbus` feature.
+//! Native Linux real-time promotion, the `LinuxBackend::Native` backend. It is the only backend
+//! tried by default when the crate is built without D-Bus support.
 //!
 //! Instead of asking rtkit over D-Bus, this promotes the thread directly with
-//! `pthread_setschedparam(SCHED_FIFO)`. It needs no D-Bus and no rtkit daemon, and works whenever
+//! `sched_setscheduler(SCHED_FIFO)`. It needs no D-Bus and no rtkit daemon, and works whenever
 //! the process is allowed to request real-time scheduling: running as root, holding `CAP_SYS_NICE`,
 //! or with an `RLIMIT_RTPRIO` limit configured (e.g. systemd `LimitRTPRIO` or
 //! `/etc/security/limits.conf`). This is the mechanism JACK and PipeWire's direct mode use.
+//!
+//! For a thread of this process, only the hard `RLIMIT_RTPRIO` limit matters: the soft limit is
+//! raised while it is in the way, and a priority above the hard limit is lowered to it.
 
 extern crate libc;
 
 use std::convert::TryFrom;
 use std::io::Error as OSError;
 use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
 
-use crate::AudioThreadPriorityError;
+use crate::limits;
+use crate::rt_linux_deadline::{self, SchedAttr};
+use crate::rt_linux_rttime::RttimeBudgets;
+use crate::{
+    AudioThreadPriorityError, BackendPromotion, ErrorKind, PriorityBackend, PromotionOptions,
+    SchedulingPolicy,
+};
 
 /// Default real-time priority to request, unless overridden with [`set_rt_priority`]. Matches the
 /// value the rtkit path already asks for.
@@ -29,7 +41,9 @@ static RT_PRIORITY: AtomicU8 = AtomicU8::new(0);
 /// 10. Pass `None` to restore the default. Values outside 1-99 are ignored with a warning.
 ///
 /// This is entirely optional: if never called, promotion uses priority 10, the value the rtkit path
-/// requests. It is specific to the Linux build without the `dbus` feature; set it before promoting.
+/// requests. It only applies to the native backend; set it before promoting.
+/// This is process-wide: to request a priority for a single promotion, use
+/// `PromotionOptions::priority` instead, which takes precedence.
 pub fn set_rt_priority(priority: Option<u8>) {
     match priority {
         Some(priority) if (1..=99).contains(&priority) => {
@@ -56,47 +70,95 @@ const SCHED_RESET_ON_FORK: libc::c_int = 0x4000_0000;
 
 // This is different from libc::pid_t, which is 32 bits, and is defined in sys/types.h.
 #[allow(non_camel_case_types)]
-type kernel_pid_t = libc::c_long;
+pub(crate) type kernel_pid_t = libc::c_long;
 
+/// A thread to promote, shared by all the Linux backends.
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct RtPriorityThreadInfoInternal {
     /// System-wide thread id (tid), used to promote a thread by id.
-    thread_id: kernel_pid_t,
-    /// Process-local thread id, used to restore scheduler characteristics.
-    pthread_id: libc::pthread_t,
+    pub(crate) thread_id: kernel_pid_t,
+    /// Process-local thread id. This information is not useful in another process, but tells
+    /// threads apart when back into the first process. Scheduling is always changed by `thread_id`.
+    /// 0 when deserialized in another process than `pid`.
+    pub(crate) pthread_id: libc::pthread_t,
     /// The PID of the process containing `thread_id`.
-    pid: libc::pid_t,
+    pub(crate) pid: libc::pid_t,
     /// The scheduling policy in place before promotion, to restore on demotion.
-    policy: libc::c_int,
+    pub(crate) policy: libc::c_int,
     /// The scheduling priority in place before promotion, to restore on demotion.
-    priority: libc::c_int,
+    pub(crate) priority: libc::c_int,
+}
+
+/// The first bytes of a serialized `RtPriorityThreadInfo`.
+const WIRE_MAGIC: [u8; 4] = *b"ATPI";
+/// The version of the serialization format, bumped when the payload changes. Version 1 had the
+/// fields in native byte order and size, and could not be exchanged between 32-bit and 64-bit
+/// processes.
+const WIRE_VERSION: u16 = 2;
+/// The magic, the version and the length of the payload.
+const WIRE_HEADER_SIZE: usize = 4 + 2 + 2;
+/// The fields, little-endian and of the same size on all targets: the thread id (`i64`), the pid,
+/// the policy and the priority (`i32`), and the `pthread_t` (`u64`), only meaningful in the process
+/// `pid`.
+const WIRE_PAYLOAD_SIZE: usize = 8 + 4 + 4 + 4 + 8;
+/// The CRC-32 of the header and the payload.
+const WIRE_CHECKSUM_SIZE: usize = 4;
+
+/// The CRC-32 (IEEE 802.3) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+fn wire_error(kind: ErrorKind, message: &str) -> AudioThreadPriorityError {
+    AudioThreadPriorityError::with_kind(kind, &format!("invalid serialized thread info: {message}"))
 }
 
 impl RtPriorityThreadInfoInternal {
-    /// Serialize to a byte buffer. The fields are packed explicitly rather than transmuting the
-    /// struct, so no uninitialized padding bytes are ever read. Any trailing padding stays zero.
-    pub fn serialize(&self) -> [u8; std::mem::size_of::<Self>()] {
-        let thread_id = self.thread_id.to_ne_bytes();
-        let pthread_id = self.pthread_id.to_ne_bytes();
-        let pid = self.pid.to_ne_bytes();
-        let policy = self.policy.to_ne_bytes();
-        let priority = self.priority.to_ne_bytes();
-
-        let mut bytes = [0u8; std::mem::size_of::<Self>()];
-        let fields = thread_id
+    /// The size of a serialized thread info, exposed in the C API as `ATP_THREAD_INFO_SIZE`.
+    pub const SERIALIZED_SIZE: usize = WIRE_HEADER_SIZE + WIRE_PAYLOAD_SIZE + WIRE_CHECKSUM_SIZE;
+
+    /// Serialize to a byte buffer, to send to another process. It starts with a magic number, the
+    /// version of the format and the length of the payload, and ends with a checksum, so that
+    /// [`try_deserialize`](Self::try_deserialize) rejects anything else than a thread info
+    /// serialized by a compatible version of this crate. The encoding is the same on all Linux
+    /// targets, so that a 32-bit process can send it to a 64-bit one, and the other way around.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn serialize(&self) -> [u8; Self::SERIALIZED_SIZE] {
+        let version = WIRE_VERSION.to_le_bytes();
+        let length = (WIRE_PAYLOAD_SIZE as u16).to_le_bytes();
+        let thread_id = (self.thread_id as i64).to_le_bytes();
+        let pid = (self.pid as i32).to_le_bytes();
+        let policy = (self.policy as i32).to_le_bytes();
+        let priority = (self.priority as i32).to_le_bytes();
+        let pthread_id = (self.pthread_id as u64).to_le_bytes();
+
+        let mut bytes = [0u8; Self::SERIALIZED_SIZE];
+        let fields = WIRE_MAGIC
             .iter()
-            .chain(&pthread_id)
+            .chain(&version)
+            .chain(&length)
+            .chain(&thread_id)
             .chain(&pid)
             .chain(&policy)
-            .chain(&priority);
+            .chain(&priority)
+            .chain(&pthread_id);
         for (dst, &src) in bytes.iter_mut().zip(fields) {
             *dst = src;
         }
+        let (fields, checksum) = bytes.split_at_mut(WIRE_HEADER_SIZE + WIRE_PAYLOAD_SIZE);
+        checksum.copy_from_slice(&crc32(fields).to_le_bytes());
         bytes
     }
-    /// Reconstruct from a byte buffer produced by `serialize`.
-    pub fn deserialize(bytes: [u8; std::mem::size_of::<Self>()]) -> Self {
+
+    /// Reconstruct from a byte buffer produced by `serialize`, checking its length, magic number,
+    /// version and checksum. The `pthread_t` is only restored in the process the thread belongs to,
+    /// and is 0 elsewhere, where it means nothing.
+    pub fn try_deserialize(bytes: &[u8]) -> Result<Self, AudioThreadPriorityError> {
         fn take<const N: usize>(src: &mut impl Iterator<Item = u8>) -> [u8; N] {
             let mut chunk = [0u8; N];
             for slot in &mut chunk {
@@ -104,19 +166,110 @@ impl RtPriorityThreadInfoInternal {
             }
             chunk
         }
-        let mut src = bytes.iter().copied();
-        RtPriorityThreadInfoInternal {
-            thread_id: kernel_pid_t::from_ne_bytes(take(&mut src)),
-            pthread_id: libc::pthread_t::from_ne_bytes(take(&mut src)),
-            pid: libc::pid_t::from_ne_bytes(take(&mut src)),
-            policy: libc::c_int::from_ne_bytes(take(&mut src)),
-            priority: libc::c_int::from_ne_bytes(take(&mut src)),
+        if bytes.len() < WIRE_HEADER_SIZE || bytes[..4] != WIRE_MAGIC {
+            return Err(wire_error(ErrorKind::InvalidArgument, "no magic number"));
+        }
+        let mut src = bytes[4..].iter().copied();
+        let version = u16::from_le_bytes(take(&mut src));
+        if version != WIRE_VERSION {
+            return Err(wire_error(
+                ErrorKind::Unsupported,
+                &format!("format version {version}, expected {WIRE_VERSION}"),
+            ));
+        }
+        let length = u16::from_le_bytes(take(&mut src)) as usize;
+        if length != WIRE_PAYLOAD_SIZE || bytes.len() != Self::SERIALIZED_SIZE {
+            return Err(wire_error(
+                ErrorKind::InvalidArgument,
+                &format!(
+                    "{} bytes with a payload of {length}, expected {} with a payload of {WIRE_PAYLOAD_SIZE}",
+                    bytes.len(),
+                    Self::SERIALIZED_SIZE
+                ),
+            ));
+        }
+        let (fields, checksum) = bytes.split_at(WIRE_HEADER_SIZE + WIRE_PAYLOAD_SIZE);
+        if crc32(fields).to_le_bytes() != checksum {
+            return Err(wire_error(ErrorKind::InvalidArgument, "checksum mismatch"));
+        }
+        let thread_id = i64::from_le_bytes(take(&mut src));
+        let pid = i32::from_le_bytes(take(&mut src));
+        let policy = i32::from_le_bytes(take(&mut src));
+        let priority = i32::from_le_bytes(take(&mut src));
+        let pthread_id = u64::from_le_bytes(take(&mut src));
+        let thread_id = libc::pid_t::try_from(thread_id)
+            .map_err(|_| wire_error(ErrorKind::InvalidArgument, "thread id out of range"))?
+            as kernel_pid_t;
+        let pthread_id = if pid == unsafe { libc::getpid() } {
+            libc::pthread_t::try_from(pthread_id)
+                .map_err(|_| wire_error(ErrorKind::InvalidArgument, "pthread_t out of range"))?
+        } else {
+            0
+        };
+        Ok(RtPriorityThreadInfoInternal {
+            thread_id,
+            pthread_id,
+            pid,
+            policy,
+            priority,
+        })
+    }
+
+    /// Reconstruct from a byte buffer produced by `serialize`.
+    ///
+    /// If `bytes` is not a valid serialized thread info, see `try_deserialize`, this logs the error
+    /// and returns a thread info with a thread id and a pid of -1, which no thread has: promoting
+    /// or demoting it fails with `ErrorKind::InvalidArgument`.
+    #[deprecated(note = "use `try_deserialize`, which returns an error for invalid bytes")]
+    pub fn deserialize(bytes: [u8; Self::SERIALIZED_SIZE]) -> Self {
+        Self::try_deserialize(&bytes).unwrap_or_else(|e| {
+            log::error!("{e}");
+            RtPriorityThreadInfoInternal {
+                thread_id: -1,
+                pthread_id: 0,
+                pid: -1,
+                policy: -1,
+                priority: -1,
+            }
+        })
+    }
+
+    /// Fail if this is not the thread info of a thread, as returned by `deserialize` for invalid
+    /// bytes: a thread id of 0 would be taken for the calling thread by the kernel.
+    pub(crate) fn check(&self) -> Result<(), AudioThreadPriorityError> {
+        if self.thread_id <= 0 || self.pid <= 0 {
+            return Err(AudioThreadPriorityError::with_kind(
+                ErrorKind::InvalidArgument,
+                &format!(
+                    "invalid thread info: thread {} of process {}",
+                    self.thread_id, self.pid
+                ),
+            ));
         }
+        Ok(())
     }
     /// Returns the PID of the process containing the thread.
     pub fn pid(&self) -> libc::pid_t {
         self.pid
     }
+
+    /// Returns the system-wide id (tid) of the thread, to change its scheduling with
+    /// `sched_setscheduler` or to send it to a privileged helper.
+    pub fn thread_id(&self) -> libc::pid_t {
+        self.thread_id as libc::pid_t
+    }
+
+    /// Returns the scheduling policy of the thread when this was captured, to restore on demotion,
+    /// as returned by `sched_getscheduler`: it may have `SCHED_RESET_ON_FORK` set.
+    pub fn policy(&self) -> libc::c_int {
+        self.policy
+    }
+
+    /// Returns the scheduling priority of the thread when this was captured, to restore on
+    /// demotion: 0 unless it was already real-time.
+    pub fn priority(&self) -> libc::c_int {
+        self.priority
+    }
 }
 
 impl PartialEq for RtPriorityThreadInfoInternal {
@@ -125,43 +278,81 @@ impl PartialEq for RtPriorityThreadInfoInternal {
     }
 }
 
+impl Eq for RtPriorityThreadInfoInternal {}
+
 pub struct RtPriorityHandleInternal {
     thread_info: RtPriorityThreadInfoInternal,
+    /// The scheduling attributes in place before a `SCHED_DEADLINE` promotion, to restore on
+    /// demotion. `None` for a `SCHED_FIFO` or `SCHED_RR` promotion.
+    deadline_saved: Option<SchedAttr>,
+    /// Whether promotion set `SCHED_RESET_ON_FORK`.
+    reset_on_fork: bool,
+    /// The real-time priority requested. `None` for `SCHED_DEADLINE`, which has no priority.
+    priority: Option<u32>,
+    /// The budget of the promotion options, kept when the buffer duration changes.
+    budget: Option<Duration>,
 }
 
-/// The POSIX `pthread_*` functions return the error number directly and do not set `errno`, so the
-/// return code must be converted with `Error::from_raw_os_error`, not read via `last_os_error`.
-fn pthread_error(context: &str, rc: libc::c_int) -> AudioThreadPriorityError {
-    AudioThreadPriorityError::new(&format!("{}: {}", context, OSError::from_raw_os_error(rc)))
+impl RtPriorityHandleInternal {
+    /// The real-time priority the thread was granted. `None` for `SCHED_DEADLINE`, which has no
+    /// priority.
+    pub fn granted_priority(&self) -> Option<u32> {
+        self.priority
+    }
 }
 
-/// The `sched_*` functions are thin syscall wrappers: they return -1 and set `errno`.
+/// The `sched_*` functions, like `prlimit`, are thin syscall wrappers: they return -1 and set
+/// `errno`.
 fn sched_error(context: &str) -> AudioThreadPriorityError {
-    AudioThreadPriorityError::new(&format!("{}: {}", context, OSError::last_os_error()))
+    let error = OSError::last_os_error();
+    AudioThreadPriorityError::from_os_error(&format!("{context}: {error}"), &error)
+}
+
+/// The error of a failed demotion. Demotion can happen on another thread than the promoted one,
+/// which may have exited since: the kernel then reports `ESRCH`.
+pub(crate) fn demotion_error(context: &str) -> AudioThreadPriorityError {
+    let error = OSError::last_os_error();
+    if error.raw_os_error() == Some(libc::ESRCH) {
+        return AudioThreadPriorityError::from_os_error(
+            &format!("{context}: the promoted thread has exited"),
+            &error,
+        );
+    }
+    AudioThreadPriorityError::from_os_error(&format!("{context}: {error}"), &error)
 }
 
 /// A thread's system-wide tid narrowed to `pid_t` for the scheduler syscalls. A tid always fits in
 /// `pid_t` (it is a pid), but convert defensively rather than truncating.
-fn scheduler_tid(thread_id: kernel_pid_t) -> Result<libc::pid_t, AudioThreadPriorityError> {
-    libc::pid_t::try_from(thread_id)
-        .map_err(|_| AudioThreadPriorityError::new("thread id does not fit in pid_t"))
+pub(crate) fn scheduler_tid(
+    thread_id: kernel_pid_t,
+) -> Result<libc::pid_t, AudioThreadPriorityError> {
+    libc::pid_t::try_from(thread_id).map_err(|_| {
+        AudioThreadPriorityError::with_kind(
+            ErrorKind::InvalidArgument,
+            "thread id does not fit in pid_t",
+        )
+    })
 }
 
 /// Get the current thread information, capturing enough to promote or demote it later, possibly from
 /// another process. The thread is identified by its system-wide tid, so a suitably privileged
-/// process can promote it via `promote_thread_to_real_time_internal`. This mirrors the rtkit path,
-/// except the native path changes the scheduler directly instead of delegating to the rtkit daemon.
+/// process can promote it via `promote_thread_to_real_time_internal`, with any of the backends.
+///
+/// The scheduling is read from the kernel rather than with `pthread_getschedparam`: glibc caches
+/// what `pthread_setschedparam` set, which is stale once the thread was demoted by tid.
 pub fn get_current_thread_info_internal(
 ) -> Result<RtPriorityThreadInfoInternal, AudioThreadPriorityError> {
     let thread_id = unsafe { libc::syscall(libc::SYS_gettid) };
     let pthread_id = unsafe { libc::pthread_self() };
     let pid = unsafe { libc::getpid() };
-    let mut policy = 0;
-    let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
 
-    let rc = unsafe { libc::pthread_getschedparam(pthread_id, &mut policy, &mut param) };
-    if rc != 0 {
-        return Err(pthread_error("pthread_getschedparam", rc));
+    let policy = unsafe { libc::sched_getscheduler(0) };
+    if policy < 0 {
+        return Err(sched_error("sched_getscheduler"));
+    }
+    let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
+    if unsafe { libc::sched_getparam(0, &mut param) } < 0 {
+        return Err(sched_error("sched_getparam"));
     }
 
     Ok(RtPriorityThreadInfoInternal {
@@ -173,76 +364,378 @@ pub fn get_current_thread_info_internal(
     })
 }
 
-/// Promote the calling thread to real-time priority using `SCHED_FIFO`.
-///
-/// The buffer size and sample rate are unused here (they matter only for the rtkit path, which
-/// derives an `RLIMIT_RTTIME` budget from them); the signature is kept to match the other backends.
-pub fn promote_current_thread_to_real_time_internal(
-    _audio_buffer_frames: u32,
-    _audio_samplerate_hz: u32,
-) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
-    let thread_info = get_current_thread_info_internal()?;
+/// The `SCHED_FIFO` or `SCHED_RR` policy to request for `options`, with `SCHED_RESET_ON_FORK`
+/// unless disabled.
+fn fixed_priority_policy(options: &PromotionOptions) -> libc::c_int {
+    let policy = match options.policy {
+        Some(SchedulingPolicy::RoundRobin) => libc::SCHED_RR,
+        _ => libc::SCHED_FIFO,
+    };
+    if options.reset_on_fork {
+        policy | SCHED_RESET_ON_FORK
+    } else {
+        policy
+    }
+}
 
-    let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
-    param.sched_priority = requested_priority();
+/// Set the soft `RLIMIT_RTTIME` of the process `pid` to `budget`, capped by its hard limit.
+fn set_rttime_budget(pid: libc::pid_t, budget: Duration) -> Result<(), AudioThreadPriorityError> {
+    let mut limit = unsafe { std::mem::zeroed::<libc::rlimit>() };
+    if unsafe { libc::prlimit(pid, libc::RLIMIT_RTTIME, std::ptr::null(), &mut limit) } < 0 {
+        return Err(sched_error("prlimit"));
+    }
+    let budget_us = libc::rlim_t::try_from(budget.as_micros()).unwrap_or(libc::RLIM_INFINITY);
+    limit.rlim_cur = budget_us.min(limit.rlim_max);
+    if unsafe { libc::prlimit(pid, libc::RLIMIT_RTTIME, &limit, std::ptr::null_mut()) } < 0 {
+        return Err(sched_error("prlimit"));
+    }
+    Ok(())
+}
 
-    let rc = unsafe {
-        libc::pthread_setschedparam(
-            thread_info.pthread_id,
-            libc::SCHED_FIFO | SCHED_RESET_ON_FORK,
-            &param,
-        )
-    };
-    if rc != 0 {
-        return Err(pthread_error("could not promote thread", rc));
+/// The soft `RLIMIT_RTPRIO` limit of this process before the native backend raised it, and the
+/// threads promoted since, restored once they are all demoted. Left raised, it would let any thread
+/// of the process make itself real-time at that priority, without going through this crate.
+struct RaisedRtprio {
+    /// The process the state belongs to: a child forked since has none of its threads.
+    pid: libc::pid_t,
+    original: Option<limits::Limit<u32>>,
+    threads: Vec<kernel_pid_t>,
+}
+
+static RAISED_RTPRIO: Mutex<RaisedRtprio> = Mutex::new(RaisedRtprio {
+    pid: 0,
+    original: None,
+    threads: Vec::new(),
+});
+
+impl RaisedRtprio {
+    fn lock() -> MutexGuard<'static, RaisedRtprio> {
+        let mut raised = RAISED_RTPRIO.lock().unwrap_or_else(PoisonError::into_inner);
+        let pid = unsafe { libc::getpid() };
+        if raised.pid != pid {
+            *raised = RaisedRtprio {
+                pid,
+                original: None,
+                threads: Vec::new(),
+            };
+        }
+        raised
+    }
+
+    /// Make room for `priority` in the limit, after the kernel refused it: raise the soft limit up
+    /// to the hard limit, which needs no privilege. Returns the priority the limit now allows,
+    /// lower than `priority` if the hard limit is, or `None` if the limit was not the reason for
+    /// the refusal, or does not allow any real-time priority.
+    fn raise(&mut self, priority: libc::c_int) -> Option<libc::c_int> {
+        let limit = limits::rtprio().ok()?;
+        let priority = priority as u32;
+        if limit.soft.is_none_or(|soft| soft >= priority) {
+            return None;
+        }
+        let allowed = limit.hard.map_or(priority, |hard| hard.min(priority));
+        if allowed < 1 {
+            return None;
+        }
+        let raised = limits::Limit {
+            soft: Some(allowed),
+            ..limit
+        };
+        if limits::set_rtprio(raised).is_err() {
+            return None;
+        }
+        if self.original.is_none() {
+            self.original = Some(limit);
+        }
+        Some(allowed as libc::c_int)
+    }
+
+    /// Count the thread `tid` among the threads promoted while the limit is raised.
+    fn add(&mut self, tid: kernel_pid_t) {
+        if self.original.is_some() && !self.threads.contains(&tid) {
+            self.threads.push(tid);
+        }
     }
 
-    Ok(RtPriorityHandleInternal { thread_info })
+    /// Forget the thread `tid`, and restore the original soft limit if no promoted thread is left.
+    fn remove(&mut self, tid: kernel_pid_t) -> Result<(), AudioThreadPriorityError> {
+        self.threads.retain(|&thread| thread != tid);
+        if !self.threads.is_empty() {
+            return Ok(());
+        }
+        let original = match self.original.take() {
+            Some(original) => original,
+            None => return Ok(()),
+        };
+        limits::set_rtprio(limits::Limit {
+            soft: original.soft,
+            hard: limits::rtprio()?.hard,
+        })
+    }
 }
 
-/// Restore the calling thread to the scheduling policy it had before promotion.
+/// Promote the thread to `SCHED_DEADLINE` instead of a fixed priority, see `rt_linux_deadline`.
+/// The reservation is always set with `SCHED_FLAG_RESET_ON_FORK`: the kernel refuses to let a
+/// `SCHED_DEADLINE` thread fork otherwise, so `options` cannot disable it.
+fn promote_to_deadline(
+    thread_info: RtPriorityThreadInfoInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    if !options.reset_on_fork {
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::Unsupported,
+            "SCHED_DEADLINE is always set with SCHED_FLAG_RESET_ON_FORK, it cannot be disabled",
+        ));
+    }
+    let tid = scheduler_tid(thread_info.thread_id)?;
+    let saved = rt_linux_deadline::promote(
+        tid,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options.budget,
+    )?;
+
+    Ok(RtPriorityHandleInternal {
+        thread_info,
+        deadline_saved: Some(saved),
+        reset_on_fork: true,
+        priority: None,
+        budget: options.budget,
+    })
+}
+
+/// Promote the calling thread, described by `thread_info`, to real-time priority, using
+/// `SCHED_FIFO` unless `options` asks for another policy.
+///
+/// The thread is promoted by tid, like any other thread, so that it can be demoted from any thread
+/// of the process.
+pub fn promote_current_thread_to_real_time_internal(
+    thread_info: RtPriorityThreadInfoInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    promote_thread_to_real_time_internal(
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options,
+    )
+}
+
+/// Restore the thread promoted by `rt_priority_handle` to the scheduling policy it had before
+/// promotion. The thread is identified by its tid, so this works from any thread of the process.
 pub fn demote_current_thread_from_real_time_internal(
     rt_priority_handle: RtPriorityHandleInternal,
 ) -> Result<(), AudioThreadPriorityError> {
+    let tid = scheduler_tid(rt_priority_handle.thread_info.thread_id)?;
+    if let Some(saved) = rt_priority_handle.deadline_saved {
+        return rt_linux_deadline::restore(tid, &saved);
+    }
+
     let RtPriorityThreadInfoInternal {
-        pthread_id,
-        policy,
-        priority,
-        ..
+        policy, priority, ..
     } = rt_priority_handle.thread_info;
 
-    // Keep SCHED_RESET_ON_FORK set: the kernel forbids an unprivileged thread from clearing that
-    // flag once set (and promotion set it), so restoring the bare saved policy would fail with
-    // EPERM. The flag is harmless on a non-real-time thread.
+    // Keep SCHED_RESET_ON_FORK set if promotion set it: the kernel forbids an unprivileged thread
+    // from clearing that flag once set, so restoring the bare saved policy would fail with EPERM.
+    // The flag is harmless on a non-real-time thread.
+    let policy = if rt_priority_handle.reset_on_fork {
+        policy | SCHED_RESET_ON_FORK
+    } else {
+        policy
+    };
     let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
     param.sched_priority = priority;
-    let rc =
-        unsafe { libc::pthread_setschedparam(pthread_id, policy | SCHED_RESET_ON_FORK, &param) };
-    if rc != 0 {
-        return Err(pthread_error("could not demote thread", rc));
+    let demotion = if unsafe { libc::sched_setscheduler(tid, policy, &param) } < 0 {
+        Err(demotion_error("could not demote thread"))
+    } else {
+        Ok(())
+    };
+    forget_budget(rt_priority_handle.thread_info, demotion)
+}
+
+/// Set the `RLIMIT_RTTIME` budget of a thread promoted with a fixed priority: the budget of
+/// `options`, or the duration of one buffer. A thread that runs for longer than that without
+/// blocking gets `SIGXCPU`, so a runaway real-time thread cannot lock up a CPU.
+///
+/// For a thread of this process, the limit is the largest budget of its real-time threads, see
+/// `RttimeBudgets`. Changing the limit of another process needs privilege, which an
+/// `RLIMIT_RTPRIO` limit does not grant. If that fails for a budget derived from the buffer
+/// duration, the promotion goes on: that process can set its own limit with
+/// `set_real_time_hard_limit`.
+fn set_promotion_budget(
+    thread_info: RtPriorityThreadInfoInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    budget: Option<Duration>,
+) -> Result<(), AudioThreadPriorityError> {
+    let derived = limits::rttime_budget(audio_buffer_frames, audio_samplerate_hz);
+    let pid = thread_info.pid;
+    if pid == unsafe { libc::getpid() } {
+        return RttimeBudgets::lock().set(thread_info.thread_id, budget.unwrap_or(derived), None);
     }
-    Ok(())
+    match set_rttime_budget(pid, budget.unwrap_or(derived)) {
+        Err(e) if budget.is_none() => {
+            log::info!("Could not set the RLIMIT_RTTIME budget of process {pid}: {e}");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Forget the `RLIMIT_RTTIME` budget of `thread_info` if it is a thread of this process, restoring
+/// the `RLIMIT_RTTIME` limit, and the `RLIMIT_RTPRIO` soft limit if it was raised, if it was the
+/// last real-time thread, and return the result of its `demotion`.
+fn forget_budget(
+    thread_info: RtPriorityThreadInfoInternal,
+    demotion: Result<(), AudioThreadPriorityError>,
+) -> Result<(), AudioThreadPriorityError> {
+    if thread_info.pid != unsafe { libc::getpid() } {
+        return demotion;
+    }
+    let restored = RttimeBudgets::lock().remove(thread_info.thread_id, None);
+    let rtprio_restored = RaisedRtprio::lock().remove(thread_info.thread_id);
+    demotion.and(restored).and(rtprio_restored)
 }
 
 /// Promote a thread identified by its tid to real-time priority. Promoting a thread other than the
 /// caller (in particular in another process) requires the caller to be privileged.
+///
+/// The buffer size and sample rate set the `SCHED_DEADLINE` reservation, or for the fixed-priority
+/// policies, the `RLIMIT_RTTIME` budget, unless `options` has one.
+///
+/// If an unprivileged process is refused the priority for one of its threads by its
+/// `RLIMIT_RTPRIO` soft limit, the soft limit is raised up to the hard limit, until the threads
+/// promoted meanwhile are demoted, and the priority lowered to the hard limit if needed: the handle
+/// has the priority granted. The limit of another process is never changed.
 pub fn promote_thread_to_real_time_internal(
     thread_info: RtPriorityThreadInfoInternal,
-    _audio_buffer_frames: u32,
-    _audio_samplerate_hz: u32,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+    options: &PromotionOptions,
 ) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    if options.policy == Some(SchedulingPolicy::Deadline) {
+        return promote_to_deadline(
+            thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            options,
+        );
+    }
+
     let tid = scheduler_tid(thread_info.thread_id)?;
 
     let mut param = unsafe { std::mem::zeroed::<libc::sched_param>() };
-    param.sched_priority = requested_priority();
+    param.sched_priority = options
+        .checked_priority()?
+        .unwrap_or_else(requested_priority);
+
+    set_promotion_budget(
+        thread_info,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        options.budget,
+    )?;
+
+    let policy = fixed_priority_policy(options);
+    if let Err(error) = set_fixed_priority(thread_info, tid, policy, &mut param) {
+        // The thread is not real-time: roll back its budget.
+        forget_budget(thread_info, Ok(()))?;
+        return Err(error);
+    }
 
-    let rc =
-        unsafe { libc::sched_setscheduler(tid, libc::SCHED_FIFO | SCHED_RESET_ON_FORK, &param) };
-    if rc < 0 {
-        return Err(sched_error("could not promote thread"));
+    Ok(RtPriorityHandleInternal {
+        thread_info,
+        deadline_saved: None,
+        reset_on_fork: options.reset_on_fork,
+        priority: Some(param.sched_priority as u32),
+        budget: options.budget,
+    })
+}
+
+/// Set the fixed-priority `policy` and `param` of the thread `tid`, described by `thread_info`,
+/// making room in the `RLIMIT_RTPRIO` limit of this process if needed, see `RaisedRtprio`. The
+/// limit of another process is left alone. `param` has the priority granted.
+fn set_fixed_priority(
+    thread_info: RtPriorityThreadInfoInternal,
+    tid: libc::pid_t,
+    policy: libc::c_int,
+    param: &mut libc::sched_param,
+) -> Result<(), AudioThreadPriorityError> {
+    let local = thread_info.pid == unsafe { libc::getpid() };
+    // Held until the thread is counted, so that a concurrent demotion does not restore the limit in
+    // between.
+    let mut raised = RaisedRtprio::lock();
+    if unsafe { libc::sched_setscheduler(tid, policy, param) } < 0 {
+        let denied = OSError::last_os_error().raw_os_error() == Some(libc::EPERM);
+        let error = sched_error("could not promote thread");
+        let allowed = match (denied && local)
+            .then(|| raised.raise(param.sched_priority))
+            .flatten()
+        {
+            Some(allowed) => allowed,
+            None => return Err(error),
+        };
+        if allowed < param.sched_priority {
+            log::info!(
+                "RLIMIT_RTPRIO only allows priority {allowed}, lowering the requested priority {}",
+                param.sched_priority
+            );
+            param.sched_priority = allowed;
+        }
+        if unsafe { libc::sched_setscheduler(tid, policy, param) } < 0 {
+            let error = sched_error("could not promote thread");
+            // Restore the limit if no other thread needs it.
+            raised.remove(thread_info.thread_id)?;
+            return Err(error);
+        }
+    }
+    if local {
+        raised.add(thread_info.thread_id);
+    }
+    Ok(())
+}
+
+/// Adapt a promotion to a new buffer duration: the `SCHED_DEADLINE` reservation, or the
+/// `RLIMIT_RTTIME` budget of a fixed priority, are derived from it, unless the promotion options
+/// set a budget. The priority is left as is.
+pub fn update_real_time_parameters_internal(
+    rt_priority_handle: &mut RtPriorityHandleInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    if rt_priority_handle.deadline_saved.is_none() {
+        if rt_priority_handle.budget.is_some() {
+            return Ok(());
+        }
+        return set_promotion_budget(
+            rt_priority_handle.thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            None,
+        );
     }
+    let tid = scheduler_tid(rt_priority_handle.thread_info.thread_id)?;
+    rt_linux_deadline::update(
+        tid,
+        audio_buffer_frames,
+        audio_samplerate_hz,
+        rt_priority_handle.budget,
+    )
+}
 
-    Ok(RtPriorityHandleInternal { thread_info })
+/// Set the `RLIMIT_RTTIME` budget of the calling process for a stream, for a thread of this process
+/// to be promoted later, possibly by another process that cannot change the limit.
+pub fn set_real_time_hard_limit_internal(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    set_rttime_budget(
+        unsafe { libc::getpid() },
+        limits::rttime_budget(audio_buffer_frames, audio_samplerate_hz),
+    )
 }
 
 /// Restore a thread identified by its tid to the scheduling policy it had before promotion.
@@ -257,16 +750,86 @@ pub fn demote_thread_from_real_time_internal(
     let rc =
         unsafe { libc::sched_setscheduler(tid, thread_info.policy | SCHED_RESET_ON_FORK, &param) };
     if rc < 0 {
-        return Err(sched_error("could not demote thread"));
+        return Err(demotion_error("could not demote thread"));
     }
     Ok(())
 }
 
-/// Setting an `RLIMIT_RTTIME` budget is only needed by the rtkit path. The native path relies on
-/// the kernel's real-time throttling (`sched_rt_runtime_us`) instead, so this is a no-op.
-pub fn set_real_time_hard_limit_internal(
-    _audio_buffer_frames: u32,
-    _audio_samplerate_hz: u32,
-) -> Result<(), AudioThreadPriorityError> {
-    Ok(())
+/// The native backend, `LinuxBackend::Native`.
+pub struct NativeBackend;
+
+impl PriorityBackend for NativeBackend {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn promote_current_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let handle = promote_current_thread_to_real_time_internal(
+            thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            options,
+        )?;
+        let granted_priority = handle.granted_priority();
+        Ok(BackendPromotion::new(handle, granted_priority))
+    }
+
+    fn demote_current_thread(
+        &self,
+        promotion: BackendPromotion,
+    ) -> Result<(), AudioThreadPriorityError> {
+        demote_current_thread_from_real_time_internal(promotion.state()?)
+    }
+
+    fn promote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let handle = promote_thread_to_real_time_internal(
+            thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            options,
+        )?;
+        let granted_priority = handle.granted_priority();
+        Ok(BackendPromotion::new(handle, granted_priority))
+    }
+
+    fn demote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let demotion = demote_thread_from_real_time_internal(thread_info);
+        forget_budget(thread_info, demotion)
+    }
+
+    fn update_parameters(
+        &self,
+        promotion: &mut BackendPromotion,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        update_real_time_parameters_internal(
+            promotion.state_mut()?,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+        )
+    }
+
+    fn set_real_time_limit(
+        &self,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        set_real_time_hard_limit_internal(audio_buffer_frames, audio_samplerate_hz)
+    }
 }
@@ -0,0 +1,151 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `LinuxBackend::Nice` backend, a last resort when real-time scheduling is not available.
+//!
+//! The thread keeps the default scheduling policy, but gets a lower nice level, so that the
+//! scheduler prefers it over the other threads of the system. On Linux, `setpriority` with
+//! `PRIO_PROCESS` and a thread id only changes that thread. Lowering the nice level needs
+//! `CAP_SYS_NICE` or an `RLIMIT_NICE` limit, which some distributions grant to desktop users.
+
+extern crate libc;
+
+use std::io::Error as OSError;
+
+use crate::rt_linux_native::{scheduler_tid, RtPriorityThreadInfoInternal};
+use crate::{
+    AudioThreadPriorityError, BackendPromotion, ErrorKind, PriorityBackend, PromotionOptions,
+};
+
+/// The nice level to request, the one PulseAudio uses for its own threads when it cannot get
+/// real-time scheduling.
+const NICE_LEVEL: libc::c_int = -11;
+
+pub struct RtPriorityHandleInternal {
+    thread_info: RtPriorityThreadInfoInternal,
+    /// The nice level in place before promotion, to restore on demotion.
+    previous_nice: libc::c_int,
+}
+
+/// The nice level of the thread `tid`.
+fn nice_level(tid: libc::pid_t) -> Result<libc::c_int, AudioThreadPriorityError> {
+    // -1 is a valid nice level, the only way to tell an error is through errno.
+    unsafe { *libc::__errno_location() = 0 };
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t) };
+    let error = OSError::last_os_error();
+    if nice == -1 && error.raw_os_error() != Some(0) {
+        return Err(AudioThreadPriorityError::from_os_error(
+            &format!("getpriority: {error}"),
+            &error,
+        ));
+    }
+    Ok(nice)
+}
+
+fn set_nice_level(
+    tid: libc::pid_t,
+    nice: libc::c_int,
+    context: &str,
+) -> Result<(), AudioThreadPriorityError> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } < 0 {
+        let error = OSError::last_os_error();
+        return Err(AudioThreadPriorityError::from_os_error(
+            &format!("{context}: {error}"),
+            &error,
+        ));
+    }
+    Ok(())
+}
+
+/// Lower the nice level of a thread identified by its tid. A thread that already has a lower nice
+/// level is left alone.
+///
+/// There is no scheduling policy to choose, so `options` cannot ask for one. The priority is
+/// checked, but otherwise unused.
+pub fn promote_thread_to_real_time_internal(
+    thread_info: RtPriorityThreadInfoInternal,
+    options: &PromotionOptions,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    if options.policy.is_some() {
+        return Err(AudioThreadPriorityError::with_kind(
+            ErrorKind::Unsupported,
+            "a nice level boost keeps the default scheduling policy, a policy cannot be requested",
+        ));
+    }
+    options.checked_priority()?;
+
+    let tid = scheduler_tid(thread_info.thread_id)?;
+    let previous_nice = nice_level(tid)?;
+    if previous_nice > NICE_LEVEL {
+        set_nice_level(tid, NICE_LEVEL, "could not lower the nice level")?;
+    }
+
+    Ok(RtPriorityHandleInternal {
+        thread_info,
+        previous_nice,
+    })
+}
+
+/// Restore the nice level the thread had before promotion.
+pub fn demote_current_thread_from_real_time_internal(
+    rt_priority_handle: RtPriorityHandleInternal,
+) -> Result<(), AudioThreadPriorityError> {
+    let tid = scheduler_tid(rt_priority_handle.thread_info.thread_id)?;
+    set_nice_level(
+        tid,
+        rt_priority_handle.previous_nice,
+        "could not restore the nice level",
+    )
+}
+
+/// The nice level boost, `LinuxBackend::Nice`.
+pub struct NiceBackend;
+
+impl PriorityBackend for NiceBackend {
+    fn name(&self) -> &'static str {
+        "nice"
+    }
+
+    fn promote_current_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        _audio_buffer_frames: u32,
+        _audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let handle = promote_thread_to_real_time_internal(thread_info, options)?;
+        Ok(BackendPromotion::new(handle, None))
+    }
+
+    fn demote_current_thread(
+        &self,
+        promotion: BackendPromotion,
+    ) -> Result<(), AudioThreadPriorityError> {
+        demote_current_thread_from_real_time_internal(promotion.state()?)
+    }
+
+    fn promote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        self.promote_current_thread(
+            thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+            options,
+        )
+    }
+
+    /// Without the handle, the previous nice level is not known, so this only restores the
+    /// scheduling policy, like the native backend.
+    fn demote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfoInternal,
+    ) -> Result<(), AudioThreadPriorityError> {
+        crate::rt_linux_native::demote_thread_from_real_time_internal(thread_info)
+    }
+}
@@ -0,0 +1,129 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `RLIMIT_RTTIME` budgets of the threads of this process promoted to a fixed real-time
+//! priority, shared by the native backend and rtkit.
+
+use std::cmp;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::limits::{self, Limit};
+use crate::rt_linux_native::kernel_pid_t;
+use crate::AudioThreadPriorityError;
+
+/// The `RLIMIT_RTTIME` budgets of the threads of this process promoted with a fixed priority, and
+/// the limit in place before the first of them, restored once they are all demoted.
+///
+/// The limit is shared by all the threads of the process, but each thread has its own CPU time
+/// accounted: the limit is the largest budget, so that no thread gets `SIGXCPU` before its own
+/// budget is spent.
+pub(crate) struct RttimeBudgets {
+    /// The process the budgets belong to: a child forked since has none of its threads.
+    pid: libc::pid_t,
+    original: Option<Limit<Duration>>,
+    threads: Vec<(kernel_pid_t, Duration)>,
+}
+
+static RTTIME_BUDGETS: Mutex<RttimeBudgets> = Mutex::new(RttimeBudgets {
+    pid: 0,
+    original: None,
+    threads: Vec::new(),
+});
+
+impl RttimeBudgets {
+    pub(crate) fn lock() -> MutexGuard<'static, RttimeBudgets> {
+        let mut budgets = RTTIME_BUDGETS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let pid = unsafe { libc::getpid() };
+        if budgets.pid != pid {
+            *budgets = RttimeBudgets {
+                pid,
+                original: None,
+                threads: Vec::new(),
+            };
+        }
+        budgets
+    }
+
+    /// Set the budget of the thread `tid`, and update the limit, with a hard limit of at most
+    /// `max_rttime` if there is one.
+    pub(crate) fn set(
+        &mut self,
+        tid: kernel_pid_t,
+        budget: Duration,
+        max_rttime: Option<Duration>,
+    ) -> Result<(), AudioThreadPriorityError> {
+        if self.original.is_none() {
+            self.original = Some(limits::rttime()?);
+        }
+        match self.threads.iter_mut().find(|(thread, _)| *thread == tid) {
+            Some((_, thread_budget)) => *thread_budget = budget,
+            None => self.threads.push((tid, budget)),
+        }
+        self.apply(max_rttime)
+    }
+
+    /// Forget the budget of the thread `tid`, and update the limit, or restore the original one if
+    /// no promoted thread is left.
+    pub(crate) fn remove(
+        &mut self,
+        tid: kernel_pid_t,
+        max_rttime: Option<Duration>,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let count = self.threads.len();
+        self.threads.retain(|(thread, _)| *thread != tid);
+        if self.threads.len() == count {
+            return Ok(());
+        }
+        if !self.threads.is_empty() {
+            return self.apply(max_rttime);
+        }
+        let original = match self.original.take() {
+            Some(original) => original,
+            None => return Ok(()),
+        };
+        if limits::set_rttime(original).is_ok() {
+            return Ok(());
+        }
+        // The hard limit was lowered, and raising it back needs privilege: restore the original
+        // limit within the current hard limit.
+        let hard = limits::rttime()?.hard;
+        limits::set_rttime(Limit {
+            soft: capped(original.soft, hard),
+            hard,
+        })
+    }
+
+    fn apply(&self, max_rttime: Option<Duration>) -> Result<(), AudioThreadPriorityError> {
+        match self.threads.iter().map(|(_, budget)| *budget).max() {
+            Some(budget) => set_rttime_limit(budget, max_rttime),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The smaller of two limits, `None` being unlimited.
+fn capped(value: Option<Duration>, max: Option<Duration>) -> Option<Duration> {
+    match (value, max) {
+        (Some(value), Some(max)) => Some(cmp::min(value, max)),
+        (value, max) => value.or(max),
+    }
+}
+
+/// Set the soft `RLIMIT_RTTIME` limit of the process to `budget`, to be able to handle going over
+/// it using `SIGXCPU`, see `install_sigxcpu_handler`. The hard limit is lowered to `max_rttime` if
+/// there is one, to prevent getting `SIGKILL`, unless it is lower already: raising it needs
+/// privilege. The soft limit is capped by the hard limit.
+pub(crate) fn set_rttime_limit(
+    budget: Duration,
+    max_rttime: Option<Duration>,
+) -> Result<(), AudioThreadPriorityError> {
+    let hard = capped(limits::rttime()?.hard, max_rttime);
+    limits::set_rttime(Limit {
+        soft: capped(Some(budget), hard),
+        hard,
+    })
+}
@@ -0,0 +1,237 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An opt-in `SIGXCPU` handler, to demote a real-time thread that overruns its `RLIMIT_RTTIME`
+//! budget instead of letting the signal kill the process.
+//!
+//! The promotion backends set the soft `RLIMIT_RTTIME` limit below the hard one: a real-time
+//! thread that runs for longer than the soft limit without blocking makes the kernel send
+//! `SIGXCPU`, and at the hard limit, `SIGKILL`. The default action of `SIGXCPU` also terminates the
+//! process, so without a handler the soft limit is as fatal as the hard one.
+//!
+//! The kernel delivers the signal to the thread that overran whenever it does not block it, so the
+//! handler demotes the thread it runs on, if that thread has a real-time policy. It only makes
+//! system calls, which are async-signal-safe, and records the event in a fixed ring of atomics,
+//! read later with [`take_sigxcpu_events`].
+
+extern crate libc;
+
+use std::io::Error as OSError;
+use std::sync::atomic::{fence, AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use log::warn;
+
+use crate::AudioThreadPriorityError;
+
+/// Prevents threads/processes forked from a real-time thread from inheriting real-time scheduling.
+const SCHED_RESET_ON_FORK: libc::c_int = 0x4000_0000;
+/// Not exposed by all libc versions.
+const SCHED_DEADLINE: libc::c_int = 6;
+
+/// The number of events kept until they are read. Older events are overwritten.
+const CAPACITY: usize = 64;
+
+/// A `SIGXCPU` received by the handler installed with [`install_sigxcpu_handler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SigxcpuEvent {
+    /// The system-wide id (tid) of the thread the signal was delivered to.
+    pub thread_id: libc::pid_t,
+    /// When the signal was handled, on the `CLOCK_MONOTONIC` clock.
+    pub time: Duration,
+    /// Whether the thread had a real-time policy, and was moved back to `SCHED_OTHER`. If not, the
+    /// signal was delivered to another thread than the one that overran, which is left alone.
+    pub demoted: bool,
+}
+
+/// A slot of the ring. `sequence` is the index of the event it holds, plus one, once the other
+/// fields are written, so that the reader can tell a complete event from one being written or
+/// overwritten.
+struct Slot {
+    sequence: AtomicUsize,
+    thread_id: AtomicI32,
+    time_ns: AtomicU64,
+    demoted: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    sequence: AtomicUsize::new(0),
+    thread_id: AtomicI32::new(0),
+    time_ns: AtomicU64::new(0),
+    demoted: AtomicBool::new(false),
+};
+
+static EVENTS: [Slot; CAPACITY] = [EMPTY_SLOT; CAPACITY];
+/// The index of the next event to write.
+static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+/// The index of the next event to read. Only used outside of the handler.
+static READ: Mutex<usize> = Mutex::new(0);
+/// The action in place before [`install_sigxcpu_handler`], restored by
+/// [`uninstall_sigxcpu_handler`]. Only used outside of the handler.
+static PREVIOUS_ACTION: Mutex<Option<libc::sigaction>> = Mutex::new(None);
+
+extern "C" fn handle_sigxcpu(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let errno = unsafe { *libc::__errno_location() };
+
+    let thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+    let policy = unsafe { libc::sched_getscheduler(0) } & !SCHED_RESET_ON_FORK;
+    let demoted = matches!(policy, libc::SCHED_FIFO | libc::SCHED_RR | SCHED_DEADLINE) && {
+        let param = libc::sched_param { sched_priority: 0 };
+        // Keep SCHED_RESET_ON_FORK set: clearing it needs privilege.
+        unsafe { libc::sched_setscheduler(0, libc::SCHED_OTHER | SCHED_RESET_ON_FORK, &param) == 0 }
+    };
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    let index = WRITTEN.fetch_add(1, Ordering::Relaxed);
+    let slot = &EVENTS[index % CAPACITY];
+    slot.sequence.store(0, Ordering::Relaxed);
+    // Pairs with the fence of the reader: one that reads any of the fields below also reads the
+    // sequence 0, or a later one, when checking the sequence again.
+    fence(Ordering::Release);
+    slot.thread_id.store(thread_id, Ordering::Relaxed);
+    slot.time_ns.store(
+        now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64,
+        Ordering::Relaxed,
+    );
+    slot.demoted.store(demoted, Ordering::Relaxed);
+    slot.sequence.store(index + 1, Ordering::Release);
+
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Install a `SIGXCPU` handler that demotes a real-time thread overrunning its `RLIMIT_RTTIME` soft
+/// limit, and records the event, to be read with [`take_sigxcpu_events`].
+///
+/// This replaces the handler in place, if any, until [`uninstall_sigxcpu_handler`] is called. The
+/// hard limit is not affected: a thread that keeps running past it still gets `SIGKILL`, which
+/// cannot be handled, but a demoted thread does not use its real-time budget anymore.
+pub fn install_sigxcpu_handler() -> Result<(), AudioThreadPriorityError> {
+    let mut previous_action = PREVIOUS_ACTION
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if previous_action.is_some() {
+        return Ok(());
+    }
+
+    let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+    action.sa_sigaction = handle_sigxcpu as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    let mut previous = unsafe { std::mem::zeroed::<libc::sigaction>() };
+    if unsafe { libc::sigaction(libc::SIGXCPU, &action, &mut previous) } < 0 {
+        let error = OSError::last_os_error();
+        return Err(AudioThreadPriorityError::from_os_error(
+            &format!("could not install the SIGXCPU handler: {error}"),
+            &error,
+        ));
+    }
+    *previous_action = Some(previous);
+    Ok(())
+}
+
+/// Restore the `SIGXCPU` handler in place before [`install_sigxcpu_handler`]. Events recorded so
+/// far can still be read.
+pub fn uninstall_sigxcpu_handler() -> Result<(), AudioThreadPriorityError> {
+    let mut previous_action = PREVIOUS_ACTION
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let previous = match previous_action.take() {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+    if unsafe { libc::sigaction(libc::SIGXCPU, &previous, std::ptr::null_mut()) } < 0 {
+        *previous_action = Some(previous);
+        let error = OSError::last_os_error();
+        return Err(AudioThreadPriorityError::from_os_error(
+            &format!("could not restore the SIGXCPU handler: {error}"),
+            &error,
+        ));
+    }
+    Ok(())
+}
+
+/// The `SIGXCPU` events recorded since the last call, oldest first. Only the last 64 are kept: if
+/// more were received in the meantime, the older ones are lost, and a warning is logged.
+pub fn take_sigxcpu_events() -> Vec<SigxcpuEvent> {
+    let mut read = READ.lock().unwrap_or_else(PoisonError::into_inner);
+    let written = WRITTEN.load(Ordering::Relaxed);
+    let mut events = Vec::new();
+    let mut lost = 0;
+    while *read < written {
+        let slot = &EVENTS[*read % CAPACITY];
+        let sequence = slot.sequence.load(Ordering::Acquire);
+        if sequence < *read + 1 {
+            // Still being written: read it next time.
+            break;
+        }
+        let event = SigxcpuEvent {
+            thread_id: slot.thread_id.load(Ordering::Relaxed),
+            time: Duration::from_nanos(slot.time_ns.load(Ordering::Relaxed)),
+            demoted: slot.demoted.load(Ordering::Relaxed),
+        };
+        // An event written over this one since it was checked is lost, along with this one.
+        fence(Ordering::Acquire);
+        if sequence == *read + 1 && slot.sequence.load(Ordering::Relaxed) == sequence {
+            events.push(event);
+        } else {
+            lost += 1;
+        }
+        *read += 1;
+    }
+    if lost > 0 {
+        warn!("{lost} SIGXCPU events were overwritten before being read.");
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_child::{fork_and_wait, PASSED, SKIPPED};
+
+    // A real-time thread that busy-loops past its soft RLIMIT_RTTIME limit is demoted by the
+    // handler, and the event is recorded. This runs in a child process, so that the limit and the
+    // handler do not affect the other tests, and needs to be allowed to use SCHED_FIFO.
+    #[test]
+    fn test_sigxcpu_demotion() {
+        let (_, code) = fork_and_wait(|| {
+            install_sigxcpu_handler().unwrap();
+            let limit = libc::rlimit {
+                rlim_cur: 20_000,
+                rlim_max: libc::RLIM_INFINITY,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limit) } < 0 {
+                return SKIPPED;
+            }
+            let param = libc::sched_param { sched_priority: 1 };
+            if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } < 0 {
+                return SKIPPED;
+            }
+            let start = std::time::Instant::now();
+            while unsafe { libc::sched_getscheduler(0) } & !SCHED_RESET_ON_FORK == libc::SCHED_FIFO
+            {
+                assert!(start.elapsed() < Duration::from_secs(5));
+            }
+            let thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+            let events = take_sigxcpu_events();
+            assert_eq!(events.len(), 1, "{:?}", events);
+            assert_eq!(events[0].thread_id, thread_id);
+            assert!(events[0].demoted);
+            assert!(take_sigxcpu_events().is_empty());
+            uninstall_sigxcpu_handler().unwrap();
+            PASSED
+        });
+        match code {
+            PASSED => {}
+            SKIPPED => eprintln!("skipping test_sigxcpu_demotion: SCHED_FIFO is not permitted"),
+            code => panic!("test_sigxcpu_demotion child exited with {}", code),
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use crate::AudioThreadPriorityError;
+use crate::{AudioThreadPriorityError, ErrorKind};
 use libc::{pthread_mach_thread_np, pthread_self, thread_policy_t};
 use log::info;
 use mach2::boolean::boolean_t;
-use mach2::kern_return::{kern_return_t, KERN_SUCCESS};
+use mach2::kern_return::{
+    kern_return_t, KERN_INVALID_ARGUMENT, KERN_NOT_SUPPORTED, KERN_NO_ACCESS,
+    KERN_PROTECTION_FAILURE, KERN_SUCCESS,
+};
 use mach2::mach_time::{mach_timebase_info, mach_timebase_info_data_t};
 use mach2::message::mach_msg_type_number_t;
 use mach2::port::mach_port_t;
@@ -11,6 +14,17 @@ use mach2::thread_policy::{
     THREAD_TIME_CONSTRAINT_POLICY, THREAD_TIME_CONSTRAINT_POLICY_COUNT,
 };
 
+/// The error of a failed Mach call that returned `rv`.
+fn mach_error(message: &str, rv: kern_return_t) -> AudioThreadPriorityError {
+    let kind = match rv {
+        KERN_INVALID_ARGUMENT => ErrorKind::InvalidArgument,
+        KERN_NO_ACCESS | KERN_PROTECTION_FAILURE => ErrorKind::PermissionDenied,
+        KERN_NOT_SUPPORTED => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    };
+    AudioThreadPriorityError::with_kind(kind, &format!("{message} ({rv})"))
+}
+
 #[derive(Debug)]
 pub struct RtPriorityHandleInternal {
     tid: mach_port_t,
@@ -49,8 +63,9 @@ pub fn demote_current_thread_from_real_time_internal(
             THREAD_TIME_CONSTRAINT_POLICY_COUNT,
         );
         if rv != KERN_SUCCESS {
-            return Err(AudioThreadPriorityError::new(
+            return Err(mach_error(
                 "thread demotion error: thread_policy_get: RT",
+                rv,
             ));
         }
 
@@ -60,18 +75,54 @@ pub fn demote_current_thread_from_real_time_internal(
     Ok(())
 }
 
-pub fn promote_current_thread_to_real_time_internal(
+/// The time constraint policy for an audio callback of `audio_buffer_frames` at
+/// `audio_samplerate_hz`.
+fn time_constraint_policy(
     audio_buffer_frames: u32,
     audio_samplerate_hz: u32,
-) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
-    let mut rt_priority_handle = RtPriorityHandleInternal::new();
-
+) -> thread_time_constraint_policy_data_t {
     let buffer_frames = if audio_buffer_frames > 0 {
         audio_buffer_frames
     } else {
         audio_samplerate_hz / 20
     };
 
+    let mut timebase_info = mach_timebase_info_data_t { denom: 0, numer: 0 };
+    unsafe {
+        mach_timebase_info(&mut timebase_info);
+    }
+
+    let ms2abs: f32 = ((timebase_info.denom as f32) / timebase_info.numer as f32) * 1000000.;
+
+    // The time constraint calculations are somewhat arbitrary for now.
+    let cb_duration = buffer_frames as f32 / (audio_samplerate_hz as f32) * 1000.;
+
+    // Computation time is half of constraint, per macOS 12 behaviour.  And capped at 50ms per macOS limits:
+    // https://github.com/apple-oss-distributions/xnu/blob/e3723e1f17661b24996789d8afc084c0c3303b26/osfmk/kern/thread_policy.c#L408
+    // https://github.com/apple-oss-distributions/xnu/blob/e3723e1f17661b24996789d8afc084c0c3303b26/osfmk/kern/sched_prim.c#L822
+    const MAX_RT_QUANTUM: f32 = 50.0;
+    let computation = cb_duration / 2.0;
+    let computation = if computation > MAX_RT_QUANTUM {
+        info!("thread computation time capped at {MAX_RT_QUANTUM}ms ({computation}ms requested).");
+        MAX_RT_QUANTUM
+    } else {
+        computation
+    };
+
+    thread_time_constraint_policy_data_t {
+        period: (cb_duration * ms2abs) as u32,
+        computation: (computation * ms2abs) as u32,
+        constraint: (cb_duration * ms2abs) as u32,
+        preemptible: 1, // true
+    }
+}
+
+pub fn promote_current_thread_to_real_time_internal(
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<RtPriorityHandleInternal, AudioThreadPriorityError> {
+    let mut rt_priority_handle = RtPriorityHandleInternal::new();
+
     unsafe {
         let tid: mach_port_t = pthread_mach_thread_np(pthread_self());
         let mut time_constraints = thread_time_constraint_policy_data_t {
@@ -98,41 +149,15 @@ pub fn promote_current_thread_to_real_time_internal(
         );
 
         if rv != KERN_SUCCESS {
-            return Err(AudioThreadPriorityError::new(
+            return Err(mach_error(
                 "thread promotion error: thread_policy_get: time_constraint",
+                rv,
             ));
         }
 
         rt_priority_handle.previous_time_constraint_policy = time_constraints;
 
-        let mut timebase_info = mach_timebase_info_data_t { denom: 0, numer: 0 };
-        mach_timebase_info(&mut timebase_info);
-
-        let ms2abs: f32 = ((timebase_info.denom as f32) / timebase_info.numer as f32) * 1000000.;
-
-        // The time constraint calculations are somewhat arbitrary for now.
-        let cb_duration = buffer_frames as f32 / (audio_samplerate_hz as f32) * 1000.;
-
-        // Computation time is half of constraint, per macOS 12 behaviour.  And capped at 50ms per macOS limits:
-        // https://github.com/apple-oss-distributions/xnu/blob/e3723e1f17661b24996789d8afc084c0c3303b26/osfmk/kern/thread_policy.c#L408
-        // https://github.com/apple-oss-distributions/xnu/blob/e3723e1f17661b24996789d8afc084c0c3303b26/osfmk/kern/sched_prim.c#L822
-        const MAX_RT_QUANTUM: f32 = 50.0;
-        let computation = cb_duration / 2.0;
-        let computation = if computation > MAX_RT_QUANTUM {
-            info!(
-                "thread computation time capped at {MAX_RT_QUANTUM}ms ({computation}ms requested)."
-            );
-            MAX_RT_QUANTUM
-        } else {
-            computation
-        };
-
-        time_constraints = thread_time_constraint_policy_data_t {
-            period: (cb_duration * ms2abs) as u32,
-            computation: (computation * ms2abs) as u32,
-            constraint: (cb_duration * ms2abs) as u32,
-            preemptible: 1, // true
-        };
+        time_constraints = time_constraint_policy(audio_buffer_frames, audio_samplerate_hz);
 
         rv = thread_policy_set(
             tid,
@@ -141,8 +166,9 @@ pub fn promote_current_thread_to_real_time_internal(
             THREAD_TIME_CONSTRAINT_POLICY_COUNT,
         );
         if rv != KERN_SUCCESS {
-            return Err(AudioThreadPriorityError::new(
+            return Err(mach_error(
                 "thread promotion error: thread_policy_set: time_constraint",
+                rv,
             ));
         }
 
@@ -151,3 +177,33 @@ pub fn promote_current_thread_to_real_time_internal(
 
     Ok(rt_priority_handle)
 }
+
+/// Set the time constraint policy of a promoted thread for a new buffer duration, keeping the
+/// policy to restore on demotion.
+pub fn update_real_time_parameters_internal(
+    rt_priority_handle: &mut RtPriorityHandleInternal,
+    audio_buffer_frames: u32,
+    audio_samplerate_hz: u32,
+) -> Result<(), AudioThreadPriorityError> {
+    let mut time_constraints = time_constraint_policy(audio_buffer_frames, audio_samplerate_hz);
+    let rv = unsafe {
+        thread_policy_set(
+            rt_priority_handle.tid,
+            THREAD_TIME_CONSTRAINT_POLICY,
+            (&mut time_constraints) as *mut _ as thread_policy_t,
+            THREAD_TIME_CONSTRAINT_POLICY_COUNT,
+        )
+    };
+    if rv != KERN_SUCCESS {
+        return Err(mach_error(
+            "thread parameters update error: thread_policy_set: time_constraint",
+            rv,
+        ));
+    }
+
+    info!(
+        "thread {} real time parameters updated.",
+        rt_priority_handle.tid
+    );
+    Ok(())
+}
@@ -22,14 +22,20 @@ impl RtPriorityHandleInternal {
     }
 }
 
+/// The error of a failed Win32 call, with its error code.
+fn win32_error_to_error(message: &str, win32_error: WIN32_ERROR) -> AudioThreadPriorityError {
+    AudioThreadPriorityError::from_os_error(
+        &format!("{message} ({win32_error})"),
+        &std::io::Error::from_raw_os_error(win32_error as i32),
+    )
+}
+
 fn avrt() -> Result<&'static AvRtLibrary, AudioThreadPriorityError> {
     static AV_RT_LIBRARY: OnceLock<Result<AvRtLibrary, WIN32_ERROR>> = OnceLock::new();
     AV_RT_LIBRARY
         .get_or_init(AvRtLibrary::try_new)
         .as_ref()
-        .map_err(|win32_error| {
-            AudioThreadPriorityError::new(&format!("Unable to load avrt.dll ({win32_error})"))
-        })
+        .map_err(|win32_error| win32_error_to_error("Unable to load avrt.dll", *win32_error))
 }
 
 pub fn promote_current_thread_to_real_time_internal(
@@ -43,9 +49,7 @@ pub fn promote_current_thread_to_real_time_internal(
             RtPriorityHandleInternal::new(mmcss_task_index, task_handle)
         })
         .map_err(|win32_error| {
-            AudioThreadPriorityError::new(&format!(
-                "Unable to bump the thread priority ({win32_error})"
-            ))
+            win32_error_to_error("Unable to bump the thread priority", win32_error)
         })
 }
 
@@ -62,9 +66,10 @@ pub fn demote_current_thread_from_real_time_internal(
             info!("task {mmcss_task_index} priority restored.");
         })
         .map_err(|win32_error| {
-            AudioThreadPriorityError::new(&format!(
-                "Unable to restore the thread priority for task {mmcss_task_index} ({win32_error})"
-            ))
+            win32_error_to_error(
+                &format!("Unable to restore the thread priority for task {mmcss_task_index}"),
+                win32_error,
+            )
         })
 }
 
@@ -0,0 +1,35 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Running a test in a forked child process, so that what it changes for the whole process
+//! (resource limits, signal handlers, environment variables, scheduling) does not affect the other
+//! tests, which run in parallel.
+
+/// The exit code of a child whose checks passed.
+pub const PASSED: i32 = 0;
+/// The exit code of a child whose checks failed, or that panicked.
+pub const FAILED: i32 = 1;
+/// The exit code of a child that could not run its checks here, for lack of privilege.
+pub const SKIPPED: i32 = 2;
+
+/// Run `f` in a forked child process, and return the pid of the child and its exit code: the value
+/// `f` returned, or `FAILED` if it panicked. The child exits with `_exit`, rather than going back
+/// to the test harness it shares with the parent.
+///
+/// `f` is dropped in the parent right after forking, so that it can own guards of locks that the
+/// child must not inherit locked by another thread: they are released in both processes.
+pub fn fork_and_wait(f: impl FnOnce() -> i32) -> (libc::pid_t, i32) {
+    let child = unsafe { libc::fork() };
+    assert!(child >= 0, "fork failed");
+    if child == 0 {
+        let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(FAILED);
+        unsafe { libc::_exit(code) };
+    }
+    drop(f);
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+    assert!(libc::WIFEXITED(status), "child status {}", status);
+    (child, libc::WEXITSTATUS(status))
+}
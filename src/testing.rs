@@ -0,0 +1,278 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A fake promotion backend for the unit tests of applications, available with the `testing`
+//! feature, on Linux.
+//!
+//! [`FakeBackend`] changes nothing: it records the calls made to it, and answers them as scripted
+//! by the test. Once installed, `promote_current_thread_to_real_time` and the other functions of
+//! the crate use it instead of the real backends, so the code under test can be exercised without
+//! the privilege to change the scheduling of threads.
+//!
+//! ```rust
+//! use audio_thread_priority::testing::{FakeBackend, FakeCall, FakeResponse};
+//! use audio_thread_priority::*;
+//!
+//! let fake = FakeBackend::install("fake");
+//! fake.push_response(FakeResponse::Fail("no real-time for you".into()));
+//!
+//! assert!(promote_current_thread_to_real_time(512, 44100).is_err());
+//! let handle = promote_current_thread_to_real_time(512, 48000).unwrap();
+//! demote_current_thread_from_real_time(handle).unwrap();
+//!
+//! let thread = get_current_thread_info().unwrap();
+//! assert_eq!(
+//!     fake.calls(),
+//!     [
+//!         FakeCall::PromoteCurrentThread { thread, audio_buffer_frames: 512, audio_samplerate_hz: 44100 },
+//!         FakeCall::PromoteCurrentThread { thread, audio_buffer_frames: 512, audio_samplerate_hz: 48000 },
+//!         FakeCall::DemoteCurrentThread { thread },
+//!     ]
+//! );
+//! # set_linux_backends(None);
+//! ```
+//!
+//! The backends to use are process-wide, so tests that install a fake backend should not run in
+//! parallel with tests that expect the real ones.
+//!
+//! To exercise the D-Bus backends instead, with the `dbus` or `with_rust_dbus` feature,
+//! [`FakeRtkit`] is a stand-in for the rtkit service, to run on a [`PrivateBus`].
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+#[cfg(any(feature = "dbus", feature = "with_rust_dbus"))]
+pub use crate::rt_linux_fake_rtkit::{FakeRtkit, PrivateBus, RtkitRefusal, RtkitRequest};
+use crate::{
+    register_priority_backend, set_linux_backends, AudioThreadPriorityError, BackendPromotion,
+    ErrorKind, LinuxBackend, PriorityBackend, PromotionOptions, RtPriorityThreadInfo,
+};
+
+/// The real-time priority granted when the promotion options do not ask for one, as with rtkit.
+const DEFAULT_PRIORITY: u32 = 10;
+
+/// How a [`FakeBackend`] answers a promotion.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FakeResponse {
+    /// Grant the priority of the promotion options, or 10.
+    Succeed,
+    /// Refuse the promotion with this message, with `ErrorKind::Other`.
+    Fail(String),
+    /// Refuse the promotion with an error of this kind and message.
+    FailWith(ErrorKind, String),
+    /// Block the calling thread for this long, as a slow rtkit would, then grant the promotion.
+    SucceedAfter(Duration),
+}
+
+/// A call made to a [`FakeBackend`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FakeCall {
+    /// The promotion of the calling thread, `thread`.
+    PromoteCurrentThread {
+        /// The thread to promote.
+        thread: RtPriorityThreadInfo,
+        /// The buffer size of the promotion.
+        audio_buffer_frames: u32,
+        /// The sample rate of the promotion.
+        audio_samplerate_hz: u32,
+    },
+    /// The demotion of the calling thread, `thread`, with the handle of its promotion.
+    DemoteCurrentThread {
+        /// The promoted thread.
+        thread: RtPriorityThreadInfo,
+    },
+    /// `update_real_time_parameters`, with the handle of a promotion of `thread`.
+    UpdateParameters {
+        /// The promoted thread.
+        thread: RtPriorityThreadInfo,
+        /// The new buffer size.
+        audio_buffer_frames: u32,
+        /// The new sample rate.
+        audio_samplerate_hz: u32,
+    },
+    /// The promotion of a thread, possibly in another process.
+    PromoteThread {
+        /// The thread to promote.
+        thread: RtPriorityThreadInfo,
+        /// The buffer size of the promotion.
+        audio_buffer_frames: u32,
+        /// The sample rate of the promotion.
+        audio_samplerate_hz: u32,
+    },
+    /// The demotion of a thread, possibly in another process.
+    DemoteThread {
+        /// The thread to demote.
+        thread: RtPriorityThreadInfo,
+    },
+    /// `set_real_time_hard_limit`, in a process about to be promoted by another.
+    SetRealTimeLimit {
+        /// The buffer size of the limit.
+        audio_buffer_frames: u32,
+        /// The sample rate of the limit.
+        audio_samplerate_hz: u32,
+    },
+}
+
+#[derive(Default)]
+struct FakeState {
+    responses: VecDeque<FakeResponse>,
+    calls: Vec<FakeCall>,
+}
+
+/// A promotion backend that records its calls instead of changing the scheduling of threads.
+///
+/// Promotions are answered with the responses pushed with [`FakeBackend::push_response`], in
+/// order, and succeed once there are none left. Demotions always succeed.
+pub struct FakeBackend {
+    name: &'static str,
+    state: Mutex<FakeState>,
+}
+
+impl FakeBackend {
+    /// A fake backend, to register with `register_priority_backend` as `name`, and to use as
+    /// `LinuxBackend::Custom(name)`.
+    pub fn new(name: &'static str) -> Arc<FakeBackend> {
+        Arc::new(FakeBackend {
+            name,
+            state: Mutex::new(FakeState::default()),
+        })
+    }
+
+    /// A fake backend, registered as `name` and set as the only backend of the process, with
+    /// `set_linux_backends`. Call `set_linux_backends(None)` to use the real backends again.
+    pub fn install(name: &'static str) -> Arc<FakeBackend> {
+        let fake = FakeBackend::new(name);
+        register_priority_backend(fake.clone());
+        set_linux_backends(Some(&[LinuxBackend::Custom(name)]));
+        fake
+    }
+
+    /// Answer the next promotion that has no response yet with `response`.
+    pub fn push_response(&self, response: FakeResponse) {
+        self.state().responses.push_back(response);
+    }
+
+    /// The calls made so far, in order.
+    pub fn calls(&self) -> Vec<FakeCall> {
+        self.state().calls.clone()
+    }
+
+    /// Forget the calls made so far, and the responses not used yet.
+    pub fn reset(&self) {
+        *self.state() = FakeState::default();
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record `call`, and answer it with the next response.
+    fn promote(
+        &self,
+        call: FakeCall,
+        thread_info: RtPriorityThreadInfo,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let response = {
+            let mut state = self.state();
+            state.calls.push(call);
+            state.responses.pop_front().unwrap_or(FakeResponse::Succeed)
+        };
+        match response {
+            FakeResponse::Succeed => {}
+            FakeResponse::Fail(message) => return Err(AudioThreadPriorityError::new(&message)),
+            FakeResponse::FailWith(kind, message) => {
+                return Err(AudioThreadPriorityError::with_kind(kind, &message))
+            }
+            FakeResponse::SucceedAfter(delay) => std::thread::sleep(delay),
+        }
+        let priority = options.priority.map_or(DEFAULT_PRIORITY, u32::from);
+        Ok(BackendPromotion::new(thread_info, Some(priority)))
+    }
+}
+
+impl PriorityBackend for FakeBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn promote_current_thread(
+        &self,
+        thread_info: RtPriorityThreadInfo,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let call = FakeCall::PromoteCurrentThread {
+            thread: thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+        };
+        self.promote(call, thread_info, options)
+    }
+
+    fn demote_current_thread(
+        &self,
+        promotion: BackendPromotion,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let thread = promotion.state()?;
+        self.state()
+            .calls
+            .push(FakeCall::DemoteCurrentThread { thread });
+        Ok(())
+    }
+
+    fn promote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfo,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+        options: &PromotionOptions,
+    ) -> Result<BackendPromotion, AudioThreadPriorityError> {
+        let call = FakeCall::PromoteThread {
+            thread: thread_info,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+        };
+        self.promote(call, thread_info, options)
+    }
+
+    fn demote_thread(
+        &self,
+        thread_info: RtPriorityThreadInfo,
+    ) -> Result<(), AudioThreadPriorityError> {
+        self.state().calls.push(FakeCall::DemoteThread {
+            thread: thread_info,
+        });
+        Ok(())
+    }
+
+    fn update_parameters(
+        &self,
+        promotion: &mut BackendPromotion,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        let thread = *promotion.state_mut()?;
+        self.state().calls.push(FakeCall::UpdateParameters {
+            thread,
+            audio_buffer_frames,
+            audio_samplerate_hz,
+        });
+        Ok(())
+    }
+
+    fn set_real_time_limit(
+        &self,
+        audio_buffer_frames: u32,
+        audio_samplerate_hz: u32,
+    ) -> Result<(), AudioThreadPriorityError> {
+        self.state().calls.push(FakeCall::SetRealTimeLimit {
+            audio_buffer_frames,
+            audio_samplerate_hz,
+        });
+        Ok(())
+    }
+}